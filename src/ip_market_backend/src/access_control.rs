@@ -0,0 +1,177 @@
+use ic_cdk::{query, update};
+use candid::Principal;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+
+// Role checks
+pub fn has_role(principal: Principal, role: Role) -> bool {
+    with_roles(|roles| {
+        roles
+            .get(&principal)
+            .is_some_and(|assignments| assignments.grants.iter().any(|g| g.role == role))
+    })
+}
+
+// Owners hold every permission; canister controllers are treated as owners so an
+// existing deployment can bootstrap roles after upgrading.
+pub fn is_owner(principal: Principal) -> bool {
+    has_role(principal, Role::Owner) || ic_cdk::api::is_controller(&principal)
+}
+
+pub fn has_any_role(principal: Principal, roles: &[Role]) -> bool {
+    is_owner(principal) || roles.iter().any(|role| has_role(principal, *role))
+}
+
+fn ensure_any_role(roles: &[Role]) -> Result<()> {
+    if has_any_role(ic_cdk::caller(), roles) {
+        Ok(())
+    } else {
+        Err(IPMarketplaceError::Unauthorized)
+    }
+}
+
+// Guards for privileged endpoints; call first thing and propagate with `?`
+pub fn require_admin() -> Result<()> {
+    ensure_any_role(&[Role::Admin])
+}
+
+pub fn require_verifier() -> Result<()> {
+    ensure_any_role(&[Role::Admin, Role::Verifier])
+}
+
+pub fn require_moderator() -> Result<()> {
+    ensure_any_role(&[Role::Admin, Role::Moderator])
+}
+
+// Owners manage every role; admins may only hand out Verifier and Moderator
+fn can_manage_role(manager: Principal, role: Role) -> bool {
+    match role {
+        Role::Owner | Role::Admin => is_owner(manager),
+        Role::Verifier | Role::Moderator => has_any_role(manager, &[Role::Admin]),
+    }
+}
+
+// Helper used by init and grant_role; returns false if the role was already held
+pub fn assign_role(principal: Principal, role: Role, granted_by: Principal) -> bool {
+    with_roles_mut(|roles| {
+        let mut assignments = roles.get(&principal).unwrap_or_default();
+        if assignments.grants.iter().any(|g| g.role == role) {
+            return false;
+        }
+        assignments.grants.push(RoleGrant {
+            role,
+            granted_by,
+            granted_at: time(),
+        });
+        roles.insert(principal, assignments);
+        true
+    })
+}
+
+// Grants the roles listed in the install/upgrade arguments
+pub fn apply_init_args(args: InitArgs, installer: Principal) {
+    let owners = if args.owners.is_empty() { vec![installer] } else { args.owners };
+    for owner in owners {
+        assign_role(owner, Role::Owner, installer);
+    }
+    for admin in args.admins {
+        assign_role(admin, Role::Admin, installer);
+    }
+    for verifier in args.verifiers {
+        assign_role(verifier, Role::Verifier, installer);
+    }
+    for moderator in args.moderators {
+        assign_role(moderator, Role::Moderator, installer);
+    }
+}
+
+fn owner_count() -> usize {
+    with_roles(|roles| {
+        roles
+            .iter()
+            .filter(|(_, assignments)| assignments.grants.iter().any(|g| g.role == Role::Owner))
+            .count()
+    })
+}
+
+#[update]
+pub fn grant_role(principal: Principal, role: Role) -> Result<bool> {
+    require_admin()?;
+    let caller = ic_cdk::caller();
+
+    if principal == Principal::anonymous() {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    if !can_manage_role(caller, role) {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+
+    if assign_role(principal, role, caller) {
+        Ok(true)
+    } else {
+        Err(IPMarketplaceError::AlreadyExists)
+    }
+}
+
+#[update]
+pub fn revoke_role(principal: Principal, role: Role) -> Result<bool> {
+    require_admin()?;
+    let caller = ic_cdk::caller();
+
+    if !can_manage_role(caller, role) {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+
+    // Never leave the canister without an owner
+    if role == Role::Owner && has_role(principal, Role::Owner) && owner_count() <= 1 {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    with_roles_mut(|roles| {
+        if let Some(mut assignments) = roles.get(&principal) {
+            let before = assignments.grants.len();
+            assignments.grants.retain(|g| g.role != role);
+            if assignments.grants.len() == before {
+                return Err(IPMarketplaceError::NotFound);
+            }
+            if assignments.grants.is_empty() {
+                roles.remove(&principal);
+            } else {
+                roles.insert(principal, assignments);
+            }
+            Ok(true)
+        } else {
+            Err(IPMarketplaceError::NotFound)
+        }
+    })
+}
+
+#[query]
+pub fn get_role_holders(role: Option<Role>) -> Vec<RoleHolder> {
+    with_roles(|roles| {
+        roles
+            .iter()
+            .filter(|(_, assignments)| match role {
+                Some(r) => assignments.grants.iter().any(|g| g.role == r),
+                None => true,
+            })
+            .map(|(principal, assignments)| RoleHolder {
+                principal,
+                grants: assignments.grants.clone(),
+            })
+            .collect()
+    })
+}
+
+#[query]
+pub fn get_my_roles() -> Vec<Role> {
+    let caller = ic_cdk::caller();
+    with_roles(|roles| {
+        roles
+            .get(&caller)
+            .map(|assignments| assignments.grants.iter().map(|g| g.role).collect())
+            .unwrap_or_default()
+    })
+}
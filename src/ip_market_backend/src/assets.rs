@@ -0,0 +1,347 @@
+use ic_cdk::{query, update};
+use sha2::{Digest, Sha256};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::{canister_id, time};
+use crate::access_control::require_admin;
+use crate::certification::{set_asset_hash, update_certified_data};
+use crate::utils::validate_image_url;
+
+// On-chain file storage. A client declares the file (name, MIME type, size,
+// SHA-256) with begin_asset_upload, sends it in fixed-size chunks, then calls
+// finalize_asset_upload, which checks the bytes against the declared hash. The
+// finished asset is served by http_request at /assets/<id>.
+
+// Every chunk but the last must be exactly this size; keeps each upload message
+// under the 2 MiB ingress limit
+pub const ASSET_CHUNK_SIZE: u64 = 1024 * 1024;
+
+pub const ASSET_PATH_PREFIX: &str = "/assets/";
+
+const ASSET_ID_SEQUENCE: &str = "ASSET";
+
+// MIME types accepted for upload. HTML and SVG are left out because they can
+// carry scripts that would run on the canister's origin.
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "audio/mpeg",
+    "audio/wav",
+    "audio/ogg",
+    "audio/flac",
+    "video/mp4",
+    "video/webm",
+    "application/pdf",
+    "application/zip",
+    "application/json",
+    "text/plain",
+    "model/gltf-binary",
+];
+
+// Leading bytes expected for types that have a reliable signature
+fn matches_signature(content_type: &str, head: &[u8]) -> bool {
+    match content_type {
+        "image/png" => head.starts_with(&[0x89, b'P', b'N', b'G']),
+        "image/jpeg" => head.starts_with(&[0xFF, 0xD8, 0xFF]),
+        "image/gif" => head.starts_with(b"GIF8"),
+        "image/webp" => head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP".as_slice()),
+        "application/pdf" => head.starts_with(b"%PDF"),
+        "application/zip" => head.starts_with(b"PK"),
+        "model/gltf-binary" => head.starts_with(b"glTF"),
+        _ => true,
+    }
+}
+
+pub fn asset_path(asset_id: u64) -> String {
+    format!("{}{}", ASSET_PATH_PREFIX, asset_id)
+}
+
+// Canonical URL stored in FileMetadata.file_url and image URLs
+pub fn asset_url(asset_id: u64) -> String {
+    format!("https://{}.icp0.io{}", canister_id(), asset_path(asset_id))
+}
+
+// Accepts the canonical URL or a bare /assets/<id> path
+pub fn asset_id_from_url(url: &str) -> Option<u64> {
+    let canonical_prefix = format!("https://{}.icp0.io", canister_id());
+    let path = url.strip_prefix(&canonical_prefix).unwrap_or(url);
+    path.strip_prefix(ASSET_PATH_PREFIX)?.parse().ok()
+}
+
+pub fn get_complete_asset(asset_id: u64) -> Option<StoredAsset> {
+    with_assets(|assets| assets.get(&asset_id)).filter(|asset| asset.status == AssetStatus::Complete)
+}
+
+// External image URLs only get the prefix check; on-chain ones must resolve to a
+// finished image upload
+pub fn validate_image_reference(url: &str) -> bool {
+    match asset_id_from_url(url) {
+        Some(asset_id) => get_complete_asset(asset_id).is_some_and(|asset| asset.content_type.starts_with("image/")),
+        None => validate_image_url(url),
+    }
+}
+
+// A file URL that points on-chain must resolve to a finished upload whose
+// SHA-256 (and size, if given) match the declared ones. External URLs pass.
+pub fn validate_stored_file(url: &str, file_hash: Option<&str>, file_size: Option<u64>) -> Result<()> {
+    let Some(asset_id) = asset_id_from_url(url) else {
+        return Ok(());
+    };
+    let asset = get_complete_asset(asset_id).ok_or(IPMarketplaceError::NotFound)?;
+    let hash_matches = file_hash.is_some_and(|hash| asset.file_hash.eq_ignore_ascii_case(hash));
+    if !hash_matches || file_size.is_some_and(|size| size != asset.file_size) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    Ok(())
+}
+
+fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn adjust_usage(owner: candid::Principal, bytes: i128, assets: i32) {
+    with_storage_usage_mut(|usage| {
+        let mut entry = usage.get(&owner).unwrap_or_default();
+        entry.bytes_used = (entry.bytes_used as i128 + bytes).max(0) as u64;
+        entry.asset_count = (entry.asset_count as i64 + assets as i64).max(0) as u32;
+        entry.updated_at = time();
+        usage.insert(owner, entry);
+    });
+}
+
+fn expected_chunk_len(asset: &StoredAsset, chunk_index: u32) -> u64 {
+    let offset = chunk_index as u64 * asset.chunk_size;
+    (asset.file_size - offset).min(asset.chunk_size)
+}
+
+fn owned_upload(asset_id: u64) -> Result<StoredAsset> {
+    let asset = with_assets(|assets| assets.get(&asset_id)).ok_or(IPMarketplaceError::NotFound)?;
+    if asset.owner != ic_cdk::caller() {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+    if asset.status != AssetStatus::Uploading {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    Ok(asset)
+}
+
+fn remove_upload(asset: &StoredAsset) {
+    with_asset_chunks_mut(|chunks| {
+        for chunk_index in 0..asset.chunk_count {
+            chunks.remove(&(asset.id, chunk_index));
+        }
+    });
+    with_assets_mut(|assets| {
+        assets.remove(&asset.id);
+    });
+    adjust_usage(asset.owner, -(asset.file_size as i128), -1);
+}
+
+// Uploads
+#[update]
+pub fn begin_asset_upload(request: BeginAssetUploadRequest) -> Result<StoredAsset> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if request.file_name.trim().is_empty() || request.file_size == 0 || !is_sha256_hex(&request.file_hash) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    let content_type = request.file_type.trim().to_lowercase();
+    if !ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(IPMarketplaceError::InvalidFileFormat);
+    }
+
+    // Uploads in progress count against the quota so parallel uploads cannot
+    // exceed it
+    let config = with_marketplace_config(|config| config.clone());
+    let used = with_storage_usage(|usage| usage.get(&caller)).unwrap_or_default().bytes_used;
+    if request.file_size > config.max_file_size_bytes || used + request.file_size > config.storage_quota_bytes {
+        return Err(IPMarketplaceError::FileTooLarge);
+    }
+
+    let asset = StoredAsset {
+        id: next_sequence(ASSET_ID_SEQUENCE),
+        owner: caller,
+        file_name: request.file_name,
+        content_type,
+        file_size: request.file_size,
+        file_hash: request.file_hash.to_lowercase(),
+        chunk_size: ASSET_CHUNK_SIZE,
+        chunk_count: request.file_size.div_ceil(ASSET_CHUNK_SIZE) as u32,
+        chunks_received: 0,
+        status: AssetStatus::Uploading,
+        created_at: now,
+        completed_at: None,
+    };
+
+    with_assets_mut(|assets| {
+        assets.insert(asset.id, asset.clone());
+    });
+    adjust_usage(caller, asset.file_size as i128, 1);
+
+    Ok(asset)
+}
+
+// Chunks may arrive in any order and may be re-sent; returns how many distinct
+// chunks have been received
+#[update]
+pub fn upload_asset_chunk(asset_id: u64, chunk_index: u32, content: Vec<u8>) -> Result<u32> {
+    let mut asset = owned_upload(asset_id)?;
+
+    if chunk_index >= asset.chunk_count || content.len() as u64 != expected_chunk_len(&asset, chunk_index) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    let replaced = with_asset_chunks_mut(|chunks| {
+        chunks.insert((asset_id, chunk_index), content)
+    });
+    if replaced.is_none() {
+        asset.chunks_received += 1;
+        with_assets_mut(|assets| {
+            assets.insert(asset_id, asset.clone());
+        });
+    }
+
+    Ok(asset.chunks_received)
+}
+
+// Verifies the uploaded bytes against the declared SHA-256 and publishes the
+// asset. On a hash mismatch the upload stays open so bad chunks can be re-sent;
+// content that does not match its declared type is discarded.
+#[update]
+pub fn finalize_asset_upload(asset_id: u64) -> Result<FileMetadata> {
+    let mut asset = owned_upload(asset_id)?;
+
+    if asset.chunks_received != asset.chunk_count {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut head = Vec::new();
+    with_asset_chunks(|chunks| {
+        for (_, chunk) in chunks.range((asset_id, 0)..(asset_id, asset.chunk_count)) {
+            if head.is_empty() {
+                head = chunk.iter().take(16).copied().collect();
+            }
+            hasher.update(&chunk);
+        }
+    });
+    let hash: [u8; 32] = hasher.finalize().into();
+
+    if hex::encode(hash) != asset.file_hash {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    if !matches_signature(&asset.content_type, &head) {
+        remove_upload(&asset);
+        return Err(IPMarketplaceError::InvalidFileFormat);
+    }
+
+    let now = time();
+    asset.status = AssetStatus::Complete;
+    asset.completed_at = Some(now);
+    with_assets_mut(|assets| {
+        assets.insert(asset_id, asset.clone());
+    });
+
+    set_asset_hash(&asset_path(asset_id), Some(hash));
+    update_certified_data();
+
+    Ok(FileMetadata {
+        file_name: asset.file_name,
+        file_type: asset.content_type,
+        file_size: asset.file_size,
+        file_hash: asset.file_hash,
+        file_url: asset_url(asset_id),
+        uploaded_at: now,
+    })
+}
+
+// Discards an unfinished upload and releases its quota
+#[update]
+pub fn cancel_asset_upload(asset_id: u64) -> Result<bool> {
+    let asset = owned_upload(asset_id)?;
+    remove_upload(&asset);
+    Ok(true)
+}
+
+#[update]
+pub fn set_storage_limits(max_file_size_bytes: u64, storage_quota_bytes: u64) -> Result<MarketplaceConfig> {
+    require_admin()?;
+
+    if max_file_size_bytes == 0 || max_file_size_bytes > storage_quota_bytes {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    Ok(with_marketplace_config_mut(|config| {
+        config.max_file_size_bytes = max_file_size_bytes;
+        config.storage_quota_bytes = storage_quota_bytes;
+        config.clone()
+    }))
+}
+
+// Queries
+#[query]
+pub fn get_asset(asset_id: u64) -> Result<StoredAsset> {
+    with_assets(|assets| assets.get(&asset_id)).ok_or(IPMarketplaceError::NotFound)
+}
+
+#[query]
+pub fn get_my_assets() -> Vec<StoredAsset> {
+    let caller = ic_cdk::caller();
+    with_assets(|assets| {
+        assets
+            .iter()
+            .filter(|(_, asset)| asset.owner == caller)
+            .map(|(_, asset)| asset)
+            .collect()
+    })
+}
+
+#[query]
+pub fn get_my_storage_usage() -> StorageUsage {
+    let caller = ic_cdk::caller();
+    with_storage_usage(|usage| usage.get(&caller)).unwrap_or_default()
+}
+
+// Serving
+pub fn read_chunk(asset_id: u64, chunk_index: u32) -> Option<Vec<u8>> {
+    with_asset_chunks(|chunks| chunks.get(&(asset_id, chunk_index)))
+}
+
+// Bytes [start, end] of an asset, both inclusive
+pub fn read_range(asset: &StoredAsset, start: u64, end: u64) -> Vec<u8> {
+    let first_chunk = (start / asset.chunk_size) as u32;
+    let last_chunk = (end / asset.chunk_size) as u32;
+    let mut bytes = Vec::with_capacity((end - start + 1) as usize);
+
+    with_asset_chunks(|chunks| {
+        for ((_, chunk_index), chunk) in chunks.range((asset.id, first_chunk)..=(asset.id, last_chunk)) {
+            let chunk_start = chunk_index as u64 * asset.chunk_size;
+            let from = start.saturating_sub(chunk_start) as usize;
+            let to = ((end - chunk_start + 1) as usize).min(chunk.len());
+            bytes.extend_from_slice(&chunk[from..to]);
+        }
+    });
+    bytes
+}
+
+// Called from post_upgrade: the certified tree lives on the heap
+pub fn certify_all_assets() {
+    let hashes: Vec<(u64, String)> = with_assets(|assets| {
+        assets
+            .iter()
+            .filter(|(_, asset)| asset.status == AssetStatus::Complete)
+            .map(|(id, asset)| (id, asset.file_hash))
+            .collect()
+    });
+    for (asset_id, file_hash) in hashes {
+        if let Some(hash) = hex::decode(file_hash).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()) {
+            set_asset_hash(&asset_path(asset_id), Some(hash));
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use ic_cdk::{query, update};
+use candid::Principal;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::ip_registry::{store_ip, validate_register_ip};
+use crate::nft_management::{commit_mint, validate_mint, MintPlan};
+use crate::collections::{collection_name_key, reserve_collection_supply};
+
+// Registers and mints many IPs in one call. Every item is validated before
+// anything is stored, so an invalid batch leaves no trace. A valid batch is then
+// committed a chunk at a time, the first chunk in the call itself and the rest
+// from timers, to stay inside the per-message instruction limit. The royalty cap
+// is resolved and collection supply reserved at submission, so committing a
+// validated item cannot fail.
+
+pub const MAX_BATCH_ITEMS: u32 = 500;
+// Editions committed per message; a chunk always holds at least one item
+const BATCH_CHUNK_EDITIONS: u32 = 100;
+const BATCH_ID_PREFIX: &str = "BATCH";
+
+#[update]
+pub fn batch_register_and_mint(
+    items: Vec<BatchMintItem>,
+) -> std::result::Result<BatchMintProgress, BatchMintError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if items.is_empty() || items.len() > MAX_BATCH_ITEMS as usize {
+        return Err(BatchMintError::InvalidBatchSize { max_items: MAX_BATCH_ITEMS });
+    }
+
+    let mut pending = Vec::with_capacity(items.len());
+    let mut errors = Vec::new();
+    // Editions claimed so far in each collection by earlier items
+    let mut reserved: BTreeMap<String, u32> = BTreeMap::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let index = index as u32;
+        let key = item.nft.collection_name.as_deref().map(collection_name_key);
+        let already_reserved = key.as_ref().and_then(|key| reserved.get(key)).copied().unwrap_or(0);
+        match validate_item(caller, &item, already_reserved) {
+            Ok(plan) => {
+                if let Some(key) = key {
+                    *reserved.entry(key).or_insert(0) += item.editions;
+                }
+                pending.push(ValidatedBatchItem {
+                    index,
+                    item,
+                    total_editions: plan.total_editions,
+                    royalty_percentage: plan.royalty_percentage,
+                    collection_name: plan.collection_name,
+                });
+            }
+            Err(error) => errors.push(BatchItemError { index, error }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(BatchMintError::InvalidItems(errors));
+    }
+    for validated in &pending {
+        if let Some(ref collection_name) = validated.collection_name {
+            reserve_collection_supply(collection_name, validated.item.editions);
+        }
+    }
+
+    let batch_id = generate_id(BATCH_ID_PREFIX);
+    let job = BatchMintJob {
+        id: batch_id.clone(),
+        owner: caller,
+        total_items: pending.len() as u32,
+        pending,
+        results: Vec::new(),
+        status: BatchMintStatus::InProgress,
+        created_at: now,
+        completed_at: None,
+    };
+    with_batch_mints_mut(|jobs| {
+        jobs.insert(batch_id.clone(), job);
+    });
+
+    Ok(commit_next_chunk(&batch_id).expect("batch was just stored"))
+}
+
+// Each item registers a fresh IP, so its mint starts with no cap and no editions
+fn validate_item(caller: Principal, item: &BatchMintItem, reserved: u32) -> Result<MintPlan> {
+    validate_register_ip(&item.ip)?;
+    validate_mint(caller, &item.nft, None, 0, item.editions, reserved)
+}
+
+fn progress(job: &BatchMintJob) -> BatchMintProgress {
+    BatchMintProgress {
+        id: job.id.clone(),
+        owner: job.owner,
+        total_items: job.total_items,
+        committed_items: job.results.len() as u32,
+        results: job.results.clone(),
+        status: job.status.clone(),
+        created_at: job.created_at,
+        completed_at: job.completed_at,
+    }
+}
+
+// Commits the next chunk of a batch and schedules the one after it
+fn commit_next_chunk(batch_id: &str) -> Option<BatchMintProgress> {
+    let mut job = with_batch_mints(|jobs| jobs.get(&batch_id.to_string()))?;
+    let now = time();
+
+    let mut chunk_len = 0;
+    let mut chunk_editions = 0;
+    for pending in &job.pending {
+        if chunk_len > 0 && chunk_editions + pending.item.editions > BATCH_CHUNK_EDITIONS {
+            break;
+        }
+        chunk_len += 1;
+        chunk_editions += pending.item.editions;
+    }
+
+    let chunk: Vec<ValidatedBatchItem> = job.pending.drain(..chunk_len).collect();
+    for pending in chunk {
+        let ip = store_ip(job.owner, pending.item.ip, now);
+        let ip_id = ip.id.clone();
+        let plan = MintPlan {
+            total_editions: pending.total_editions,
+            royalty_percentage: pending.royalty_percentage,
+            collection_name: pending.collection_name,
+        };
+        let nfts = commit_mint(job.owner, ip, &pending.item.nft, pending.item.editions, &plan, now);
+        job.results.push(BatchMintResult {
+            index: pending.index,
+            ip_id,
+            nft_ids: nfts.into_iter().map(|nft| nft.id).collect(),
+        });
+    }
+
+    if job.pending.is_empty() {
+        job.status = BatchMintStatus::Complete;
+        job.completed_at = Some(now);
+    } else {
+        schedule_next_chunk(job.id.clone());
+    }
+
+    let progress = progress(&job);
+    with_batch_mints_mut(|jobs| {
+        jobs.insert(job.id.clone(), job);
+    });
+    Some(progress)
+}
+
+fn schedule_next_chunk(batch_id: String) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        commit_next_chunk(&batch_id);
+    });
+}
+
+// Called from post_upgrade: timers do not survive upgrades
+pub fn resume_batch_mints() {
+    let in_progress: Vec<String> = with_batch_mints(|jobs| {
+        jobs.iter()
+            .filter(|(_, job)| job.status == BatchMintStatus::InProgress)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for batch_id in in_progress {
+        schedule_next_chunk(batch_id);
+    }
+}
+
+#[query]
+pub fn get_batch_mint(batch_id: String) -> Result<BatchMintProgress> {
+    with_batch_mints(|jobs| jobs.get(&batch_id))
+        .map(|job| progress(&job))
+        .ok_or(IPMarketplaceError::NotFound)
+}
+
+#[query]
+pub fn get_my_batch_mints() -> Vec<BatchMintProgress> {
+    let caller = ic_cdk::caller();
+    with_batch_mints(|jobs| {
+        jobs.iter()
+            .filter(|(_, job)| job.owner == caller)
+            .map(|(_, job)| progress(&job))
+            .collect()
+    })
+}
@@ -0,0 +1,89 @@
+use ic_certification::{fork, labeled, leaf, pruned, AsHashTree, Hash, HashTree};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::Nat;
+use sha2::{Digest, Sha256};
+
+use crate::storage::*;
+use crate::runtime::set_certified_data;
+use crate::icrc3::tip;
+
+// The canister's certified data is the root hash of
+//
+//   http_assets      -> path -> SHA-256 of the response body
+//   last_block_hash  -> ICRC-3 tip hash
+//   last_block_index -> ICRC-3 tip index
+//
+// Labels are kept in sorted order so each subtree can be looked up in a witness.
+
+const HTTP_ASSETS_LABEL: &str = "http_assets";
+
+fn tip_tree() -> HashTree {
+    match tip() {
+        Some((index, hash)) => fork(
+            labeled("last_block_hash", leaf(hash.to_vec())),
+            labeled("last_block_index", leaf(leb128(index))),
+        ),
+        None => ic_certification::empty(),
+    }
+}
+
+fn assets_root() -> HashTree {
+    labeled(HTTP_ASSETS_LABEL, pruned(with_http_assets(|assets| assets.root_hash())))
+}
+
+// Called after every change to the block log or the HTTP assets, and from
+// post_upgrade
+pub fn update_certified_data() {
+    let root = fork(assets_root(), tip_tree());
+    set_certified_data(&root.digest());
+}
+
+// Witness for icrc3_get_tip_certificate; the HTTP assets are pruned
+pub fn tip_witness() -> HashTree {
+    fork(pruned(assets_root().digest()), tip_tree())
+}
+
+// Witness for one HTTP path; the block log tip is pruned
+pub fn asset_witness(path: &str) -> HashTree {
+    let assets = with_http_assets(|assets| assets.witness(path.as_bytes()));
+    fork(labeled(HTTP_ASSETS_LABEL, assets), pruned(tip_tree().digest()))
+}
+
+// Records the body served at `path`; pass None to stop certifying it. Call
+// update_certified_data afterwards.
+pub fn set_asset(path: &str, body: Option<&[u8]>) {
+    set_asset_hash(path, body.map(|body| Sha256::digest(body).into()));
+}
+
+// As set_asset, for bodies whose SHA-256 is already known
+pub fn set_asset_hash(path: &str, hash: Option<Hash>) {
+    with_http_assets_mut(|assets| match hash {
+        Some(hash) => assets.insert(path.to_string(), hash),
+        None => assets.delete(path.as_bytes()),
+    });
+}
+
+// IC-Certificate header for a response served at `path`. Only available in
+// query calls.
+pub fn certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let tree = encode_tree(&asset_witness(path));
+    Some((
+        "IC-Certificate".to_string(),
+        format!("certificate=:{}:, tree=:{}:", BASE64.encode(certificate), BASE64.encode(tree)),
+    ))
+}
+
+pub fn encode_tree(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer.self_describe().expect("failed to encode hash tree");
+    serde::Serialize::serialize(tree, &mut serializer).expect("failed to encode hash tree");
+    serializer.into_inner()
+}
+
+fn leb128(value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    Nat::from(value).encode(&mut bytes).expect("failed to encode nat");
+    bytes
+}
@@ -0,0 +1,326 @@
+use ic_cdk::{query, update};
+use candid::Principal;
+use std::collections::BTreeMap;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::assets::validate_image_reference;
+use crate::royalties::resolve_royalty_percentage;
+use crate::http::certify_collection;
+
+const MAX_COLLECTION_NAME_LENGTH: usize = 64;
+const MAX_COLLECTION_SYMBOL_LENGTH: usize = 16;
+const MAX_COLLECTION_DESCRIPTION_LENGTH: usize = 2_000;
+pub const MAX_ALLOWED_MINTERS: usize = 100;
+
+// Names are unique ignoring case, so "cryptopunks" cannot shadow "CryptoPunks"
+pub fn collection_name_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+pub fn find_collection(name: &str) -> Option<Collection> {
+    let collection_id = with_collection_names(|names| names.get(&collection_name_key(name)))?;
+    with_collections(|collections| collections.get(&collection_id))
+}
+
+fn validate_symbol(symbol: &str) -> Result<()> {
+    if symbol.trim().is_empty() || symbol.len() > MAX_COLLECTION_SYMBOL_LENGTH {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    Ok(())
+}
+
+fn validate_description(description: &str) -> Result<()> {
+    if description.len() > MAX_COLLECTION_DESCRIPTION_LENGTH {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    Ok(())
+}
+
+fn validate_banner(banner_url: &str) -> Result<()> {
+    if !validate_image_reference(banner_url) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    Ok(())
+}
+
+fn validate_allowed_minters(allowed_minters: &[Principal]) -> Result<()> {
+    if allowed_minters.len() > MAX_ALLOWED_MINTERS
+        || allowed_minters.contains(&Principal::anonymous())
+    {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    Ok(())
+}
+
+// Names already used by NFTs minted before collections existed stay free so
+// nobody can claim those NFTs
+fn collection_name_taken(name: &str) -> bool {
+    let key = collection_name_key(name);
+    with_collection_names(|names| names.contains_key(&key))
+        || with_reserved_collection_names(|names| names.contains_key(&key))
+}
+
+#[update]
+pub fn create_collection(request: CreateCollectionRequest) -> Result<Collection> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if caller == Principal::anonymous() {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+
+    let name = request.name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_COLLECTION_NAME_LENGTH {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    validate_symbol(&request.symbol)?;
+    validate_description(&request.description)?;
+    if let Some(ref banner_url) = request.banner_url {
+        validate_banner(banner_url)?;
+    }
+    if let Some(royalty_percentage) = request.default_royalty_percentage {
+        resolve_royalty_percentage(Some(royalty_percentage))?;
+    }
+    if request.max_supply == Some(0) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    validate_allowed_minters(&request.allowed_minters)?;
+
+    if collection_name_taken(&name) {
+        return Err(IPMarketplaceError::AlreadyExists);
+    }
+    let key = collection_name_key(&name);
+
+    let collection = Collection {
+        id: generate_id("COLLECTION"),
+        name,
+        symbol: request.symbol,
+        owner: caller,
+        description: request.description,
+        banner_url: request.banner_url,
+        default_royalty_percentage: request.default_royalty_percentage,
+        max_supply: request.max_supply,
+        allowed_minters: request.allowed_minters,
+        minted: 0,
+        created_at: now,
+        updated_at: now,
+    };
+
+    with_collections_mut(|collections| {
+        collections.insert(collection.id.clone(), collection.clone());
+    });
+    with_collection_names_mut(|names| {
+        names.insert(key, collection.id.clone());
+    });
+    certify_collection(&collection.name);
+
+    Ok(collection)
+}
+
+#[update]
+pub fn update_collection(collection_id: String, request: UpdateCollectionRequest) -> Result<Collection> {
+    let caller = ic_cdk::caller();
+
+    let mut collection = with_collections(|collections| {
+        collections.get(&collection_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
+
+    if collection.owner != caller {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+
+    if let Some(symbol) = request.symbol {
+        validate_symbol(&symbol)?;
+        collection.symbol = symbol;
+    }
+    if let Some(description) = request.description {
+        validate_description(&description)?;
+        collection.description = description;
+    }
+    if let Some(banner_url) = request.banner_url {
+        validate_banner(&banner_url)?;
+        collection.banner_url = Some(banner_url);
+    }
+    if let Some(royalty_percentage) = request.default_royalty_percentage {
+        resolve_royalty_percentage(Some(royalty_percentage))?;
+        collection.default_royalty_percentage = Some(royalty_percentage);
+    }
+    // Supply already minted cannot be taken back
+    if let Some(max_supply) = request.max_supply {
+        if max_supply == 0 || max_supply < collection.minted {
+            return Err(IPMarketplaceError::InvalidInput);
+        }
+        collection.max_supply = Some(max_supply);
+    }
+    if let Some(allowed_minters) = request.allowed_minters {
+        validate_allowed_minters(&allowed_minters)?;
+        collection.allowed_minters = allowed_minters;
+    }
+    collection.updated_at = time();
+
+    with_collections_mut(|collections| {
+        collections.insert(collection_id, collection.clone());
+    });
+    certify_collection(&collection.name);
+
+    Ok(collection)
+}
+
+// Checks that `caller` may mint `count` editions into the named collection,
+// on top of `reserved` claimed by earlier items of the same batch
+pub fn check_collection_mint(caller: Principal, name: &str, count: u32, reserved: u32) -> Result<Collection> {
+    let collection = find_collection(name).ok_or(IPMarketplaceError::NotFound)?;
+
+    if collection.owner != caller && !collection.allowed_minters.contains(&caller) {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+    if let Some(max_supply) = collection.max_supply {
+        let requested = collection.minted as u64 + reserved as u64 + count as u64;
+        if requested > max_supply as u64 {
+            return Err(IPMarketplaceError::InvalidInput);
+        }
+    }
+
+    Ok(collection)
+}
+
+// One-time migration: reserves the names of collections that only exist on NFTs
+// minted before collections did. Minting now needs a collection record, so no
+// new names of this kind appear.
+pub fn reserve_legacy_collection_names() {
+    let legacy: Vec<String> = with_nft_registry(|registry| {
+        registry
+            .iter()
+            .filter_map(|(_, nft)| nft.collection_name)
+            .map(|name| collection_name_key(&name))
+            .filter(|key| !with_collection_names(|names| names.contains_key(key)))
+            .collect()
+    });
+    with_reserved_collection_names_mut(|names| {
+        for key in legacy {
+            names.insert(key, ());
+        }
+    });
+}
+
+// Counts newly minted NFTs, all under one collection name, towards its supply
+pub fn record_collection_mint(collection_name: &str, nfts: &[IPNft]) {
+    let Some(first) = nfts.first() else {
+        return;
+    };
+    with_collection_supply_mut(|supply| {
+        let mut entry = supply.get(&collection_name.to_string()).unwrap_or_else(|| CollectionSupply {
+            total_supply: 0,
+            first_image: first.image.clone(),
+            first_royalty_percentage: first.royalty_percentage,
+            first_creator: first.creator,
+        });
+        entry.total_supply += nfts.len() as u32;
+        supply.insert(collection_name.to_string(), entry);
+    });
+}
+
+pub fn record_collection_burn(collection_name: &str) {
+    with_collection_supply_mut(|supply| {
+        if let Some(mut entry) = supply.get(&collection_name.to_string()) {
+            entry.total_supply = entry.total_supply.saturating_sub(1);
+            supply.insert(collection_name.to_string(), entry);
+        }
+    });
+}
+
+// One-time migration: counts the NFTs minted before supply was kept
+pub fn rebuild_collection_supply() {
+    let mut firsts: BTreeMap<String, (u32, IPNft)> = BTreeMap::new();
+    with_nft_registry(|registry| {
+        for (_, nft) in registry.iter() {
+            let Some(collection_name) = nft.collection_name.clone() else {
+                continue;
+            };
+            let (count, first) = firsts.entry(collection_name).or_insert_with(|| (0, nft.clone()));
+            *count += 1;
+            if nft.minted_at < first.minted_at {
+                *first = nft;
+            }
+        }
+    });
+
+    with_collection_supply_mut(|supply| {
+        supply.clear_new();
+        for (collection_name, (total_supply, first)) in firsts {
+            supply.insert(collection_name, CollectionSupply {
+                total_supply,
+                first_image: first.image,
+                first_royalty_percentage: first.royalty_percentage,
+                first_creator: first.creator,
+            });
+        }
+    });
+}
+
+// Counts `count` editions against the collection's max supply. Called once a
+// mint is accepted, so batches hold their supply while they are committed.
+pub fn reserve_collection_supply(name: &str, count: u32) {
+    let Some(collection_id) = with_collection_names(|names| names.get(&collection_name_key(name))) else {
+        return;
+    };
+    with_collections_mut(|collections| {
+        if let Some(mut collection) = collections.get(&collection_id) {
+            collection.minted += count;
+            collections.insert(collection_id, collection);
+        }
+    });
+}
+
+#[query]
+pub fn get_collection(collection_id: String) -> Result<Collection> {
+    with_collections(|collections| {
+        collections.get(&collection_id)
+    }).ok_or(IPMarketplaceError::NotFound)
+}
+
+#[query]
+pub fn get_collection_by_name(name: String) -> Result<Collection> {
+    find_collection(&name).ok_or(IPMarketplaceError::NotFound)
+}
+
+#[query]
+pub fn get_collections_by_owner(owner: Principal) -> Vec<Collection> {
+    with_collections(|collections| {
+        collections
+            .iter()
+            .filter(|(_, collection)| collection.owner == owner)
+            .map(|(_, collection)| collection)
+            .collect()
+    })
+}
+
+#[query]
+pub fn get_all_collections() -> Vec<Collection> {
+    with_collections(|collections| {
+        collections.iter().map(|(_, collection)| collection).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::mint_test_nft;
+
+    #[test]
+    fn legacy_collection_names_stay_reserved() {
+        let owner = Principal::from_slice(&[1]);
+        let mut nft = mint_test_nft("NFT_1", 1, owner, owner);
+        nft.collection_name = Some("CryptoPunks".to_string());
+        insert_nft(nft);
+        assert!(!collection_name_taken("cryptopunks"));
+
+        reserve_legacy_collection_names();
+
+        assert!(collection_name_taken("cryptopunks"));
+        assert!(collection_name_taken(" CRYPTOPUNKS "));
+        assert!(!collection_name_taken("Other"));
+    }
+}
@@ -0,0 +1,184 @@
+use ic_cdk::{query, update};
+use candid::{Nat, Principal};
+use std::ops::Bound;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::icrc7::take_value;
+use crate::trending::{record_activity, FAVORITE_WEIGHT};
+
+// Each user favorites an NFT at most once. The set is stored twice, keyed by
+// user and by NFT, so both directions page in key order. IPNft.favorite_count
+// mirrors the number of favoriters. Only a user's first favorite of an NFT adds
+// to its trending score, so toggling cannot push it up.
+
+fn set_favorite_count(nft_id: &str, count: u64) -> Option<u64> {
+    let mut nft = with_nft_registry(|registry| registry.get(&nft_id.to_string()))?;
+    nft.favorite_count = count;
+    insert_nft(nft);
+    Some(count)
+}
+
+fn count_favoriters(nft_id: &str) -> u64 {
+    let start = (IdKey(nft_id.to_string()), Principal::management_canister());
+    with_nft_favoriters(|favoriters| {
+        favoriters
+            .range(start..)
+            .take_while(|((id, _), _)| id.0 == nft_id)
+            .count() as u64
+    })
+}
+
+#[update]
+pub fn toggle_nft_favorite(nft_id: String) -> Result<u64> {
+    toggle_favorite(ic_cdk::caller(), nft_id)
+}
+
+fn toggle_favorite(caller: Principal, nft_id: String) -> Result<u64> {
+    if caller == Principal::anonymous() {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+
+    let exists = with_nft_registry(|registry| registry.contains_key(&nft_id));
+    if !exists {
+        return Err(IPMarketplaceError::NotFound);
+    }
+
+    let by_user = (caller, IdKey(nft_id.clone()));
+    let by_nft = (IdKey(nft_id.clone()), caller);
+    let was_favorite = with_favorites(|favorites| favorites.contains_key(&by_user));
+    if was_favorite {
+        with_favorites_mut(|favorites| favorites.remove(&by_user));
+        with_nft_favoriters_mut(|favoriters| favoriters.remove(&by_nft));
+    } else {
+        let now = time();
+        with_favorites_mut(|favorites| favorites.insert(by_user, now));
+        with_nft_favoriters_mut(|favoriters| favoriters.insert(by_nft.clone(), now));
+        let first_time = with_counted_favorites_mut(|counted| counted.insert(by_nft, ()).is_none());
+        if first_time {
+            record_activity(&nft_id, FAVORITE_WEIGHT);
+        }
+    }
+
+    set_favorite_count(&nft_id, count_favoriters(&nft_id)).ok_or(IPMarketplaceError::NotFound)
+}
+
+// Drops every favorite of a burned NFT
+pub fn remove_nft_favorites(nft_id: &str) {
+    let start = (IdKey(nft_id.to_string()), Principal::management_canister());
+    let favoriters: Vec<Principal> = with_nft_favoriters(|favoriters| {
+        favoriters
+            .range(start.clone()..)
+            .take_while(|((id, _), _)| id.0 == nft_id)
+            .map(|((_, user), _)| user)
+            .collect()
+    });
+    for user in favoriters {
+        with_favorites_mut(|favorites| favorites.remove(&(user, IdKey(nft_id.to_string()))));
+        with_nft_favoriters_mut(|by_nft| by_nft.remove(&(IdKey(nft_id.to_string()), user)));
+    }
+
+    let counted: Vec<Principal> = with_counted_favorites(|counted| {
+        counted
+            .range(start..)
+            .take_while(|((id, _), _)| id.0 == nft_id)
+            .map(|((_, user), _)| user)
+            .collect()
+    });
+    with_counted_favorites_mut(|by_nft| {
+        for user in counted {
+            by_nft.remove(&(IdKey(nft_id.to_string()), user));
+        }
+    });
+}
+
+// One-time migration: favorite_count used to be a bare counter that anyone
+// could bump, so bring it in line with the favorites set
+pub fn reconcile_favorite_counts() {
+    let stale: Vec<(String, u64)> = with_nft_registry(|registry| {
+        registry
+            .iter()
+            .map(|(id, nft)| (count_favoriters(&id), id, nft.favorite_count))
+            .filter(|(count, _, stored)| count != stored)
+            .map(|(count, id, _)| (id, count))
+            .collect()
+    });
+    for (nft_id, count) in stale {
+        set_favorite_count(&nft_id, count);
+    }
+}
+
+#[query]
+pub fn is_favorite(nft_id: String) -> bool {
+    let caller = ic_cdk::caller();
+    with_favorites(|favorites| favorites.contains_key(&(caller, IdKey(nft_id))))
+}
+
+// The caller's favorite NFTs in NFT ID order, starting after `prev`
+#[query]
+pub fn get_my_favorites(prev: Option<String>, take: Option<Nat>) -> Vec<IPNft> {
+    let caller = ic_cdk::caller();
+    let start = match prev {
+        Some(prev) => Bound::Excluded((caller, IdKey(prev))),
+        None => Bound::Included((caller, IdKey(String::new()))),
+    };
+
+    let nft_ids: Vec<String> = with_favorites(|favorites| {
+        favorites
+            .range((start, Bound::Unbounded))
+            .take_while(|((user, _), _)| *user == caller)
+            .map(|((_, nft_id), _)| nft_id.0)
+            .take(take_value(take))
+            .collect()
+    });
+    with_nft_registry(|registry| {
+        nft_ids.iter().filter_map(|nft_id| registry.get(nft_id)).collect()
+    })
+}
+
+// Users who favorited an NFT in principal order, starting after `prev`
+#[query]
+pub fn get_nft_favoriters(nft_id: String, prev: Option<Principal>, take: Option<Nat>) -> Vec<Principal> {
+    let start = match prev {
+        Some(prev) => Bound::Excluded((IdKey(nft_id.clone()), prev)),
+        None => Bound::Included((IdKey(nft_id.clone()), Principal::management_canister())),
+    };
+
+    with_nft_favoriters(|favoriters| {
+        favoriters
+            .range((start, Bound::Unbounded))
+            .take_while(|((id, _), _)| id.0 == nft_id)
+            .map(|((_, user), _)| user)
+            .take(take_value(take))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::mint_test_nft;
+
+    fn trending_score(nft_id: &str) -> f64 {
+        with_trending_nfts(|scores| scores.get(&nft_id.to_string())).map_or(0.0, |score| score.score)
+    }
+
+    #[test]
+    fn refavoriting_does_not_count_towards_trending_again() {
+        let user = Principal::from_slice(&[1]);
+        mint_test_nft("NFT_1", 1, user, user);
+
+        assert_eq!(toggle_favorite(user, "NFT_1".to_string()).unwrap(), 1);
+        assert_eq!(trending_score("NFT_1"), FAVORITE_WEIGHT);
+
+        for _ in 0..5 {
+            assert_eq!(toggle_favorite(user, "NFT_1".to_string()).unwrap(), 0);
+            assert_eq!(toggle_favorite(user, "NFT_1".to_string()).unwrap(), 1);
+        }
+        assert_eq!(trending_score("NFT_1"), FAVORITE_WEIGHT);
+
+        toggle_favorite(Principal::from_slice(&[2]), "NFT_1".to_string()).unwrap();
+        assert_eq!(trending_score("NFT_1"), 2.0 * FAVORITE_WEIGHT);
+    }
+}
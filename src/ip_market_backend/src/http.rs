@@ -0,0 +1,399 @@
+use ic_cdk::query;
+use serde_json::{json, Map, Value as JsonValue};
+use sha2::{Digest, Sha256};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::canister_id;
+use crate::certification::{certificate_header, set_asset, update_certified_data};
+use crate::assets::*;
+use crate::collections::find_collection;
+
+// Serves NFT metadata in the ERC-721 / OpenSea JSON format at
+// /metadata/<nft_id>, collection-level metadata at /collection/<name>, and
+// uploaded files at /assets/<id>.
+// Every body is hashed into the certified tree when the underlying data
+// changes, so query responses carry an IC-Certificate header.
+
+const METADATA_PREFIX: &str = "/metadata/";
+const COLLECTION_PREFIX: &str = "/collection/";
+
+const METADATA_CACHE_CONTROL: &str = "public, max-age=3600";
+const COLLECTION_CACHE_CONTROL: &str = "public, max-age=300";
+// Asset IDs are never reused, so their bytes never change
+const ASSET_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+pub fn metadata_path(nft_id: &str) -> String {
+    format!("{}{}", METADATA_PREFIX, nft_id)
+}
+
+pub fn collection_path(collection_name: &str) -> String {
+    format!("{}{}", COLLECTION_PREFIX, collection_name)
+}
+
+fn attribute_json(attribute: &NFTAttribute) -> JsonValue {
+    let mut entry = Map::new();
+    entry.insert("trait_type".to_string(), json!(attribute.trait_type));
+    let value = match &attribute.value {
+        AttributeValue::Text(t) => json!(t),
+        AttributeValue::Number(n) => json!(n),
+        AttributeValue::Boolean(b) => json!(b),
+    };
+    entry.insert("value".to_string(), value);
+    if let Some(ref display_type) = attribute.display_type {
+        entry.insert("display_type".to_string(), json!(display_type));
+    }
+    if let Some(max_value) = attribute.max_value {
+        entry.insert("max_value".to_string(), json!(max_value));
+    }
+    JsonValue::Object(entry)
+}
+
+// ERC-721 metadata JSON; None if the NFT does not exist
+pub fn metadata_json(nft_id: &str) -> Option<Vec<u8>> {
+    let metadata = with_nft_metadata(|registry| registry.get(&nft_id.to_string()))?;
+    let nft = with_nft_registry(|registry| registry.get(&nft_id.to_string()));
+
+    let mut body = Map::new();
+    body.insert("name".to_string(), json!(metadata.name));
+    body.insert("description".to_string(), json!(metadata.description));
+    body.insert("image".to_string(), json!(metadata.image));
+    if let Some(ref external_url) = metadata.external_url {
+        body.insert("external_url".to_string(), json!(external_url));
+    }
+    if let Some(ref animation_url) = metadata.animation_url {
+        body.insert("animation_url".to_string(), json!(animation_url));
+    }
+    if let Some(ref background_color) = metadata.background_color {
+        body.insert("background_color".to_string(), json!(background_color));
+    }
+    body.insert(
+        "attributes".to_string(),
+        JsonValue::Array(metadata.attributes.iter().map(attribute_json).collect()),
+    );
+
+    let mut properties = json!({
+        "ip_category": metadata.ip_category,
+        "ip_type": metadata.ip_type,
+        "creator": metadata.creator,
+        "creation_date": metadata.creation_date,
+        "jurisdiction": metadata.jurisdiction,
+        "license_type": metadata.license_type,
+        "file_type": metadata.file_type,
+        "file_size": metadata.file_size,
+        "minted_date": metadata.minted_date,
+        "blockchain": metadata.blockchain,
+        "token_standard": metadata.token_standard,
+    });
+    if let Some(nft) = nft {
+        properties["token_id"] = json!(nft.token_id);
+        properties["ip_id"] = json!(nft.ip_id);
+        properties["collection"] = json!(nft.collection_name);
+        properties["edition_number"] = json!(nft.edition_number);
+        properties["total_editions"] = json!(nft.total_editions);
+    }
+    body.insert("properties".to_string(), properties);
+
+    Some(JsonValue::Object(body).to_string().into_bytes())
+}
+
+// Contract-level metadata for a collection; None if there is neither a
+// collection record nor any NFT minted under the name
+pub fn collection_json(collection_name: &str) -> Option<Vec<u8>> {
+    let supply = with_collection_supply(|supply| supply.get(&collection_name.to_string()));
+
+    let body = match find_collection(collection_name) {
+        Some(collection) => {
+            let royalty_percentage = collection
+                .default_royalty_percentage
+                .or(supply.as_ref().map(|supply| supply.first_royalty_percentage))
+                .unwrap_or(0);
+            json!({
+                "name": collection.name,
+                "symbol": collection.symbol,
+                "description": collection.description,
+                "image": collection.banner_url.clone().or(supply.as_ref().map(|supply| supply.first_image.clone())),
+                "banner_image": collection.banner_url,
+                "seller_fee_basis_points": royalty_percentage as u32 * 100,
+                "fee_recipient": collection.owner.to_string(),
+                "total_supply": supply.map_or(0, |supply| supply.total_supply),
+                "max_supply": collection.max_supply,
+            })
+        }
+        None => {
+            let supply = supply.filter(|supply| supply.total_supply > 0)?;
+            json!({
+                "name": collection_name,
+                "description": format!("{} on the IP Marketplace", collection_name),
+                "image": supply.first_image,
+                "seller_fee_basis_points": supply.first_royalty_percentage as u32 * 100,
+                "fee_recipient": supply.first_creator.to_string(),
+                "total_supply": supply.total_supply,
+            })
+        }
+    };
+    Some(body.to_string().into_bytes())
+}
+
+// Re-hashes the bodies served for an NFT and its collection; called whenever
+// either changes
+pub fn certify_nft(nft_id: &str, collection_name: Option<&str>) {
+    certify_nfts(&[nft_id.to_string()], collection_name);
+}
+
+// As certify_nft for several NFTs in the same collection, such as the editions
+// from one mint
+pub fn certify_nfts(nft_ids: &[String], collection_name: Option<&str>) {
+    for nft_id in nft_ids {
+        set_asset(&metadata_path(nft_id), metadata_json(nft_id).as_deref());
+    }
+    if let Some(collection_name) = collection_name {
+        set_asset(&collection_path(collection_name), collection_json(collection_name).as_deref());
+    }
+    update_certified_data();
+}
+
+// Re-hashes a collection's metadata after its record changes
+pub fn certify_collection(collection_name: &str) {
+    set_asset(&collection_path(collection_name), collection_json(collection_name).as_deref());
+    update_certified_data();
+}
+
+// Called from post_upgrade: the certified tree lives on the heap
+pub fn certify_all() {
+    let nfts: Vec<(String, Option<String>)> = with_nft_registry(|registry| {
+        registry.iter().map(|(id, nft)| (id, nft.collection_name)).collect()
+    });
+    let mut collections = std::collections::BTreeSet::new();
+
+    for (nft_id, collection_name) in nfts {
+        set_asset(&metadata_path(&nft_id), metadata_json(&nft_id).as_deref());
+        collections.extend(collection_name);
+    }
+    with_collections(|records| {
+        collections.extend(records.iter().map(|(_, collection)| collection.name));
+    });
+    for collection_name in collections {
+        set_asset(&collection_path(&collection_name), collection_json(&collection_name).as_deref());
+    }
+    certify_all_assets();
+    update_certified_data();
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn response(status_code: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers,
+        body,
+        streaming_strategy: None,
+    }
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    response(
+        status_code,
+        vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        message.as_bytes().to_vec(),
+    )
+}
+
+fn json_response(path: &str, body: Vec<u8>, cache_control: &str) -> HttpResponse {
+    let mut headers = vec![
+        ("Content-Type".to_string(), "application/json; charset=utf-8".to_string()),
+        ("Cache-Control".to_string(), cache_control.to_string()),
+        ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ("ETag".to_string(), format!("\"{}\"", hex::encode(Sha256::digest(&body)))),
+    ];
+    headers.extend(certificate_header(path));
+    response(200, headers, body)
+}
+
+// Parses a single `bytes=` range against a body of `size` bytes into inclusive
+// bounds; Err(()) if it cannot be satisfied
+fn parse_range(header: &str, size: u64) -> Option<std::result::Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    // Multipart ranges are not supported; serve the whole body instead
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let bounds = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, size.saturating_sub(1)),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size.saturating_sub(1))),
+    };
+    if size == 0 || bounds.0 > bounds.1 || bounds.0 >= size {
+        return Some(Err(()));
+    }
+    Some(Ok(bounds))
+}
+
+fn asset_headers(asset: &StoredAsset) -> Vec<(String, String)> {
+    vec![
+        ("Content-Type".to_string(), asset.content_type.clone()),
+        ("Cache-Control".to_string(), ASSET_CACHE_CONTROL.to_string()),
+        ("Accept-Ranges".to_string(), "bytes".to_string()),
+        ("ETag".to_string(), format!("\"{}\"", asset.file_hash)),
+        ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+    ]
+}
+
+// Full responses are certified and streamed one chunk at a time. Range
+// responses are capped at one chunk and cannot be certified, so they are only
+// accepted by gateways that skip verification (e.g. the raw domain).
+fn asset_response(path: &str, asset: &StoredAsset, range_header: Option<&str>) -> HttpResponse {
+    let mut headers = asset_headers(asset);
+
+    if let Some(range) = range_header.and_then(|header| parse_range(header, asset.file_size)) {
+        let Ok((start, end)) = range else {
+            headers.push(("Content-Range".to_string(), format!("bytes */{}", asset.file_size)));
+            return response(416, headers, Vec::new());
+        };
+        let end = end.min(start + asset.chunk_size - 1);
+        headers.push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, asset.file_size)));
+        return response(206, headers, read_range(asset, start, end));
+    }
+
+    headers.extend(certificate_header(path));
+    let mut http_response = response(200, headers, read_chunk(asset.id, 0).unwrap_or_default());
+    if asset.chunk_count > 1 {
+        http_response.streaming_strategy = Some(StreamingStrategy::Callback {
+            callback: StreamingCallback::new(canister_id(), "http_request_streaming_callback".to_string()),
+            token: StreamingCallbackToken {
+                asset_id: asset.id,
+                chunk_index: 1,
+            },
+        });
+    }
+    http_response
+}
+
+#[query]
+pub fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let Some(asset) = get_complete_asset(token.asset_id) else {
+        ic_cdk::trap("asset not found");
+    };
+    let body = read_chunk(token.asset_id, token.chunk_index).unwrap_or_else(|| ic_cdk::trap("chunk not found"));
+    let next_chunk = token.chunk_index + 1;
+
+    StreamingCallbackHttpResponse {
+        body,
+        token: (next_chunk < asset.chunk_count).then_some(StreamingCallbackToken {
+            asset_id: token.asset_id,
+            chunk_index: next_chunk,
+        }),
+    }
+}
+
+#[query]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return error_response(405, "Method not allowed");
+    }
+
+    let raw_path = request.url.split(['?', '#']).next().unwrap_or("");
+    let path = percent_decode(raw_path);
+
+    if let Some(nft_id) = path.strip_prefix(METADATA_PREFIX) {
+        match metadata_json(nft_id) {
+            Some(body) => json_response(&path, body, METADATA_CACHE_CONTROL),
+            None => error_response(404, "NFT not found"),
+        }
+    } else if let Some(collection_name) = path.strip_prefix(COLLECTION_PREFIX) {
+        match collection_json(collection_name) {
+            Some(body) => json_response(&path, body, COLLECTION_CACHE_CONTROL),
+            None => error_response(404, "Collection not found"),
+        }
+    } else if let Some(asset_id) = path.strip_prefix(ASSET_PATH_PREFIX) {
+        let range_header = request
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("range"))
+            .map(|(_, value)| value.as_str());
+        match asset_id.parse().ok().and_then(get_complete_asset) {
+            Some(asset) => asset_response(&path, &asset, range_header),
+            None => error_response(404, "Asset not found"),
+        }
+    } else {
+        error_response(404, "Not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collections::{rebuild_collection_supply, record_collection_burn, record_collection_mint};
+    use crate::test_fixtures::mint_test_nft;
+    use candid::Principal;
+
+    const COLLECTION: &str = "Legacy Collection";
+
+    fn mint_into_collection(nft_id: &str, token_id: u64, image: &str) -> IPNft {
+        let creator = Principal::from_slice(&[3]);
+        let mut nft = mint_test_nft(nft_id, token_id, creator, creator);
+        nft.collection_name = Some(COLLECTION.to_string());
+        nft.image = image.to_string();
+        nft.minted_at = token_id;
+        insert_nft(nft.clone());
+        nft
+    }
+
+    fn served() -> Option<JsonValue> {
+        collection_json(COLLECTION).map(|body| serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn collection_json_follows_mints_and_burns() {
+        assert!(served().is_none());
+
+        let first = mint_into_collection("NFT_1", 1, "first.png");
+        let second = mint_into_collection("NFT_2", 2, "second.png");
+        record_collection_mint(COLLECTION, &[first, second]);
+
+        let body = served().unwrap();
+        assert_eq!(body["total_supply"], 2);
+        assert_eq!(body["image"], "first.png");
+        assert_eq!(body["seller_fee_basis_points"], 1_000);
+
+        record_collection_burn(COLLECTION);
+        assert_eq!(served().unwrap()["total_supply"], 1);
+        record_collection_burn(COLLECTION);
+        assert!(served().is_none());
+    }
+
+    #[test]
+    fn supply_is_rebuilt_from_the_registry() {
+        mint_into_collection("NFT_2", 2, "second.png");
+        mint_into_collection("NFT_1", 1, "first.png");
+
+        rebuild_collection_supply();
+
+        let body = served().unwrap();
+        assert_eq!(body["total_supply"], 2);
+        assert_eq!(body["image"], "first.png");
+    }
+}
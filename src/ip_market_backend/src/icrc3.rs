@@ -0,0 +1,347 @@
+use ic_cdk::query;
+use candid::{Nat, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::certification::{encode_tree, tip_witness, update_certified_data};
+use crate::icrc7::{nat_to_u64, owner_account};
+
+// ICRC-3 block log. Every NFT and marketplace event is appended as a block
+// holding the hash of its predecessor; the tip is certified so clients can
+// verify the whole chain.
+
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 1_000;
+
+// Block types defined by ICRC-7 and ICRC-37
+pub const BTYPE_MINT: &str = "7mint";
+pub const BTYPE_TRANSFER: &str = "7xfer";
+pub const BTYPE_BURN: &str = "7burn";
+pub const BTYPE_APPROVE: &str = "37approve";
+pub const BTYPE_APPROVE_COLLECTION: &str = "37approve_coll";
+pub const BTYPE_REVOKE: &str = "37revoke";
+pub const BTYPE_REVOKE_COLLECTION: &str = "37revoke_coll";
+pub const BTYPE_TRANSFER_FROM: &str = "37xfer";
+
+// Marketplace block types
+pub const BTYPE_LIST: &str = "ipm_list";
+pub const BTYPE_BID: &str = "ipm_bid";
+pub const BTYPE_SALE: &str = "ipm_sale";
+pub const BTYPE_CANCEL: &str = "ipm_cancel";
+
+// Representation-independent hash from the ICRC-3 specification
+pub fn hash_value(value: &Value) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    match value {
+        Value::Blob(bytes) => hasher.update(bytes),
+        Value::Text(text) => hasher.update(text.as_bytes()),
+        Value::Nat(n) => {
+            let mut bytes = Vec::new();
+            n.encode(&mut bytes).expect("failed to encode nat");
+            hasher.update(bytes);
+        }
+        Value::Int(i) => {
+            let mut bytes = Vec::new();
+            i.encode(&mut bytes).expect("failed to encode int");
+            hasher.update(bytes);
+        }
+        Value::Array(items) => {
+            for item in items {
+                hasher.update(hash_value(item));
+            }
+        }
+        Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+// Index and hash of the last block
+pub fn tip() -> Option<(u64, [u8; 32])> {
+    with_block_log(|log| {
+        let index = log.len().checked_sub(1)?;
+        log.get(index).map(|block| (index, hash_value(&block)))
+    })
+}
+
+// Appends a block and re-certifies the tip; returns the new block's index
+pub fn append_block(btype: &str, tx: Vec<(String, Value)>) -> u64 {
+    let mut block = Vec::new();
+    if let Some((_, parent_hash)) = tip() {
+        block.push(("phash".to_string(), Value::Blob(parent_hash.to_vec())));
+    }
+    block.push(("btype".to_string(), Value::Text(btype.to_string())));
+    block.push(("ts".to_string(), Value::Nat(Nat::from(time()))));
+    block.push(("tx".to_string(), Value::Map(tx)));
+
+    let index = with_block_log(|log| {
+        log.append(&Value::Map(block)).expect("failed to append block")
+    });
+    update_certified_data();
+    index
+}
+
+// Value builders
+pub fn account_value(account: &Account) -> Value {
+    let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(ref subaccount) = account.subaccount {
+        parts.push(Value::Blob(subaccount.clone()));
+    }
+    Value::Array(parts)
+}
+
+fn principal_value(principal: Principal) -> Value {
+    account_value(&Account {
+        owner: principal,
+        subaccount: None,
+    })
+}
+
+fn nat_value(value: u64) -> Value {
+    Value::Nat(Nat::from(value))
+}
+
+fn text_value(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+fn push_optional(tx: &mut Vec<(String, Value)>, memo: &Option<Vec<u8>>, created_at_time: Option<u64>) {
+    if let Some(ref memo) = memo {
+        tx.push(("memo".to_string(), Value::Blob(memo.clone())));
+    }
+    if let Some(created_at_time) = created_at_time {
+        tx.push(("ts".to_string(), nat_value(created_at_time)));
+    }
+}
+
+// Event logging
+pub fn log_mint(nft: &IPNft) -> u64 {
+    let tx = vec![
+        ("tid".to_string(), nat_value(nft.token_id)),
+        ("to".to_string(), principal_value(nft.owner)),
+        ("nid".to_string(), text_value(&nft.id)),
+        ("ip".to_string(), text_value(&nft.ip_id)),
+    ];
+    append_block(BTYPE_MINT, tx)
+}
+
+pub fn log_transfer(
+    token_id: u64,
+    from: &Account,
+    to: &Account,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> u64 {
+    let mut tx = vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("from".to_string(), account_value(from)),
+        ("to".to_string(), account_value(to)),
+    ];
+    push_optional(&mut tx, memo, created_at_time);
+    append_block(BTYPE_TRANSFER, tx)
+}
+
+pub fn log_burn(nft: &IPNft) -> u64 {
+    let tx = vec![
+        ("tid".to_string(), nat_value(nft.token_id)),
+        ("from".to_string(), account_value(&owner_account(nft))),
+        ("nid".to_string(), text_value(&nft.id)),
+    ];
+    append_block(BTYPE_BURN, tx)
+}
+
+pub fn log_transfer_from(
+    token_id: u64,
+    spender: &Account,
+    from: &Account,
+    to: &Account,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> u64 {
+    let mut tx = vec![
+        ("tid".to_string(), nat_value(token_id)),
+        ("spender".to_string(), account_value(spender)),
+        ("from".to_string(), account_value(from)),
+        ("to".to_string(), account_value(to)),
+    ];
+    push_optional(&mut tx, memo, created_at_time);
+    append_block(BTYPE_TRANSFER_FROM, tx)
+}
+
+// Logs a token approval (token_id set) or a collection approval
+pub fn log_approve(token_id: Option<u64>, from: &Account, approval: &ApprovalInfo) -> u64 {
+    let mut tx = Vec::new();
+    if let Some(token_id) = token_id {
+        tx.push(("tid".to_string(), nat_value(token_id)));
+    }
+    tx.push(("from".to_string(), account_value(from)));
+    tx.push(("spender".to_string(), account_value(&approval.spender)));
+    if let Some(expires_at) = approval.expires_at {
+        tx.push(("exp".to_string(), nat_value(expires_at)));
+    }
+    push_optional(&mut tx, &approval.memo, approval.created_at_time);
+    let btype = if token_id.is_some() { BTYPE_APPROVE } else { BTYPE_APPROVE_COLLECTION };
+    append_block(btype, tx)
+}
+
+// Logs a token revocation (token_id set) or a collection revocation; a missing
+// spender revokes every approval
+pub fn log_revoke(
+    token_id: Option<u64>,
+    from: &Account,
+    spender: &Option<Account>,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> u64 {
+    let mut tx = Vec::new();
+    if let Some(token_id) = token_id {
+        tx.push(("tid".to_string(), nat_value(token_id)));
+    }
+    tx.push(("from".to_string(), account_value(from)));
+    if let Some(ref spender) = spender {
+        tx.push(("spender".to_string(), account_value(spender)));
+    }
+    push_optional(&mut tx, memo, created_at_time);
+    let btype = if token_id.is_some() { BTYPE_REVOKE } else { BTYPE_REVOKE_COLLECTION };
+    append_block(btype, tx)
+}
+
+pub fn log_listing(listing: &MarketplaceListing) -> u64 {
+    let mut tx = vec![
+        ("lid".to_string(), text_value(&listing.id)),
+        ("nid".to_string(), text_value(&listing.nft_id)),
+        ("seller".to_string(), principal_value(listing.seller)),
+        ("price".to_string(), nat_value(listing.price)),
+        ("currency".to_string(), text_value(&listing.currency)),
+    ];
+    if let Some(ref auction_data) = listing.auction_data {
+        tx.push(("auction_end".to_string(), nat_value(auction_data.auction_end)));
+    }
+    if let Some(expires_at) = listing.expires_at {
+        tx.push(("exp".to_string(), nat_value(expires_at)));
+    }
+    append_block(BTYPE_LIST, tx)
+}
+
+pub fn log_bid(listing_id: &str, bidder: Principal, amount: u64) -> u64 {
+    let tx = vec![
+        ("lid".to_string(), text_value(listing_id)),
+        ("bidder".to_string(), principal_value(bidder)),
+        ("amount".to_string(), nat_value(amount)),
+    ];
+    append_block(BTYPE_BID, tx)
+}
+
+// `transfer_block` is the 7xfer block that moved the NFT to the buyer
+pub fn log_sale(breakdown: &SaleBreakdown, seller: Principal, buyer: Principal, transfer_block: u64) -> u64 {
+    let mut tx = vec![
+        ("lid".to_string(), text_value(&breakdown.listing_id)),
+        ("nid".to_string(), text_value(&breakdown.nft_id)),
+        ("seller".to_string(), principal_value(seller)),
+        ("buyer".to_string(), principal_value(buyer)),
+        ("price".to_string(), nat_value(breakdown.price)),
+        ("currency".to_string(), text_value(&breakdown.currency)),
+        ("fee".to_string(), nat_value(breakdown.platform_fee)),
+        ("royalty".to_string(), nat_value(breakdown.royalty)),
+        ("xfer".to_string(), nat_value(transfer_block)),
+    ];
+    if let Some(ref payment) = breakdown.transaction_hash {
+        tx.push(("payment".to_string(), text_value(payment)));
+    }
+    append_block(BTYPE_SALE, tx)
+}
+
+pub fn log_cancel(listing: &MarketplaceListing) -> u64 {
+    let tx = vec![
+        ("lid".to_string(), text_value(&listing.id)),
+        ("nid".to_string(), text_value(&listing.nft_id)),
+        ("seller".to_string(), principal_value(listing.seller)),
+    ];
+    append_block(BTYPE_CANCEL, tx)
+}
+
+// Queries
+#[query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    with_block_log(|log| {
+        let log_length = log.len();
+        let mut remaining = MAX_BLOCKS_PER_RESPONSE;
+        let mut blocks = Vec::new();
+
+        for arg in args {
+            let start = nat_to_u64(&arg.start).unwrap_or(u64::MAX).min(log_length);
+            let length = nat_to_u64(&arg.length).unwrap_or(u64::MAX).min(remaining);
+            let end = start.saturating_add(length).min(log_length);
+
+            for id in start..end {
+                if let Some(block) = log.get(id) {
+                    blocks.push(BlockWithId {
+                        id: Nat::from(id),
+                        block,
+                    });
+                }
+            }
+            remaining -= end - start;
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+#[query]
+pub fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    Some(DataCertificate {
+        certificate,
+        hash_tree: encode_tree(&tip_witness()),
+    })
+}
+
+#[query]
+pub fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    Vec::new()
+}
+
+#[query]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    let icrc7 = "https://github.com/dfinity/ICRC/ICRCs/ICRC-7";
+    let icrc37 = "https://github.com/dfinity/ICRC/ICRCs/ICRC-37";
+    let icrc3 = "https://github.com/dfinity/ICRC/ICRCs/ICRC-3";
+    [
+        (BTYPE_MINT, icrc7),
+        (BTYPE_TRANSFER, icrc7),
+        (BTYPE_BURN, icrc7),
+        (BTYPE_APPROVE, icrc37),
+        (BTYPE_APPROVE_COLLECTION, icrc37),
+        (BTYPE_REVOKE, icrc37),
+        (BTYPE_REVOKE_COLLECTION, icrc37),
+        (BTYPE_TRANSFER_FROM, icrc37),
+        (BTYPE_LIST, icrc3),
+        (BTYPE_BID, icrc3),
+        (BTYPE_SALE, icrc3),
+        (BTYPE_CANCEL, icrc3),
+    ]
+    .iter()
+    .map(|(block_type, url)| SupportedBlockType {
+        block_type: block_type.to_string(),
+        url: url.to_string(),
+    })
+    .collect()
+}
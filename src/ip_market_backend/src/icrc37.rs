@@ -0,0 +1,452 @@
+use ic_cdk::{query, update};
+use candid::{Nat, Principal};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::icrc7::*;
+use crate::nft_management::record_nft_transfer;
+use crate::marketplace::{cancel_listings_for_nft, ensure_listings_cancellable};
+use crate::icrc3::{log_approve, log_revoke, log_transfer_from};
+
+// ICRC-37 approvals. Token approvals are dropped whenever the token changes
+// hands; collection approvals belong to the owner and outlive transfers.
+
+pub const MAX_APPROVALS_PER_TOKEN_OR_COLLECTION: usize = 10;
+pub const MAX_REVOKE_APPROVALS: usize = 20;
+
+fn is_active(approval: &ApprovalInfo, now: u64) -> bool {
+    approval.expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+fn memo_too_long(memo: &Option<Vec<u8>>) -> bool {
+    memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_SIZE)
+}
+
+// Adds or replaces the approval for `approval.spender`, dropping expired ones
+fn upsert_approval(approvals: &mut Vec<ApprovalInfo>, approval: ApprovalInfo, now: u64) -> bool {
+    approvals.retain(|existing| {
+        is_active(existing, now)
+            && !(same_account(&existing.spender, &approval.spender)
+                && normalize_subaccount(&existing.from_subaccount) == normalize_subaccount(&approval.from_subaccount))
+    });
+    if approvals.len() >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+        return false;
+    }
+    approvals.push(approval);
+    true
+}
+
+// Removes approvals from `from_subaccount`, either for one spender or all of
+// them; returns how many were removed
+fn remove_approvals(approvals: &mut Vec<ApprovalInfo>, from_subaccount: &Option<Vec<u8>>, spender: &Option<Account>) -> usize {
+    let before = approvals.len();
+    let from_subaccount = normalize_subaccount(from_subaccount);
+    approvals.retain(|existing| {
+        normalize_subaccount(&existing.from_subaccount) != from_subaccount
+            || spender.as_ref().is_some_and(|s| !same_account(&existing.spender, s))
+    });
+    before - approvals.len()
+}
+
+fn approval_matches(approval: &ApprovalInfo, spender: &Account, from_subaccount: &Option<Vec<u8>>, now: u64) -> bool {
+    is_active(approval, now)
+        && same_account(&approval.spender, spender)
+        && normalize_subaccount(&approval.from_subaccount) == *from_subaccount
+}
+
+// True if `spender` may move `nft` out of its current owner's account
+pub fn is_spender_approved(nft: &IPNft, spender: &Account, now: u64) -> bool {
+    let from_subaccount = normalize_subaccount(&nft.owner_subaccount);
+
+    let token_approved = with_token_approvals(|approvals| approvals.get(&nft.token_id))
+        .is_some_and(|token| token.approvals.iter().any(|a| approval_matches(a, spender, &from_subaccount, now)));
+
+    token_approved
+        || with_collection_approvals(|approvals| approvals.get(&nft.owner))
+            .is_some_and(|collection| collection.approvals.iter().any(|a| approval_matches(a, spender, &from_subaccount, now)))
+}
+
+// Called from record_nft_transfer
+pub fn clear_token_approvals(token_id: u64) {
+    with_token_approvals_mut(|approvals| {
+        approvals.remove(&token_id);
+    });
+}
+
+fn invalid_spender(caller: Principal, spender: &Account) -> bool {
+    spender.owner == caller || spender.owner == Principal::anonymous()
+}
+
+// Approvals
+fn approve_token(caller: Principal, arg: ApproveTokenArg, now: u64) -> ApproveTokenResult {
+    let info = arg.approval_info.clone();
+
+    if memo_too_long(&info.memo) {
+        return Err(ApproveTokenError::GenericError {
+            error_code: Nat::from(ERROR_MEMO_TOO_LONG),
+            message: "memo exceeds icrc7:max_memo_size".to_string(),
+        });
+    }
+    check_created_at_time(info.created_at_time, now, ApproveTokenError::TooOld, |ledger_time| {
+        ApproveTokenError::CreatedInFuture { ledger_time }
+    })?;
+    let hash = transaction_hash("icrc37_approve_tokens", caller, &arg, info.created_at_time);
+    if let Some(block_index) = duplicate_of(&hash) {
+        return Err(ApproveTokenError::GenericError {
+            error_code: Nat::from(ERROR_DUPLICATE),
+            message: format!("duplicate of block {}", block_index),
+        });
+    }
+    if invalid_spender(caller, &info.spender) {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+    if !is_active(&info, now) {
+        return Err(ApproveTokenError::GenericError {
+            error_code: Nat::from(ERROR_ALREADY_EXPIRED),
+            message: "expires_at is in the past".to_string(),
+        });
+    }
+
+    let nft = nft_by_token_id(&arg.token_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    let from = Account {
+        owner: caller,
+        subaccount: info.from_subaccount.clone(),
+    };
+    if !same_account(&owner_account(&nft), &from) {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+
+    let added = with_token_approvals_mut(|approvals| {
+        let mut token = approvals.get(&nft.token_id).unwrap_or(TokenApprovals {
+            token_id: nft.token_id,
+            approvals: Vec::new(),
+        });
+        let added = upsert_approval(&mut token.approvals, info.clone(), now);
+        approvals.insert(nft.token_id, token);
+        added
+    });
+    if !added {
+        return Err(ApproveTokenError::GenericError {
+            error_code: Nat::from(ERROR_TOO_MANY_APPROVALS),
+            message: "too many approvals on this token".to_string(),
+        });
+    }
+
+    let block_index = log_approve(Some(nft.token_id), &owner_account(&nft), &info);
+    record_transaction(hash, info.created_at_time, block_index, now);
+    Ok(Nat::from(block_index))
+}
+
+#[update]
+pub fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let error = ApproveTokenError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} approvals per call", MAX_UPDATE_BATCH_SIZE),
+        };
+        return vec![Some(Err(error))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(approve_token(caller, arg, now)))
+        .collect()
+}
+
+fn approve_collection(caller: Principal, arg: ApproveCollectionArg, now: u64) -> ApproveCollectionResult {
+    let info = arg.approval_info.clone();
+
+    if memo_too_long(&info.memo) {
+        return Err(ApproveCollectionError::GenericError {
+            error_code: Nat::from(ERROR_MEMO_TOO_LONG),
+            message: "memo exceeds icrc7:max_memo_size".to_string(),
+        });
+    }
+    check_created_at_time(info.created_at_time, now, ApproveCollectionError::TooOld, |ledger_time| {
+        ApproveCollectionError::CreatedInFuture { ledger_time }
+    })?;
+    let hash = transaction_hash("icrc37_approve_collection", caller, &arg, info.created_at_time);
+    if let Some(block_index) = duplicate_of(&hash) {
+        return Err(ApproveCollectionError::GenericError {
+            error_code: Nat::from(ERROR_DUPLICATE),
+            message: format!("duplicate of block {}", block_index),
+        });
+    }
+    if invalid_spender(caller, &info.spender) {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+    if !is_active(&info, now) {
+        return Err(ApproveCollectionError::GenericError {
+            error_code: Nat::from(ERROR_ALREADY_EXPIRED),
+            message: "expires_at is in the past".to_string(),
+        });
+    }
+
+    let added = with_collection_approvals_mut(|approvals| {
+        let mut collection = approvals.get(&caller).unwrap_or(CollectionApprovals {
+            owner: caller,
+            approvals: Vec::new(),
+        });
+        let added = upsert_approval(&mut collection.approvals, info.clone(), now);
+        approvals.insert(caller, collection);
+        added
+    });
+    if !added {
+        return Err(ApproveCollectionError::GenericError {
+            error_code: Nat::from(ERROR_TOO_MANY_APPROVALS),
+            message: "too many collection approvals".to_string(),
+        });
+    }
+
+    let from = Account {
+        owner: caller,
+        subaccount: normalize_subaccount(&info.from_subaccount),
+    };
+    let block_index = log_approve(None, &from, &info);
+    record_transaction(hash, info.created_at_time, block_index, now);
+    Ok(Nat::from(block_index))
+}
+
+#[update]
+pub fn icrc37_approve_collection(args: Vec<ApproveCollectionArg>) -> Vec<Option<ApproveCollectionResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let error = ApproveCollectionError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} approvals per call", MAX_UPDATE_BATCH_SIZE),
+        };
+        return vec![Some(Err(error))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(approve_collection(caller, arg, now)))
+        .collect()
+}
+
+// Revocation
+fn revoke_token_approval(caller: Principal, arg: RevokeTokenApprovalArg, now: u64) -> RevokeTokenApprovalResult {
+    if memo_too_long(&arg.memo) {
+        return Err(RevokeTokenApprovalError::GenericError {
+            error_code: Nat::from(ERROR_MEMO_TOO_LONG),
+            message: "memo exceeds icrc7:max_memo_size".to_string(),
+        });
+    }
+    check_created_at_time(arg.created_at_time, now, RevokeTokenApprovalError::TooOld, |ledger_time| {
+        RevokeTokenApprovalError::CreatedInFuture { ledger_time }
+    })?;
+    let hash = transaction_hash("icrc37_revoke_token_approvals", caller, &arg, arg.created_at_time);
+    if let Some(block_index) = duplicate_of(&hash) {
+        return Err(RevokeTokenApprovalError::GenericError {
+            error_code: Nat::from(ERROR_DUPLICATE),
+            message: format!("duplicate of block {}", block_index),
+        });
+    }
+
+    let nft = nft_by_token_id(&arg.token_id).ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount.clone(),
+    };
+    if !same_account(&owner_account(&nft), &from) {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+
+    let removed = with_token_approvals_mut(|approvals| {
+        let mut token = approvals.get(&nft.token_id)?;
+        let removed = remove_approvals(&mut token.approvals, &arg.from_subaccount, &arg.spender);
+        if token.approvals.is_empty() {
+            approvals.remove(&nft.token_id);
+        } else {
+            approvals.insert(nft.token_id, token);
+        }
+        Some(removed)
+    });
+    if removed.unwrap_or(0) == 0 {
+        return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+    }
+
+    let block_index = log_revoke(Some(nft.token_id), &owner_account(&nft), &arg.spender, &arg.memo, arg.created_at_time);
+    record_transaction(hash, arg.created_at_time, block_index, now);
+    Ok(Nat::from(block_index))
+}
+
+#[update]
+pub fn icrc37_revoke_token_approvals(args: Vec<RevokeTokenApprovalArg>) -> Vec<Option<RevokeTokenApprovalResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if args.len() > MAX_REVOKE_APPROVALS {
+        let error = RevokeTokenApprovalError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} revocations per call", MAX_REVOKE_APPROVALS),
+        };
+        return vec![Some(Err(error))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(revoke_token_approval(caller, arg, now)))
+        .collect()
+}
+
+fn revoke_collection_approval(caller: Principal, arg: RevokeCollectionApprovalArg, now: u64) -> RevokeCollectionApprovalResult {
+    if memo_too_long(&arg.memo) {
+        return Err(RevokeCollectionApprovalError::GenericError {
+            error_code: Nat::from(ERROR_MEMO_TOO_LONG),
+            message: "memo exceeds icrc7:max_memo_size".to_string(),
+        });
+    }
+    check_created_at_time(arg.created_at_time, now, RevokeCollectionApprovalError::TooOld, |ledger_time| {
+        RevokeCollectionApprovalError::CreatedInFuture { ledger_time }
+    })?;
+    let hash = transaction_hash("icrc37_revoke_collection_approvals", caller, &arg, arg.created_at_time);
+    if let Some(block_index) = duplicate_of(&hash) {
+        return Err(RevokeCollectionApprovalError::GenericError {
+            error_code: Nat::from(ERROR_DUPLICATE),
+            message: format!("duplicate of block {}", block_index),
+        });
+    }
+
+    let removed = with_collection_approvals_mut(|approvals| {
+        let mut collection = approvals.get(&caller)?;
+        let removed = remove_approvals(&mut collection.approvals, &arg.from_subaccount, &arg.spender);
+        if collection.approvals.is_empty() {
+            approvals.remove(&caller);
+        } else {
+            approvals.insert(caller, collection);
+        }
+        Some(removed)
+    });
+    if removed.unwrap_or(0) == 0 {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+
+    let from = Account {
+        owner: caller,
+        subaccount: normalize_subaccount(&arg.from_subaccount),
+    };
+    let block_index = log_revoke(None, &from, &arg.spender, &arg.memo, arg.created_at_time);
+    record_transaction(hash, arg.created_at_time, block_index, now);
+    Ok(Nat::from(block_index))
+}
+
+#[update]
+pub fn icrc37_revoke_collection_approvals(args: Vec<RevokeCollectionApprovalArg>) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if args.len() > MAX_REVOKE_APPROVALS {
+        let error = RevokeCollectionApprovalError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} revocations per call", MAX_REVOKE_APPROVALS),
+        };
+        return vec![Some(Err(error))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(revoke_collection_approval(caller, arg, now)))
+        .collect()
+}
+
+// Delegated transfers
+fn transfer_from(caller: Principal, arg: Icrc37TransferFromArg, now: u64) -> Icrc37TransferFromResult {
+    if memo_too_long(&arg.memo) {
+        return Err(Icrc37TransferFromError::GenericError {
+            error_code: Nat::from(ERROR_MEMO_TOO_LONG),
+            message: "memo exceeds icrc7:max_memo_size".to_string(),
+        });
+    }
+    check_created_at_time(arg.created_at_time, now, Icrc37TransferFromError::TooOld, |ledger_time| {
+        Icrc37TransferFromError::CreatedInFuture { ledger_time }
+    })?;
+    let hash = transaction_hash("icrc37_transfer_from", caller, &arg, arg.created_at_time);
+    if let Some(duplicate_of) = duplicate_of(&hash) {
+        return Err(Icrc37TransferFromError::Duplicate { duplicate_of });
+    }
+
+    let nft = nft_by_token_id(&arg.token_id).ok_or(Icrc37TransferFromError::NonExistingTokenId)?;
+    let spender = Account {
+        owner: caller,
+        subaccount: arg.spender_subaccount.clone(),
+    };
+    if !same_account(&owner_account(&nft), &arg.from) || !is_spender_approved(&nft, &spender, now) {
+        return Err(Icrc37TransferFromError::Unauthorized);
+    }
+    if arg.to.owner == Principal::anonymous() || same_account(&arg.to, &arg.from) {
+        return Err(Icrc37TransferFromError::InvalidRecipient);
+    }
+    if !nft.is_transferable {
+        return Err(Icrc37TransferFromError::GenericError {
+            error_code: Nat::from(ERROR_NOT_TRANSFERABLE),
+            message: "token is not transferable".to_string(),
+        });
+    }
+    if ensure_listings_cancellable(&nft.id).is_err() {
+        return Err(Icrc37TransferFromError::GenericError {
+            error_code: Nat::from(ERROR_LISTING_NOT_CANCELLABLE),
+            message: "token is listed and the listing cannot be cancelled".to_string(),
+        });
+    }
+    cancel_listings_for_nft(&nft.id);
+
+    let to = Account {
+        owner: arg.to.owner,
+        subaccount: normalize_subaccount(&arg.to.subaccount),
+    };
+    let block_index = log_transfer_from(nft.token_id, &spender, &owner_account(&nft), &to, &arg.memo, arg.created_at_time);
+    record_nft_transfer(&nft.id, to, None, Some(block_index.to_string()), now);
+    record_transaction(hash, arg.created_at_time, block_index, now);
+
+    Ok(Nat::from(block_index))
+}
+
+#[update]
+pub fn icrc37_transfer_from(args: Vec<Icrc37TransferFromArg>) -> Vec<Option<Icrc37TransferFromResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let error = Icrc37TransferFromError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} transfers per call", MAX_UPDATE_BATCH_SIZE),
+        };
+        return vec![Some(Err(error))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(transfer_from(caller, arg, now)))
+        .collect()
+}
+
+// Queries
+#[query]
+pub fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    let now = time();
+    args.iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|arg| {
+            nft_by_token_id(&arg.token_id).is_some_and(|nft| {
+                normalize_subaccount(&nft.owner_subaccount) == normalize_subaccount(&arg.from_subaccount)
+                    && is_spender_approved(&nft, &arg.spender, now)
+            })
+        })
+        .collect()
+}
+
+#[query]
+pub fn icrc37_metadata() -> Vec<(String, Value)> {
+    vec![
+        (
+            "icrc37:max_approvals_per_token_or_collection".to_string(),
+            Value::Nat(Nat::from(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION as u64)),
+        ),
+        (
+            "icrc37:max_revoke_approvals".to_string(),
+            Value::Nat(Nat::from(MAX_REVOKE_APPROVALS as u64)),
+        ),
+    ]
+}
@@ -0,0 +1,451 @@
+use ic_cdk::{query, update};
+use candid::{CandidType, Nat, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::nft_management::record_nft_transfer;
+use crate::marketplace::{cancel_listings_for_nft, ensure_listings_cancellable};
+use crate::icrc3::log_transfer;
+
+// ICRC-7 view of NFT_REGISTRY: IPNft.token_id is the ICRC-7 token ID and the
+// owner account is (IPNft.owner, IPNft.owner_subaccount).
+
+pub const COLLECTION_NAME: &str = "IP Marketplace";
+pub const COLLECTION_SYMBOL: &str = "IPNFT";
+pub const COLLECTION_DESCRIPTION: &str = "Intellectual property registered and tokenized on the IP Marketplace";
+
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_TAKE_VALUE: usize = 100;
+pub const MAX_TAKE_VALUE: usize = 1_000;
+pub const MAX_MEMO_SIZE: usize = 32;
+pub const TX_WINDOW_NANOS: u64 = 24 * 3600 * 1_000_000_000;
+pub const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+// Generic error codes returned in the ICRC-7 and ICRC-37 GenericError variants
+pub const ERROR_NOT_TRANSFERABLE: u64 = 1;
+pub const ERROR_MEMO_TOO_LONG: u64 = 2;
+pub const ERROR_ALREADY_EXPIRED: u64 = 3;
+pub const ERROR_TOO_MANY_APPROVALS: u64 = 4;
+pub const ERROR_LISTING_NOT_CANCELLABLE: u64 = 5;
+// ICRC-37 approvals and revocations have no Duplicate variant
+pub const ERROR_DUPLICATE: u64 = 6;
+
+// Transaction hashes expired per recorded transaction; more than one, so the
+// deduplication map shrinks back after a burst
+const DEDUP_PRUNE_BATCH_SIZE: usize = 10;
+
+// ICRC-1 treats a missing subaccount and the all-zero subaccount as the same account
+pub fn normalize_subaccount(subaccount: &Option<Vec<u8>>) -> Option<Vec<u8>> {
+    match subaccount {
+        Some(bytes) if bytes.iter().any(|b| *b != 0) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+pub fn same_account(a: &Account, b: &Account) -> bool {
+    a.owner == b.owner && normalize_subaccount(&a.subaccount) == normalize_subaccount(&b.subaccount)
+}
+
+pub fn owner_account(nft: &IPNft) -> Account {
+    Account {
+        owner: nft.owner,
+        subaccount: normalize_subaccount(&nft.owner_subaccount),
+    }
+}
+
+pub fn nat_to_u64(n: &Nat) -> Option<u64> {
+    u64::try_from(n.0.clone()).ok()
+}
+
+pub fn nft_by_token_id(token_id: &Nat) -> Option<IPNft> {
+    let token_id = nat_to_u64(token_id)?;
+    let nft_id = with_token_index(|index| index.get(&token_id))?;
+    with_nft_registry(|registry| registry.get(&nft_id))
+}
+
+pub fn take_value(take: Option<Nat>) -> usize {
+    take.and_then(|t| nat_to_u64(&t))
+        .map_or(DEFAULT_TAKE_VALUE, |t| t as usize)
+        .min(MAX_TAKE_VALUE)
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+fn nat(value: u64) -> Value {
+    Value::Nat(Nat::from(value))
+}
+
+fn attribute_value(value: &AttributeValue) -> Value {
+    match value {
+        AttributeValue::Text(t) => text(t),
+        AttributeValue::Number(n) => text(&n.to_string()),
+        AttributeValue::Boolean(b) => text(&b.to_string()),
+    }
+}
+
+fn token_metadata(nft: &IPNft) -> Vec<(String, Value)> {
+    let mut entries = vec![
+        ("icrc7:name".to_string(), text(&nft.name)),
+        ("icrc7:description".to_string(), text(&nft.description)),
+        ("icrc7:image".to_string(), text(&nft.image)),
+        ("icrc7:metadata_uri".to_string(), text(&nft.metadata_uri)),
+        ("ip:id".to_string(), text(&nft.ip_id)),
+        ("ip:nft_id".to_string(), text(&nft.id)),
+        ("ip:creator".to_string(), text(&nft.creator.to_string())),
+        ("ip:royalty_percentage".to_string(), nat(nft.royalty_percentage as u64)),
+        ("ip:minted_at".to_string(), nat(nft.minted_at)),
+    ];
+    if let Some(ref collection) = nft.collection_name {
+        entries.push(("ip:collection".to_string(), text(collection)));
+    }
+    if let Some(edition) = nft.edition_number {
+        entries.push(("ip:edition_number".to_string(), nat(edition as u64)));
+    }
+    if let Some(total) = nft.total_editions {
+        entries.push(("ip:total_editions".to_string(), nat(total as u64)));
+    }
+
+    if let Some(metadata) = with_nft_metadata(|registry| registry.get(&nft.id)) {
+        entries.push(("ip:category".to_string(), text(&metadata.ip_category)));
+        entries.push(("ip:type".to_string(), text(&metadata.ip_type)));
+        let attributes = metadata
+            .attributes
+            .iter()
+            .map(|attr| (attr.trait_type.clone(), attribute_value(&attr.value)))
+            .collect();
+        entries.push(("ip:attributes".to_string(), Value::Map(attributes)));
+    }
+
+    entries
+}
+
+// Collection-level queries
+#[query]
+pub fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:name".to_string(), text(COLLECTION_NAME)),
+        ("icrc7:symbol".to_string(), text(COLLECTION_SYMBOL)),
+        ("icrc7:description".to_string(), text(COLLECTION_DESCRIPTION)),
+        ("icrc7:total_supply".to_string(), Value::Nat(icrc7_total_supply())),
+        ("icrc7:max_query_batch_size".to_string(), nat(MAX_QUERY_BATCH_SIZE as u64)),
+        ("icrc7:max_update_batch_size".to_string(), nat(MAX_UPDATE_BATCH_SIZE as u64)),
+        ("icrc7:default_take_value".to_string(), nat(DEFAULT_TAKE_VALUE as u64)),
+        ("icrc7:max_take_value".to_string(), nat(MAX_TAKE_VALUE as u64)),
+        ("icrc7:max_memo_size".to_string(), nat(MAX_MEMO_SIZE as u64)),
+        ("icrc7:atomic_batch_transfers".to_string(), text("false")),
+        ("icrc7:tx_window".to_string(), nat(TX_WINDOW_NANOS)),
+        ("icrc7:permitted_drift".to_string(), nat(PERMITTED_DRIFT_NANOS)),
+    ]
+}
+
+#[query]
+pub fn icrc7_name() -> String {
+    COLLECTION_NAME.to_string()
+}
+
+#[query]
+pub fn icrc7_symbol() -> String {
+    COLLECTION_SYMBOL.to_string()
+}
+
+#[query]
+pub fn icrc7_description() -> Option<String> {
+    Some(COLLECTION_DESCRIPTION.to_string())
+}
+
+#[query]
+pub fn icrc7_total_supply() -> Nat {
+    Nat::from(with_token_index(|index| index.len()))
+}
+
+#[query]
+pub fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-3".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-37".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-37".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
+
+// Token-level queries
+#[query]
+pub fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|token_id| nft_by_token_id(token_id).map(|nft| token_metadata(&nft)))
+        .collect()
+}
+
+#[query]
+pub fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|token_id| nft_by_token_id(token_id).map(|nft| owner_account(&nft)))
+        .collect()
+}
+
+#[query]
+pub fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts
+        .into_iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|account| {
+            let held = nfts_of_owner(account.owner)
+                .iter()
+                .filter(|nft| same_account(&account, &owner_account(nft)))
+                .count();
+            Nat::from(held)
+        })
+        .collect()
+}
+
+#[query]
+pub fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = match prev {
+        Some(prev) => match nat_to_u64(&prev) {
+            Some(p) if p < u64::MAX => p + 1,
+            _ => return Vec::new(),
+        },
+        None => 0,
+    };
+
+    with_token_index(|index| {
+        index
+            .range(start..)
+            .take(take_value(take))
+            .map(|(token_id, _)| Nat::from(token_id))
+            .collect()
+    })
+}
+
+#[query]
+pub fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let prev = prev.and_then(|p| nat_to_u64(&p));
+
+    let mut token_ids: Vec<u64> = nfts_of_owner(account.owner)
+        .iter()
+        .filter(|nft| same_account(&account, &owner_account(nft)))
+        .map(|nft| nft.token_id)
+        .filter(|token_id| prev.is_none_or(|p| *token_id > p))
+        .collect();
+    token_ids.sort_unstable();
+    token_ids.truncate(take_value(take));
+    token_ids.into_iter().map(Nat::from).collect()
+}
+
+// Transfers
+// Each standard has its own error type, so callers supply the two variants
+pub fn check_created_at_time<E>(
+    created_at_time: Option<u64>,
+    now: u64,
+    too_old: E,
+    created_in_future: impl FnOnce(u64) -> E,
+) -> std::result::Result<(), E> {
+    if let Some(created_at) = created_at_time {
+        if created_at + TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS < now {
+            return Err(too_old);
+        }
+        if created_at > now + PERMITTED_DRIFT_NANOS {
+            return Err(created_in_future(now));
+        }
+    }
+    Ok(())
+}
+
+// Deduplication. A call that sets created_at_time is identified by the hash of
+// its method, caller and argument; repeating it while it could still pass
+// check_created_at_time is answered with the block of the first call.
+pub fn transaction_hash<T: CandidType>(
+    method: &str,
+    caller: Principal,
+    arg: &T,
+    created_at_time: Option<u64>,
+) -> Option<[u8; 32]> {
+    created_at_time?;
+    let bytes = candid::encode_args((method, caller, arg))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to encode {} argument: {}", method, e)));
+    Some(Sha256::digest(bytes).into())
+}
+
+pub fn duplicate_of(hash: &Option<[u8; 32]>) -> Option<Nat> {
+    let hash = hash.as_ref()?;
+    with_recent_transactions(|transactions| transactions.get(hash)).map(Nat::from)
+}
+
+pub fn record_transaction(hash: Option<[u8; 32]>, created_at_time: Option<u64>, block_index: u64, now: u64) {
+    prune_transactions(now);
+    if let (Some(hash), Some(created_at)) = (hash, created_at_time) {
+        with_recent_transactions_mut(|transactions| transactions.insert(hash, block_index));
+        with_transaction_expiry_mut(|expiry| expiry.insert((created_at, hash), ()));
+    }
+}
+
+// Drops transactions old enough that a repeat would be rejected as TooOld
+fn prune_transactions(now: u64) {
+    let cutoff = now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS);
+    let expired: Vec<(u64, [u8; 32])> = with_transaction_expiry_mut(|expiry| {
+        expiry
+            .iter()
+            .take_while(|((created_at, _), _)| *created_at < cutoff)
+            .take(DEDUP_PRUNE_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in expired {
+        with_transaction_expiry_mut(|expiry| expiry.remove(&key));
+        with_recent_transactions_mut(|transactions| transactions.remove(&key.1));
+    }
+}
+
+fn generic_error(error_code: u64, message: &str) -> Icrc7TransferError {
+    Icrc7TransferError::GenericError {
+        error_code: Nat::from(error_code),
+        message: message.to_string(),
+    }
+}
+
+fn transfer_one(caller: Principal, arg: Icrc7TransferArg, now: u64) -> Icrc7TransferResult {
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount.clone(),
+    };
+
+    if arg.memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_SIZE) {
+        return Err(generic_error(ERROR_MEMO_TOO_LONG, "memo exceeds icrc7:max_memo_size"));
+    }
+    check_created_at_time(arg.created_at_time, now, Icrc7TransferError::TooOld, |ledger_time| {
+        Icrc7TransferError::CreatedInFuture { ledger_time }
+    })?;
+    let hash = transaction_hash("icrc7_transfer", caller, &arg, arg.created_at_time);
+    if let Some(duplicate_of) = duplicate_of(&hash) {
+        return Err(Icrc7TransferError::Duplicate { duplicate_of });
+    }
+
+    let nft = nft_by_token_id(&arg.token_id).ok_or(Icrc7TransferError::NonExistingTokenId)?;
+
+    if !same_account(&owner_account(&nft), &from) {
+        return Err(Icrc7TransferError::Unauthorized);
+    }
+    if arg.to.owner == Principal::anonymous() || same_account(&arg.to, &from) {
+        return Err(Icrc7TransferError::InvalidRecipient);
+    }
+    if !nft.is_transferable {
+        return Err(generic_error(ERROR_NOT_TRANSFERABLE, "token is not transferable"));
+    }
+    if ensure_listings_cancellable(&nft.id).is_err() {
+        return Err(generic_error(ERROR_LISTING_NOT_CANCELLABLE, "token is listed and the listing cannot be cancelled"));
+    }
+    cancel_listings_for_nft(&nft.id);
+
+    let to = Account {
+        owner: arg.to.owner,
+        subaccount: normalize_subaccount(&arg.to.subaccount),
+    };
+    let block_index = log_transfer(nft.token_id, &owner_account(&nft), &to, &arg.memo, arg.created_at_time);
+    record_nft_transfer(&nft.id, to, None, Some(block_index.to_string()), now);
+    record_transaction(hash, arg.created_at_time, block_index, now);
+
+    Ok(Nat::from(block_index))
+}
+
+// Transfers are applied independently; each entry's result is returned in order
+#[update]
+pub fn icrc7_transfer(args: Vec<Icrc7TransferArg>) -> Vec<Option<Icrc7TransferResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let error = Icrc7TransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} transfers per call", MAX_UPDATE_BATCH_SIZE),
+        };
+        return vec![Some(Err(error))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(transfer_one(caller, arg, now)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::mock::{set_time, START_TIME};
+    use crate::test_fixtures::mint_test_nft;
+
+    fn alice() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn bob() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn transfer_arg(to: Principal, created_at_time: Option<u64>) -> Icrc7TransferArg {
+        Icrc7TransferArg {
+            from_subaccount: None,
+            to: Account { owner: to, subaccount: None },
+            token_id: Nat::from(1u64),
+            memo: None,
+            created_at_time,
+        }
+    }
+
+    #[test]
+    fn repeated_transfer_is_answered_with_the_first_block() {
+        mint_test_nft("NFT_1", 1, alice(), alice());
+        let arg = transfer_arg(bob(), Some(START_TIME));
+
+        let block_index = transfer_one(alice(), arg.clone(), START_TIME).unwrap();
+        let repeat = transfer_one(alice(), arg, START_TIME + 1);
+
+        assert!(matches!(repeat, Err(Icrc7TransferError::Duplicate { duplicate_of }) if duplicate_of == block_index));
+    }
+
+    #[test]
+    fn transfers_without_created_at_time_are_not_deduplicated() {
+        mint_test_nft("NFT_1", 1, alice(), alice());
+
+        transfer_one(alice(), transfer_arg(bob(), None), START_TIME).unwrap();
+        transfer_one(bob(), transfer_arg(alice(), None), START_TIME).unwrap();
+        let repeat = transfer_one(alice(), transfer_arg(bob(), None), START_TIME);
+
+        assert!(repeat.is_ok());
+    }
+
+    #[test]
+    fn transactions_expire_with_the_window() {
+        set_time(START_TIME);
+        mint_test_nft("NFT_1", 1, alice(), alice());
+        let first = transfer_arg(bob(), Some(START_TIME));
+        transfer_one(alice(), first.clone(), START_TIME).unwrap();
+
+        // A later transaction prunes the first once it is past the window
+        let later = START_TIME + TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS + 1;
+        transfer_one(bob(), transfer_arg(alice(), Some(later)), later).unwrap();
+
+        let hash = transaction_hash("icrc7_transfer", alice(), &first, first.created_at_time);
+        assert!(duplicate_of(&hash).is_none());
+        assert!(matches!(transfer_one(alice(), first, later), Err(Icrc7TransferError::TooOld)));
+    }
+}
@@ -1,23 +1,41 @@
-use ic_cdk::api::time;
 use ic_cdk::{query, update};
 use candid::Principal;
 
 use crate::types::*;
 use crate::storage::*;
-use crate::utils::*;
+use crate::runtime::time;
+use crate::access_control::require_verifier;
+use crate::assets::{validate_image_reference, validate_stored_file};
+use crate::text_search::{index_ip_text, text_scores, Corpus};
 
 #[update]
 pub fn register_ip(request: RegisterIPRequest) -> Result<IntellectualProperty> {
-    let caller = ic_cdk::caller();
-    let now = time();
-    
+    validate_register_ip(&request)?;
+    Ok(store_ip(ic_cdk::caller(), request, time()))
+}
+
+// Checks a registration without storing anything; shared with batch minting
+pub fn validate_register_ip(request: &RegisterIPRequest) -> Result<()> {
     // Validate image URL if provided
     if let Some(ref url) = request.image_url {
-        if !validate_image_url(url) {
+        if !validate_image_reference(url) {
             return Err(IPMarketplaceError::InvalidInput);
         }
     }
     
+    // Files stored on-chain must match their declared hashes
+    if let Some(ref url) = request.metadata.file_url {
+        validate_stored_file(url, request.metadata.file_hash.as_deref(), None)?;
+    }
+    for file in &request.additional_files {
+        validate_stored_file(&file.file_url, Some(&file.file_hash), Some(file.file_size))?;
+    }
+    
+    Ok(())
+}
+
+// Stores a validated registration for `caller`
+pub fn store_ip(caller: Principal, request: RegisterIPRequest, now: u64) -> IntellectualProperty {
     // Generate unique ID for the IP
     let ip_id = generate_id("IP");
     
@@ -33,15 +51,16 @@ pub fn register_ip(request: RegisterIPRequest) -> Result<IntellectualProperty> {
         registration_date: now,
         metadata: request.metadata,
         verification_status: VerificationStatus::Pending,
-        nft_id: None,
+        nft_ids: Vec::new(),
+        total_editions: None,
+        minted_editions: 0,
         image_url: request.image_url,
         additional_files: request.additional_files,
     };
     
     // Store in registry
-    with_ip_registry_mut(|registry| {
-        registry.insert(ip_id.clone(), ip.clone());
-    });
+    insert_ip(ip.clone());
+    index_ip_text(&ip);
     
     // Update user profile
     with_user_registry_mut(|registry| {
@@ -51,7 +70,7 @@ pub fn register_ip(request: RegisterIPRequest) -> Result<IntellectualProperty> {
         }
     });
     
-    Ok(ip)
+    ip
 }
 
 #[query]
@@ -63,49 +82,41 @@ pub fn get_ip_by_id(ip_id: String) -> Result<IntellectualProperty> {
 
 #[query]
 pub fn get_user_ips(user: Principal) -> Vec<IntellectualProperty> {
-    with_ip_registry(|registry| {
-        registry
-            .iter()
-            .filter(|(_, ip)| ip.owner == user)
-            .map(|(_, ip)| ip.clone())
-            .collect()
-    })
+    ips_of_owner(user)
 }
 
+// IPs matching every word of `query`, most relevant first; an empty query
+// matches every IP
 #[query]
-pub fn search_ips(query: String, ip_type: Option<IPType>) -> Vec<IntellectualProperty> {
-    let query_lower = query.to_lowercase();
+pub fn search_ips(query: String, ip_type: Option<IPType>) -> Vec<IPSearchHit> {
+    let scores = text_scores(Corpus::Ips, &query);
+    let candidates: Vec<IntellectualProperty> = with_ip_registry(|registry| match scores {
+        Some(ref scores) => scores.keys().filter_map(|id| registry.get(id)).collect(),
+        None => registry.iter().map(|(_, ip)| ip).collect(),
+    });
     
-    with_ip_registry(|registry| {
-        registry
-            .iter()
-            .filter(|(_, ip)| {
-                let matches_query = ip.title.to_lowercase().contains(&query_lower) ||
-                                  ip.description.to_lowercase().contains(&query_lower) ||
-                                  ip.metadata.tags.iter().any(|tag| tag.to_lowercase().contains(&query_lower));
-                
-                let matches_type = match &ip_type {
-                    Some(t) => std::mem::discriminant(&ip.ip_type) == std::mem::discriminant(t),
-                    None => true,
-                };
-                
-                matches_query && matches_type
-            })
-            .map(|(_, ip)| ip.clone())
-            .collect()
-    })
+    let mut hits: Vec<IPSearchHit> = candidates
+        .into_iter()
+        .filter(|ip| match &ip_type {
+            Some(t) => std::mem::discriminant(&ip.ip_type) == std::mem::discriminant(t),
+            None => true,
+        })
+        .map(|ip| {
+            let score = scores.as_ref().and_then(|scores| scores.get(&ip.id)).copied().unwrap_or(0.0);
+            IPSearchHit { ip, score }
+        })
+        .collect();
+    // Ties keep IP ID order
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits
 }
 
 #[update]
 pub fn verify_ip(ip_id: String, status: VerificationStatus) -> Result<bool> {
-    // In production, this should check for admin privileges
-    with_ip_registry_mut(|registry| {
-        if let Some(mut ip) = registry.get(&ip_id) {
-            ip.verification_status = status;
-            registry.insert(ip_id, ip);
-            Ok(true)
-        } else {
-            Err(IPMarketplaceError::NotFound)
-        }
-    })
+    require_verifier()?;
+    
+    let mut ip = with_ip_registry(|registry| registry.get(&ip_id)).ok_or(IPMarketplaceError::NotFound)?;
+    ip.verification_status = status;
+    insert_ip(ip);
+    Ok(true)
 }
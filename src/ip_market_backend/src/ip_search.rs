@@ -0,0 +1,176 @@
+use ic_cdk::query;
+use candid::Nat;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::icrc7::{nat_to_u64, take_value};
+use crate::text_search::{text_scores, Corpus};
+
+// Faceted search over the IP registry. Each IP is checked against every filter
+// dimension separately, so one pass yields both the matches and, per dimension,
+// the counts under all the other filters.
+
+// Most values reported per text facet, highest counts first
+pub const MAX_FACET_VALUES: usize = 50;
+
+const TYPE: usize = 0;
+const CATEGORY: usize = 1;
+const TAGS: usize = 2;
+const JURISDICTION: usize = 3;
+const VERIFICATION: usize = 4;
+const HAS_NFT: usize = 5;
+const DIMENSIONS: usize = 6;
+
+fn normalize(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+fn in_range(timestamp: u64, range: &DateRange) -> bool {
+    range.from.is_none_or(|from| timestamp >= from) && range.to.is_none_or(|to| timestamp <= to)
+}
+
+fn matches_dates(ip: &IntellectualProperty, filters: &IPSearchFilters) -> bool {
+    filters.created.as_ref().is_none_or(|range| in_range(ip.creation_date, range)) &&
+    filters.registered.as_ref().is_none_or(|range| in_range(ip.registration_date, range)) &&
+    filters.expires.as_ref().is_none_or(|range| {
+        ip.metadata.expiry_date.is_some_and(|expiry| in_range(expiry, range))
+    })
+}
+
+fn dimension_matches(ip: &IntellectualProperty, filters: &IPSearchFilters) -> [bool; DIMENSIONS] {
+    let tags: BTreeSet<String> = ip.metadata.tags.iter().map(|tag| normalize(tag)).collect();
+    let mut matches = [true; DIMENSIONS];
+
+    matches[TYPE] = filters.ip_type.as_ref().is_none_or(|ip_type| *ip_type == ip.ip_type);
+    matches[CATEGORY] = filters
+        .category
+        .as_ref()
+        .is_none_or(|category| normalize(category) == normalize(&ip.metadata.category));
+    matches[TAGS] = (filters.tags_any.is_empty() ||
+                     filters.tags_any.iter().any(|tag| tags.contains(&normalize(tag)))) &&
+                    filters.tags_all.iter().all(|tag| tags.contains(&normalize(tag)));
+    matches[JURISDICTION] = filters
+        .jurisdiction
+        .as_ref()
+        .is_none_or(|jurisdiction| normalize(jurisdiction) == normalize(&ip.metadata.jurisdiction));
+    matches[VERIFICATION] = filters
+        .verification_status
+        .as_ref()
+        .is_none_or(|status| *status == ip.verification_status);
+    matches[HAS_NFT] = filters.has_nft.is_none_or(|has_nft| has_nft != ip.nft_ids.is_empty());
+    matches
+}
+
+// Whether the IP passes every dimension but `skip`
+fn matches_except(matches: &[bool; DIMENSIONS], skip: usize) -> bool {
+    matches.iter().enumerate().all(|(dimension, matched)| dimension == skip || *matched)
+}
+
+fn top_values(counts: BTreeMap<String, u64>) -> Vec<FacetCount> {
+    let mut values: Vec<FacetCount> = counts
+        .into_iter()
+        .map(|(value, count)| FacetCount { value, count })
+        .collect();
+    // Ties keep value order
+    values.sort_by_key(|facet| Reverse(facet.count));
+    values.truncate(MAX_FACET_VALUES);
+    values
+}
+
+#[derive(Default)]
+struct FacetTally {
+    ip_types: Vec<IPTypeFacetCount>,
+    categories: BTreeMap<String, u64>,
+    tags: BTreeMap<String, u64>,
+    jurisdictions: BTreeMap<String, u64>,
+    verification_statuses: Vec<VerificationFacetCount>,
+    with_nft: u64,
+    without_nft: u64,
+}
+
+impl FacetTally {
+    fn add(&mut self, ip: &IntellectualProperty, matches: &[bool; DIMENSIONS]) {
+        if matches_except(matches, TYPE) {
+            match self.ip_types.iter_mut().find(|facet| facet.ip_type == ip.ip_type) {
+                Some(facet) => facet.count += 1,
+                None => self.ip_types.push(IPTypeFacetCount { ip_type: ip.ip_type.clone(), count: 1 }),
+            }
+        }
+        if matches_except(matches, CATEGORY) {
+            *self.categories.entry(normalize(&ip.metadata.category)).or_insert(0) += 1;
+        }
+        if matches_except(matches, TAGS) {
+            let tags: BTreeSet<String> = ip.metadata.tags.iter().map(|tag| normalize(tag)).collect();
+            for tag in tags {
+                *self.tags.entry(tag).or_insert(0) += 1;
+            }
+        }
+        if matches_except(matches, JURISDICTION) {
+            *self.jurisdictions.entry(normalize(&ip.metadata.jurisdiction)).or_insert(0) += 1;
+        }
+        if matches_except(matches, VERIFICATION) {
+            match self.verification_statuses.iter_mut().find(|facet| facet.status == ip.verification_status) {
+                Some(facet) => facet.count += 1,
+                None => self.verification_statuses.push(VerificationFacetCount {
+                    status: ip.verification_status.clone(),
+                    count: 1,
+                }),
+            }
+        }
+        if matches_except(matches, HAS_NFT) {
+            if ip.nft_ids.is_empty() {
+                self.without_nft += 1;
+            } else {
+                self.with_nft += 1;
+            }
+        }
+    }
+
+    fn finish(mut self) -> IPSearchFacets {
+        self.ip_types.sort_by_key(|facet| Reverse(facet.count));
+        self.verification_statuses.sort_by_key(|facet| Reverse(facet.count));
+        IPSearchFacets {
+            ip_types: self.ip_types,
+            categories: top_values(self.categories),
+            tags: top_values(self.tags),
+            jurisdictions: top_values(self.jurisdictions),
+            verification_statuses: self.verification_statuses,
+            with_nft: self.with_nft,
+            without_nft: self.without_nft,
+        }
+    }
+}
+
+// IPs matching the query text and every filter, most relevant first and then
+// in IP ID order, skipping `offset` and returning up to `take`; the facets
+// cover all matches, not just the page
+#[query]
+pub fn faceted_search_ips(filters: IPSearchFilters, offset: Option<Nat>, take: Option<Nat>) -> IPSearchResults {
+    let scores = filters.query.as_deref().and_then(|query| text_scores(Corpus::Ips, query));
+    let candidates: Vec<IntellectualProperty> = with_ip_registry(|registry| match scores {
+        Some(ref scores) => scores.keys().filter_map(|id| registry.get(id)).collect(),
+        None => registry.iter().map(|(_, ip)| ip).collect(),
+    });
+
+    let mut tally = FacetTally::default();
+    let mut hits = Vec::new();
+    for ip in candidates.into_iter().filter(|ip| matches_dates(ip, &filters)) {
+        let matches = dimension_matches(&ip, &filters);
+        tally.add(&ip, &matches);
+        if matches.iter().all(|matched| *matched) {
+            let score = scores.as_ref().and_then(|scores| scores.get(&ip.id)).copied().unwrap_or(0.0);
+            hits.push(IPSearchHit { ip, score });
+        }
+    }
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let total_count = hits.len() as u64;
+    let offset = offset.and_then(|offset| nat_to_u64(&offset)).unwrap_or(0) as usize;
+    IPSearchResults {
+        hits: hits.into_iter().skip(offset).take(take_value(take)).collect(),
+        total_count,
+        facets: tally.finish(),
+    }
+}
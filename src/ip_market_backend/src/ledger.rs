@@ -0,0 +1,184 @@
+// Calls into ICRC-1/ICRC-2 ledger canisters. Unit tests run outside a canister
+// and get the scripted ledger in `mock` instead.
+
+#[cfg(not(test))]
+pub use ic::{icrc1_balance_of, icrc1_fee, icrc1_transfer, icrc2_allowance, icrc2_transfer_from};
+
+#[cfg(not(test))]
+mod ic {
+    use candid::{Nat, Principal};
+    use ic_cdk::api::call::CallResult;
+
+    use crate::types::*;
+
+    pub async fn icrc1_fee(ledger: Principal) -> CallResult<Nat> {
+        ic_cdk::call(ledger, "icrc1_fee", ()).await.map(|(fee,)| fee)
+    }
+
+    pub async fn icrc1_balance_of(ledger: Principal, account: Account) -> CallResult<Nat> {
+        ic_cdk::call(ledger, "icrc1_balance_of", (account,)).await.map(|(balance,)| balance)
+    }
+
+    pub async fn icrc2_allowance(ledger: Principal, args: AllowanceArgs) -> CallResult<Allowance> {
+        ic_cdk::call(ledger, "icrc2_allowance", (args,)).await.map(|(allowance,)| allowance)
+    }
+
+    pub async fn icrc1_transfer(
+        ledger: Principal,
+        args: TransferArg,
+    ) -> CallResult<std::result::Result<Nat, TransferError>> {
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await.map(|(result,)| result)
+    }
+
+    pub async fn icrc2_transfer_from(
+        ledger: Principal,
+        args: TransferFromArgs,
+    ) -> CallResult<std::result::Result<Nat, TransferFromError>> {
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await.map(|(result,)| result)
+    }
+}
+
+#[cfg(test)]
+pub use mock::{icrc1_balance_of, icrc1_fee, icrc1_transfer, icrc2_allowance, icrc2_transfer_from};
+
+// Accepts every call, charging a fixed fee and handing out increasing block
+// indices, unless a test queues a different result. Every account holds and has
+// approved an unlimited amount unless a test sets it. Calls are recorded so tests
+// can check what was sent.
+#[cfg(test)]
+pub mod mock {
+    use candid::{Nat, Principal};
+    use ic_cdk::api::call::{CallResult, RejectionCode};
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+
+    use crate::types::*;
+
+    pub const DEFAULT_FEE: u64 = 10;
+
+    type TransferResult = CallResult<std::result::Result<Nat, TransferError>>;
+    type TransferFromResult = CallResult<std::result::Result<Nat, TransferFromError>>;
+
+    struct MockLedger {
+        fee: u64,
+        next_block: u64,
+        transfers: Vec<TransferArg>,
+        transfers_from: Vec<TransferFromArgs>,
+        transfer_results: VecDeque<TransferResult>,
+        transfer_from_results: VecDeque<TransferFromResult>,
+        balances: BTreeMap<Principal, u64>,
+        allowances: BTreeMap<Principal, Allowance>,
+        // Runs while an icrc2_transfer_from is in flight, standing in for
+        // messages that interleave with the call
+        during_transfer_from: Option<Box<dyn FnOnce()>>,
+    }
+
+    thread_local! {
+        static LEDGER: RefCell<MockLedger> = RefCell::new(MockLedger {
+            fee: DEFAULT_FEE,
+            next_block: 0,
+            transfers: Vec::new(),
+            transfers_from: Vec::new(),
+            transfer_results: VecDeque::new(),
+            transfer_from_results: VecDeque::new(),
+            balances: BTreeMap::new(),
+            allowances: BTreeMap::new(),
+            during_transfer_from: None,
+        });
+    }
+
+    fn next_block(ledger: &mut MockLedger) -> Nat {
+        ledger.next_block += 1;
+        Nat::from(ledger.next_block)
+    }
+
+    // Points `currency` at the mock ledger
+    pub fn configure_currency(currency: &str) {
+        let ledger_canister_id = Principal::from_slice(&[0xfe]);
+        crate::storage::with_currency_ledgers_mut(|ledgers| {
+            ledgers.insert(currency.to_string(), CurrencyLedger {
+                currency: currency.to_string(),
+                ledger_canister_id,
+                configured_by: ledger_canister_id,
+                configured_at: 0,
+            });
+        });
+    }
+
+    pub fn reject() -> (RejectionCode, String) {
+        (RejectionCode::SysTransient, "mock reject".to_string())
+    }
+
+    pub fn set_fee(fee: u64) {
+        LEDGER.with(|ledger| ledger.borrow_mut().fee = fee);
+    }
+
+    pub fn set_balance(owner: Principal, balance: u64) {
+        LEDGER.with(|ledger| ledger.borrow_mut().balances.insert(owner, balance));
+    }
+
+    pub fn set_allowance(owner: Principal, allowance: u64, expires_at: Option<u64>) {
+        let allowance = Allowance { allowance: Nat::from(allowance), expires_at };
+        LEDGER.with(|ledger| ledger.borrow_mut().allowances.insert(owner, allowance));
+    }
+
+    pub fn push_transfer_result(result: TransferResult) {
+        LEDGER.with(|ledger| ledger.borrow_mut().transfer_results.push_back(result));
+    }
+
+    pub fn push_transfer_from_result(result: TransferFromResult) {
+        LEDGER.with(|ledger| ledger.borrow_mut().transfer_from_results.push_back(result));
+    }
+
+    pub fn during_transfer_from(f: impl FnOnce() + 'static) {
+        LEDGER.with(|ledger| ledger.borrow_mut().during_transfer_from = Some(Box::new(f)));
+    }
+
+    pub fn transfers() -> Vec<TransferArg> {
+        LEDGER.with(|ledger| ledger.borrow().transfers.clone())
+    }
+
+    pub fn transfers_from() -> Vec<TransferFromArgs> {
+        LEDGER.with(|ledger| ledger.borrow().transfers_from.clone())
+    }
+
+    pub async fn icrc1_fee(_ledger: Principal) -> CallResult<Nat> {
+        Ok(LEDGER.with(|ledger| Nat::from(ledger.borrow().fee)))
+    }
+
+    pub async fn icrc1_balance_of(_ledger: Principal, account: Account) -> CallResult<Nat> {
+        let balance = LEDGER.with(|ledger| ledger.borrow().balances.get(&account.owner).copied());
+        Ok(Nat::from(balance.unwrap_or(u64::MAX)))
+    }
+
+    pub async fn icrc2_allowance(_ledger: Principal, args: AllowanceArgs) -> CallResult<Allowance> {
+        let allowance = LEDGER.with(|ledger| ledger.borrow().allowances.get(&args.account.owner).cloned());
+        Ok(allowance.unwrap_or(Allowance { allowance: Nat::from(u64::MAX), expires_at: None }))
+    }
+
+    pub async fn icrc1_transfer(_ledger: Principal, args: TransferArg) -> TransferResult {
+        LEDGER.with(|ledger| {
+            let mut ledger = ledger.borrow_mut();
+            ledger.transfers.push(args);
+            match ledger.transfer_results.pop_front() {
+                Some(result) => result,
+                None => Ok(Ok(next_block(&mut ledger))),
+            }
+        })
+    }
+
+    pub async fn icrc2_transfer_from(_ledger: Principal, args: TransferFromArgs) -> TransferFromResult {
+        let during = LEDGER.with(|ledger| ledger.borrow_mut().during_transfer_from.take());
+        if let Some(during) = during {
+            during();
+        }
+        LEDGER.with(|ledger| {
+            let mut ledger = ledger.borrow_mut();
+            ledger.transfers_from.push(args);
+            match ledger.transfer_from_results.pop_front() {
+                Some(result) => result,
+                None => Ok(Ok(next_block(&mut ledger))),
+            }
+        })
+    }
+}
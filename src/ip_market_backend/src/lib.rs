@@ -1,11 +1,34 @@
 // Module declarations
 pub mod types;
 pub mod storage;
+pub mod access_control;
+pub mod migrations;
 pub mod utils;
+pub mod runtime;
+pub mod ledger;
 pub mod ip_registry;
 pub mod nft_management;
 pub mod user_management;
 pub mod marketplace;
+pub mod payments;
+pub mod royalties;
+pub mod treasury;
+pub mod icrc7;
+pub mod icrc37;
+pub mod icrc3;
+pub mod certification;
+pub mod http;
+pub mod assets;
+pub mod batch_mint;
+pub mod collections;
+pub mod rarity;
+pub mod favorites;
+pub mod views;
+pub mod trending;
+pub mod text_search;
+pub mod ip_search;
+#[cfg(test)]
+mod test_fixtures;
 
 // Re-export public types and functions
 pub use types::*;
@@ -13,15 +36,56 @@ pub use ip_registry::*;
 pub use nft_management::*;
 pub use user_management::*;
 pub use marketplace::*;
+pub use access_control::{get_my_roles, get_role_holders, grant_role, revoke_role};
+pub use migrations::get_schema_migration_report;
+pub use payments::{get_currency_ledgers, get_pending_payouts, remove_currency_ledger, resolve_pending_payout, set_currency_ledger};
+pub use royalties::{get_creator_royalties, get_marketplace_config, get_my_royalties, set_max_royalty_percentage};
+pub use treasury::{
+    get_fee_change_history, get_pending_withdrawals, get_treasury_balances, resolve_pending_withdrawal,
+    set_platform_fee, withdraw_treasury,
+};
+pub use icrc7::{
+    icrc10_supported_standards, icrc7_balance_of, icrc7_collection_metadata, icrc7_description, icrc7_name,
+    icrc7_owner_of, icrc7_symbol, icrc7_token_metadata, icrc7_tokens, icrc7_tokens_of, icrc7_total_supply,
+    icrc7_transfer,
+};
+pub use http::{http_request, http_request_streaming_callback};
+pub use assets::{
+    begin_asset_upload, cancel_asset_upload, finalize_asset_upload, get_asset, get_my_assets, get_my_storage_usage,
+    set_storage_limits, upload_asset_chunk,
+};
+pub use batch_mint::{batch_register_and_mint, get_batch_mint, get_my_batch_mints};
+pub use collections::{
+    create_collection, get_all_collections, get_collection, get_collection_by_name, get_collections_by_owner,
+    update_collection,
+};
+pub use rarity::get_collection_traits;
+pub use favorites::{get_my_favorites, get_nft_favoriters, is_favorite, toggle_nft_favorite};
+pub use views::{get_nft_view_history, increment_nft_view};
+pub use trending::{get_trending_collections, get_trending_nfts, set_trending_params};
+pub use ip_search::faceted_search_ips;
+pub use icrc3::{icrc3_get_archives, icrc3_get_blocks, icrc3_get_tip_certificate, icrc3_supported_block_types};
+pub use icrc37::{
+    icrc37_approve_collection, icrc37_approve_tokens, icrc37_is_approved, icrc37_metadata,
+    icrc37_revoke_collection_approvals, icrc37_revoke_token_approvals, icrc37_transfer_from,
+};
 
 use ic_cdk::{init, post_upgrade, pre_upgrade};
-use candid::Principal;
+use candid::{Nat, Principal};
 
 // Canister lifecycle functions
 #[init]
-fn init() {
+fn init(args: Option<InitArgs>) {
     // Initialize the canister
     // The stable structures are automatically initialized
+    access_control::apply_init_args(args.unwrap_or_default(), ic_cdk::caller());
+    migrations::skip_one_time_migrations();
+    marketplace::start_auction_sweeper();
+    payments::start_payout_retrier();
+    treasury::start_withdrawal_retrier();
+    views::start_view_pruner();
+    trending::start_trending_pruner();
+    rarity::start_rarity_refresher();
     ic_cdk::println!("IP Marketplace backend canister initialized");
 }
 
@@ -33,9 +97,41 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
     // Called after canister upgrade
     // With ic-stable-structures, data is automatically restored
+    for (kind, count) in migrations::run_schema_migrations() {
+        if count > 0 {
+            ic_cdk::println!("Migrated {} {} records to the current schema", count, kind);
+        }
+    }
+    storage::reconcile_id_counters();
+    storage::rebuild_token_index();
+    storage::rebuild_secondary_indexes();
+    for name in migrations::run_one_time_migrations() {
+        ic_cdk::println!("Ran one-time migration {}", name);
+    }
+    text_search::rebuild_text_index();
+    rarity::rebuild_rarity();
+    let stale = marketplace::cancel_stale_listings();
+    if stale > 0 {
+        ic_cdk::println!("Cancelled {} listings whose seller no longer owns the NFT", stale);
+    }
+    // Rebuild the certified HTTP assets and re-certify the block log tip
+    http::certify_all();
+    // Upgrade arguments may add role holders, but an omitted owner list does not
+    // make the upgrading principal an owner
+    if let Some(args) = args {
+        access_control::apply_init_args(args, ic_cdk::caller());
+    }
+    // Timers do not survive upgrades
+    marketplace::start_auction_sweeper();
+    payments::start_payout_retrier();
+    treasury::start_withdrawal_retrier();
+    views::start_view_pruner();
+    trending::start_trending_pruner();
+    rarity::start_rarity_refresher();
+    batch_mint::resume_batch_mints();
     ic_cdk::println!("Canister upgrade completed - data restored from stable memory");
 }
 
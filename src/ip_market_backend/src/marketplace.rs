@@ -1,10 +1,18 @@
-use ic_cdk::api::time;
 use ic_cdk::{query, update};
 use candid::Principal;
 
 use crate::types::*;
 use crate::storage::*;
+use crate::runtime::time;
 use crate::user_management::*;
+use crate::access_control::require_moderator;
+use crate::payments::*;
+use crate::royalties::*;
+use crate::treasury::*;
+use crate::nft_management::record_nft_transfer;
+use crate::icrc3::{log_bid, log_cancel, log_listing, log_sale, log_transfer};
+use crate::icrc7::owner_account;
+use crate::trending::{record_activity, BID_WEIGHT, SALE_WEIGHT};
 
 #[update]
 pub fn list_nft_for_sale(request: ListNFTRequest) -> Result<MarketplaceListing> {
@@ -21,6 +29,14 @@ pub fn list_nft_for_sale(request: ListNFTRequest) -> Result<MarketplaceListing>
         return Err(IPMarketplaceError::Unauthorized);
     }
     
+    // One live listing per NFT
+    if !live_listings_for_nft(&request.nft_id).is_empty() {
+        return Err(IPMarketplaceError::AlreadyExists);
+    }
+    
+    // The currency must settle on a configured ledger
+    ledger_for_currency(&request.currency)?;
+    
     let listing_id = generate_id("LISTING");
     
     let auction_data = if request.is_auction {
@@ -48,166 +64,464 @@ pub fn list_nft_for_sale(request: ListNFTRequest) -> Result<MarketplaceListing>
         auction_data,
     };
     
-    with_marketplace_mut(|registry| {
-        registry.insert(listing_id, listing.clone());
+    insert_listing(listing.clone());
+    log_listing(&listing);
+    
+    Ok(listing)
+}
+
+// Bids are binding: the bidder must have approved the marketplace for the bid
+// plus the ledger fee, and hold that much, for the bid to be accepted
+#[update]
+pub async fn place_bid(listing_id: String, bid_amount: u64) -> Result<bool> {
+    bid(listing_id, ic_cdk::caller(), bid_amount).await
+}
+
+async fn bid(listing_id: String, bidder: Principal, bid_amount: u64) -> Result<bool> {
+    if bidder == Principal::anonymous() {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+    
+    let listing = open_auction(&listing_id, bidder, bid_amount)?;
+    let auction_end = listing.auction_data.as_ref().map_or(0, |a| a.auction_end);
+    ensure_can_pay(&listing.currency, bidder, bid_amount, auction_end).await?;
+    
+    // Another bid may have landed while the ledger was queried
+    let mut listing = open_auction(&listing_id, bidder, bid_amount)?;
+    if let Some(ref mut auction_data) = listing.auction_data {
+        auction_data.current_bid = bid_amount;
+        auction_data.highest_bidder = Some(bidder);
+    }
+    
+    let nft_id = listing.nft_id.clone();
+    insert_listing(listing);
+    with_auction_bids_mut(|bids| {
+        bids.insert((IdKey(listing_id.clone()), bid_amount), Bid {
+            bidder,
+            amount: bid_amount,
+            placed_at: time(),
+        });
     });
+    log_bid(&listing_id, bidder, bid_amount);
+    record_activity(&nft_id, BID_WEIGHT);
+    Ok(true)
+}
+
+// The auction, if it still takes this bid
+fn open_auction(listing_id: &str, bidder: Principal, bid_amount: u64) -> Result<MarketplaceListing> {
+    let listing = with_marketplace(|marketplace| {
+        marketplace.get(&listing_id.to_string())
+    }).ok_or(IPMarketplaceError::NotFound)?;
+    
+    // Check if it's an auction
+    if !matches!(listing.status, ListingStatus::InAuction) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    let Some(ref auction_data) = listing.auction_data else {
+        return Err(IPMarketplaceError::InvalidInput);
+    };
+    
+    if listing.seller == bidder {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    // Check if auction hasn't ended; settlement is left to finalize_auction
+    if time() > auction_data.auction_end {
+        return Err(IPMarketplaceError::AuctionEnded);
+    }
+    
+    // Check minimum bid
+    let min_bid = auction_data.current_bid + auction_data.min_bid_increment;
+    if bid_amount < min_bid {
+        return Err(IPMarketplaceError::BidTooLow);
+    }
     
     Ok(listing)
 }
 
+// The auction's bids, highest first
+#[query]
+pub fn get_auction_bids(listing_id: String) -> Vec<Bid> {
+    auction_bids(&listing_id).into_iter().rev().collect()
+}
+
+// Lowest first
+fn auction_bids(listing_id: &str) -> Vec<Bid> {
+    let start = (IdKey(listing_id.to_string()), 0);
+    with_auction_bids(|bids| {
+        bids.range(start..)
+            .take_while(|((id, _), _)| id.0 == listing_id)
+            .map(|(_, bid)| bid)
+            .collect()
+    })
+}
+
+fn clear_auction_bids(listing_id: &str) {
+    let amounts: Vec<u64> = auction_bids(listing_id).iter().map(|bid| bid.amount).collect();
+    with_auction_bids_mut(|bids| {
+        for amount in amounts {
+            bids.remove(&(IdKey(listing_id.to_string()), amount));
+        }
+    });
+}
+
+// Drops the bids of a winner who could not pay, making the next-highest bid the
+// auction's highest. Returns that bid.
+fn forfeit_bids(listing: &mut MarketplaceListing, bidder: Principal) -> Option<(Principal, u64)> {
+    let bids = auction_bids(&listing.id);
+    with_auction_bids_mut(|stored| {
+        for bid in bids.iter().filter(|bid| bid.bidder == bidder) {
+            stored.remove(&(IdKey(listing.id.clone()), bid.amount));
+        }
+    });
+    
+    let next = bids.iter().rev().find(|bid| bid.bidder != bidder);
+    if let Some(ref mut auction_data) = listing.auction_data {
+        auction_data.highest_bidder = next.map(|bid| bid.bidder);
+        auction_data.current_bid = next.map_or(auction_data.starting_price, |bid| bid.amount);
+    }
+    insert_listing(listing.clone());
+    next.map(|bid| (bid.bidder, bid.amount))
+}
+
 #[update]
-pub fn place_bid(listing_id: String, bid_amount: u64) -> Result<bool> {
-    let caller = ic_cdk::caller();
+pub async fn buy_nft(listing_id: String) -> Result<SaleBreakdown> {
+    purchase(listing_id, ic_cdk::caller()).await
+}
+
+async fn purchase(listing_id: String, caller: Principal) -> Result<SaleBreakdown> {
     let now = time();
     
-    with_marketplace_mut(|marketplace| {
-        if let Some(mut listing) = marketplace.get(&listing_id) {
-            // Check if it's an auction
-            if !matches!(listing.status, ListingStatus::InAuction) {
-                return Err(IPMarketplaceError::InvalidInput);
-            }
-            
-            if let Some(ref mut auction_data) = listing.auction_data {
-                // Check if auction hasn't ended
-                if now > auction_data.auction_end {
-                    listing.status = ListingStatus::Expired;
-                    marketplace.insert(listing_id, listing);
-                    return Err(IPMarketplaceError::AuctionEnded);
-                }
-                
-                // Check minimum bid
-                let min_bid = auction_data.current_bid + auction_data.min_bid_increment;
-                if bid_amount < min_bid {
-                    return Err(IPMarketplaceError::BidTooLow);
-                }
-                
-                // Update auction data
-                auction_data.current_bid = bid_amount;
-                auction_data.highest_bidder = Some(caller);
-                
-                marketplace.insert(listing_id, listing);
-                Ok(true)
-            } else {
-                Err(IPMarketplaceError::InvalidInput)
-            }
-        } else {
-            Err(IPMarketplaceError::NotFound)
+    let mut listing = with_marketplace(|marketplace| {
+        marketplace.get(&listing_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
+    
+    // Check if listing is active
+    if !matches!(listing.status, ListingStatus::Active) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    // Check if listing hasn't expired
+    if let Some(expires_at) = listing.expires_at {
+        if now > expires_at {
+            listing.status = ListingStatus::Expired;
+            insert_listing(listing);
+            return Err(IPMarketplaceError::OperationFailed);
         }
-    })
+    }
+    
+    if !seller_owns_nft(&listing) {
+        close_listing(listing);
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+    
+    if listing.seller == caller {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    // Hold the listing while the ledger call is in flight so it cannot be bought
+    // twice or cancelled under us
+    let _lock = CallLock::acquire(&listing_id).ok_or(IPMarketplaceError::OperationFailed)?;
+    
+    // Fixed for the payouts of this sale, so their retries repeat one transfer
+    let fee = currency_fee(&listing.currency).await?;
+    
+    let block_index = collect_payment(
+        &listing.currency,
+        caller,
+        listing.price,
+        &listing_id,
+    ).await?;
+    
+    // The NFT may have left the seller while the payment was in flight
+    if !seller_owns_nft(&listing) {
+        let refund = queue_payout(PayoutKind::Refund, caller, &listing, listing.price, fee, time());
+        close_listing(listing);
+        send_payouts(refund.into_iter().collect()).await;
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+    
+    let (breakdown, payouts) = settle_sale(&listing, caller, listing.price, Some(block_index.to_string()), fee, time());
+    
+    // Mark listing as sold
+    let nft_id = listing.nft_id.clone();
+    listing.status = ListingStatus::Sold;
+    insert_listing(listing);
+    cancel_listings_for_nft(&nft_id);
+    
+    send_payouts(payouts).await;
+    
+    Ok(breakdown)
+}
+
+// Moves the listed NFT from the seller to the buyer, records the sale and splits
+// the price: the platform fee goes to the treasury, the creator royalty and seller
+// proceeds are queued for payout out of the marketplace account, each less the
+// ledger fee `fee`. Shared by fixed-price purchases and auction settlement;
+// returns the breakdown and the queued payout IDs.
+fn settle_sale(
+    listing: &MarketplaceListing,
+    buyer: Principal,
+    price: u64,
+    transaction_hash: Option<String>,
+    fee: u64,
+    now: u64,
+) -> (SaleBreakdown, Vec<String>) {
+    let seller = listing.seller;
+    
+    // Transfer NFT ownership
+    let transfer_block = with_nft_registry(|registry| registry.get(&listing.nft_id))
+        .map(|nft| log_transfer(nft.token_id, &owner_account(&nft), &principal_account(buyer), &None, None));
+    let (creator, royalty_percentage) = record_nft_transfer(
+        &listing.nft_id,
+        principal_account(buyer),
+        Some(price),
+        transaction_hash.clone(),
+        now,
+    )
+    .map_or((seller, 0), |nft| (nft.creator, nft.royalty_percentage));
+    
+    // Update user profiles
+    update_user_sales_stats(seller, price, 0);
+    update_user_sales_stats(buyer, 0, price);
+    
+    let breakdown = sale_breakdown(listing, price, royalty_percentage, creator, transaction_hash);
+    if let Some(transfer_block) = transfer_block {
+        log_sale(&breakdown, seller, buyer, transfer_block);
+    }
+    record_activity(&listing.nft_id, SALE_WEIGHT);
+    
+    credit_treasury(&listing.currency, breakdown.platform_fee);
+    
+    let mut payouts = Vec::new();
+    if breakdown.royalty > 0 {
+        record_royalty_accrued(creator, &listing.currency, breakdown.royalty);
+        payouts.extend(queue_payout(PayoutKind::Royalty, creator, listing, breakdown.royalty, fee, now));
+    }
+    payouts.extend(queue_payout(PayoutKind::SellerProceeds, seller, listing, breakdown.seller_proceeds, fee, now));
+    (breakdown, payouts)
 }
 
+// Attempts queued payouts right away; failures stay queued for the retry timer
+async fn send_payouts(payout_ids: Vec<String>) {
+    for payout_id in payout_ids {
+        let _ = send_payout(payout_id).await;
+    }
+}
+
+// Closes an auction after `auction_end`: the highest bidder pays their bid and
+// receives the NFT. A winner who cannot pay forfeits and the next-highest bidder
+// is offered the NFT at their bid; the listing expires once no bidder is left.
+// Anyone may trigger settlement.
 #[update]
-pub fn buy_nft(listing_id: String) -> Result<bool> {
-    let caller = ic_cdk::caller();
-    let now = time();
+pub async fn finalize_auction(listing_id: String) -> Result<MarketplaceListing> {
+    settle_auction(listing_id).await
+}
+
+async fn settle_auction(listing_id: String) -> Result<MarketplaceListing> {
+    let mut listing = with_marketplace(|marketplace| {
+        marketplace.get(&listing_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
     
-    with_marketplace_mut(|marketplace| {
-        if let Some(mut listing) = marketplace.get(&listing_id) {
-            // Check if listing is active
-            if !matches!(listing.status, ListingStatus::Active) {
-                return Err(IPMarketplaceError::InvalidInput);
+    if !matches!(listing.status, ListingStatus::InAuction) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    let auction_data = listing.auction_data.clone().ok_or(IPMarketplaceError::InvalidInput)?;
+    if time() <= auction_data.auction_end {
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+    
+    let _lock = CallLock::acquire(&listing_id).ok_or(IPMarketplaceError::OperationFailed)?;
+    
+    let mut payouts = Vec::new();
+    let mut next_bid = auction_data.highest_bidder.map(|bidder| (bidder, auction_data.current_bid));
+    // Bids are not escrowed, so a stale auction closes without charging anyone
+    let mut outcome = (!seller_owns_nft(&listing)).then_some(ListingStatus::Cancelled);
+    let fee = match (&outcome, next_bid) {
+        (None, Some(_)) => currency_fee(&listing.currency).await?,
+        _ => 0,
+    };
+    while let (None, Some((bidder, amount))) = (&outcome, next_bid) {
+        let payment = collect_payment(&listing.currency, bidder, amount, &listing_id).await;
+        
+        match payment {
+            Ok(block_index) if seller_owns_nft(&listing) => {
+                (_, payouts) = settle_sale(&listing, bidder, amount, Some(block_index.to_string()), fee, time());
+                listing.price = amount;
+                outcome = Some(ListingStatus::Sold);
+            }
+            // The NFT left the seller while the payment was in flight
+            Ok(_) => {
+                payouts.extend(queue_payout(PayoutKind::Refund, bidder, &listing, amount, fee, time()));
+                outcome = Some(ListingStatus::Cancelled);
             }
-            
-            // Check if listing hasn't expired
-            if let Some(expires_at) = listing.expires_at {
-                if now > expires_at {
-                    listing.status = ListingStatus::Expired;
-                    marketplace.insert(listing_id, listing);
-                    return Err(IPMarketplaceError::OperationFailed);
-                }
+            // A winner who cannot pay forfeits; the NFT stays with the seller
+            // and the next-highest bidder is tried
+            Err(IPMarketplaceError::InsufficientFunds) => {
+                next_bid = forfeit_bids(&mut listing, bidder);
             }
-            
-            // In a real implementation, you'd handle payment here
-            // For now, we'll just simulate the transfer
-            
-            // Transfer NFT ownership
-            let nft_id = listing.nft_id.clone();
-            let seller = listing.seller;
-            let price = listing.price;
-            
-            with_nft_registry_mut(|nft_registry| {
-                if let Some(mut nft) = nft_registry.get(&nft_id) {
-                    nft.owner = caller;
-                    nft.transfer_history.push(TransferRecord {
-                        from: seller,
-                        to: caller,
-                        timestamp: now,
-                        transaction_hash: None,
-                        price: Some(price),
-                    });
-                    nft_registry.insert(nft_id.clone(), nft);
-                }
-            });
-            
-            // Update user profiles
-            remove_nft_from_user(seller, &nft_id);
-            add_nft_to_user(caller, nft_id);
-            update_user_sales_stats(seller, price, 0);
-            update_user_sales_stats(caller, 0, price);
-            
-            // Mark listing as sold
-            listing.status = ListingStatus::Sold;
-            marketplace.insert(listing_id, listing);
-            
-            Ok(true)
-        } else {
-            Err(IPMarketplaceError::NotFound)
+            Err(e) => return Err(e),
         }
-    })
+    }
+    listing.status = outcome.unwrap_or(ListingStatus::Expired);
+    
+    insert_listing(listing.clone());
+    clear_auction_bids(&listing_id);
+    match listing.status {
+        ListingStatus::Sold => cancel_listings_for_nft(&listing.nft_id),
+        ListingStatus::Cancelled => {
+            log_cancel(&listing);
+        }
+        _ => {}
+    }
+    
+    send_payouts(payouts).await;
+    Ok(listing)
+}
+
+// Auctions settled per sweep, to keep each timer callback within instruction limits
+const AUCTION_SWEEP_BATCH_SIZE: usize = 50;
+
+// How often ended auctions are swept
+pub const AUCTION_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Settles auctions whose end time has passed; run periodically from a timer
+pub fn sweep_ended_auctions() {
+    let now = time();
+    let ended: Vec<String> = listings_with_status(&ListingStatus::InAuction)
+        .into_iter()
+        .filter(|listing| {
+            listing.auction_data.as_ref().is_some_and(|a| now > a.auction_end) &&
+            !is_locked(&listing.id)
+        })
+        .map(|listing| listing.id)
+        .take(AUCTION_SWEEP_BATCH_SIZE)
+        .collect();
+    
+    for listing_id in ended {
+        ic_cdk::spawn(async move {
+            if let Err(e) = settle_auction(listing_id.clone()).await {
+                ic_cdk::println!("Failed to settle auction {}: {:?}", listing_id, e);
+            }
+        });
+    }
+}
+
+pub fn start_auction_sweeper() {
+    ic_cdk_timers::set_timer_interval(AUCTION_SWEEP_INTERVAL, sweep_ended_auctions);
 }
 
 #[update]
 pub fn cancel_listing(listing_id: String) -> Result<bool> {
     let caller = ic_cdk::caller();
     
-    with_marketplace_mut(|marketplace| {
-        if let Some(mut listing) = marketplace.get(&listing_id) {
-            // Check ownership
-            if listing.seller != caller {
-                return Err(IPMarketplaceError::Unauthorized);
-            }
-            
-            // Check if listing can be cancelled
-            if matches!(listing.status, ListingStatus::Sold) {
-                return Err(IPMarketplaceError::InvalidInput);
-            }
-            
-            // For auctions, check if there are bids
-            if let Some(ref auction_data) = listing.auction_data {
-                if auction_data.highest_bidder.is_some() {
-                    return Err(IPMarketplaceError::InvalidInput);
-                }
-            }
-            
-            listing.status = ListingStatus::Cancelled;
-            marketplace.insert(listing_id, listing);
-            Ok(true)
-        } else {
-            Err(IPMarketplaceError::NotFound)
+    let mut listing = with_marketplace(|marketplace| {
+        marketplace.get(&listing_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
+    
+    // Check ownership
+    if listing.seller != caller {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+    
+    // Check if listing can be cancelled
+    if matches!(listing.status, ListingStatus::Sold) {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    // A purchase is mid-payment
+    if is_locked(&listing_id) {
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+    
+    // For auctions, check if there are bids
+    if let Some(ref auction_data) = listing.auction_data {
+        if auction_data.highest_bidder.is_some() {
+            return Err(IPMarketplaceError::InvalidInput);
         }
-    })
+    }
+    
+    listing.status = ListingStatus::Cancelled;
+    insert_listing(listing.clone());
+    log_cancel(&listing);
+    Ok(true)
+}
+
+fn live_listings_for_nft(nft_id: &str) -> Vec<MarketplaceListing> {
+    listings_for_nft(nft_id)
+        .into_iter()
+        .filter(|listing| matches!(listing.status, ListingStatus::Active | ListingStatus::InAuction))
+        .collect()
+}
+
+// Whether the listing's seller still owns the NFT. Transfers and burns cancel
+// live listings, so this only fails for listings made stale some other way.
+fn seller_owns_nft(listing: &MarketplaceListing) -> bool {
+    with_nft_registry(|registry| registry.get(&listing.nft_id))
+        .is_some_and(|nft| nft.owner == listing.seller)
+}
+
+fn close_listing(mut listing: MarketplaceListing) {
+    listing.status = ListingStatus::Cancelled;
+    insert_listing(listing.clone());
+    clear_auction_bids(&listing.id);
+    log_cancel(&listing);
+}
+
+// Called from post_upgrade: cancels live listings whose seller no longer owns
+// the NFT, left over from before transfers cancelled listings
+pub fn cancel_stale_listings() -> usize {
+    let stale: Vec<MarketplaceListing> = live_listings()
+        .into_iter()
+        .filter(|listing| !seller_owns_nft(listing))
+        .collect();
+    let count = stale.len();
+    for listing in stale {
+        close_listing(listing);
+    }
+    count
+}
+
+// Live listings in listing ID order
+fn live_listings() -> Vec<MarketplaceListing> {
+    let mut listings = listings_with_status(&ListingStatus::Active);
+    listings.extend(listings_with_status(&ListingStatus::InAuction));
+    listings.sort_by(|a, b| a.id.cmp(&b.id));
+    listings
+}
+
+// Fails if a live listing for the NFT cannot be withdrawn, under the same
+// rules as cancel_listing
+pub fn ensure_listings_cancellable(nft_id: &str) -> Result<()> {
+    for listing in live_listings_for_nft(nft_id) {
+        if is_locked(&listing.id) {
+            return Err(IPMarketplaceError::OperationFailed);
+        }
+        if listing.auction_data.as_ref().is_some_and(|a| a.highest_bidder.is_some()) {
+            return Err(IPMarketplaceError::InvalidInput);
+        }
+    }
+    Ok(())
+}
+
+// Cancels every live listing for the NFT; check ensure_listings_cancellable
+// first
+pub fn cancel_listings_for_nft(nft_id: &str) {
+    for listing in live_listings_for_nft(nft_id) {
+        close_listing(listing);
+    }
 }
 
 #[query]
 pub fn get_marketplace_listings() -> Vec<MarketplaceListing> {
-    with_marketplace(|marketplace| {
-        marketplace
-            .iter()
-            .filter(|(_, listing)| matches!(listing.status, ListingStatus::Active | ListingStatus::InAuction))
-            .map(|(_, listing)| listing.clone())
-            .collect()
-    })
+    live_listings()
 }
 
 #[query]
 pub fn get_listings_by_seller(seller: Principal) -> Vec<MarketplaceListing> {
-    with_marketplace(|marketplace| {
-        marketplace
-            .iter()
-            .filter(|(_, listing)| listing.seller == seller)
-            .map(|(_, listing)| listing.clone())
-            .collect()
-    })
+    listings_of_seller(seller)
 }
 
 #[query]
@@ -255,63 +569,269 @@ pub fn get_listing_by_id(listing_id: String) -> Result<MarketplaceListing> {
 
 #[query]
 pub fn get_active_listings_by_nft(nft_id: String) -> Vec<MarketplaceListing> {
-    with_marketplace(|marketplace| {
-        marketplace
-            .iter()
-            .filter(|(_, listing)| {
-                listing.nft_id == nft_id && 
-                matches!(listing.status, ListingStatus::Active | ListingStatus::InAuction)
-            })
-            .map(|(_, listing)| listing.clone())
-            .collect()
-    })
+    live_listings_for_nft(&nft_id)
 }
 
 #[query]
 pub fn get_expired_listings() -> Vec<MarketplaceListing> {
     let now = time();
-    with_marketplace(|marketplace| {
-        marketplace
-            .iter()
-            .filter(|(_, listing)| {
-                if let Some(expires_at) = listing.expires_at {
-                    now > expires_at && matches!(listing.status, ListingStatus::Active)
-                } else {
-                    false
-                }
-            })
-            .map(|(_, listing)| listing.clone())
-            .collect()
-    })
+    listings_with_status(&ListingStatus::Active)
+        .into_iter()
+        .filter(|listing| listing.expires_at.is_some_and(|expires_at| now > expires_at))
+        .collect()
 }
 
 // Admin function to clean up expired listings
 #[update]
 pub fn cleanup_expired_listings() -> Result<u32> {
-    let now = time();
-    let mut cleaned_count = 0;
+    require_moderator()?;
     
-    with_marketplace_mut(|marketplace| {
-        let expired_ids: Vec<String> = marketplace
-            .iter()
-            .filter(|(_, listing)| {
-                if let Some(expires_at) = listing.expires_at {
-                    now > expires_at && matches!(listing.status, ListingStatus::Active)
-                } else {
-                    false
-                }
-            })
-            .map(|(id, _)| id.clone())
-            .collect();
-        
-        for id in expired_ids {
-            if let Some(mut listing) = marketplace.get(&id) {
-                listing.status = ListingStatus::Expired;
-                marketplace.insert(id, listing);
-                cleaned_count += 1;
-            }
-        }
-    });
+    let mut cleaned_count = 0;
+    for mut listing in get_expired_listings() {
+        listing.status = ListingStatus::Expired;
+        insert_listing(listing);
+        cleaned_count += 1;
+    }
     
     Ok(cleaned_count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::mock as ledger;
+    use crate::payments::get_pending_payouts;
+    use crate::royalties::get_creator_royalties;
+    use crate::runtime::mock::{set_time, START_TIME};
+    use crate::test_fixtures::mint_test_nft;
+    use crate::treasury::get_treasury_balances;
+    use candid::Nat;
+
+    const CURRENCY: &str = "TEST";
+    const PRICE: u64 = 10_000;
+
+    fn seller() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn buyer() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn creator() -> Principal {
+        Principal::from_slice(&[3])
+    }
+
+    fn nft_owner(nft_id: &str) -> Principal {
+        with_nft_registry(|registry| registry.get(&nft_id.to_string())).unwrap().owner
+    }
+
+    fn listing_status(listing_id: &str) -> ListingStatus {
+        with_marketplace(|marketplace| marketplace.get(&listing_id.to_string())).unwrap().status
+    }
+
+    // A 10% royalty NFT owned by the seller and listed for PRICE, with a 2.5%
+    // platform fee
+    fn list_nft(auction_data: Option<AuctionData>) -> MarketplaceListing {
+        ledger::configure_currency(CURRENCY);
+        with_marketplace_config_mut(|config| config.platform_fee_bps = 250);
+
+        mint_test_nft("NFT_1", 1, seller(), creator());
+
+        let listing = MarketplaceListing {
+            id: "LISTING_1".to_string(),
+            nft_id: "NFT_1".to_string(),
+            seller: seller(),
+            price: PRICE,
+            currency: CURRENCY.to_string(),
+            listed_at: 0,
+            expires_at: None,
+            status: if auction_data.is_some() { ListingStatus::InAuction } else { ListingStatus::Active },
+            license_terms: None,
+            auction_data,
+        };
+        insert_listing(listing.clone());
+        listing
+    }
+
+    fn ended_auction(highest_bidder: Option<Principal>) -> AuctionData {
+        AuctionData {
+            starting_price: PRICE,
+            current_bid: PRICE,
+            highest_bidder,
+            auction_end: START_TIME - 1,
+            min_bid_increment: 100,
+        }
+    }
+
+    #[tokio::test]
+    async fn purchase_splits_price_between_treasury_creator_and_seller() {
+        let listing = list_nft(None);
+
+        let breakdown = purchase(listing.id.clone(), buyer()).await.unwrap();
+
+        assert_eq!(breakdown.platform_fee, 250);
+        assert_eq!(breakdown.royalty, 1_000);
+        assert_eq!(breakdown.seller_proceeds, 8_750);
+        assert_eq!(breakdown.transaction_hash.as_deref(), Some("1"));
+        assert_eq!(nft_owner(&listing.nft_id), buyer());
+        assert!(matches!(listing_status(&listing.id), ListingStatus::Sold));
+
+        let payments = ledger::transfers_from();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].from, principal_account(buyer()));
+        assert_eq!(payments[0].amount, Nat::from(PRICE));
+
+        // Both payouts are sent right away, net of the ledger fee
+        let payouts = ledger::transfers();
+        assert_eq!(payouts.len(), 2);
+        assert_eq!(payouts[0].to, principal_account(creator()));
+        assert_eq!(payouts[0].amount, Nat::from(1_000 - ledger::DEFAULT_FEE));
+        assert_eq!(payouts[1].to, principal_account(seller()));
+        assert_eq!(payouts[1].amount, Nat::from(8_750 - ledger::DEFAULT_FEE));
+        assert!(get_pending_payouts(None).is_empty());
+
+        assert_eq!(get_treasury_balances()[0].collected, 250);
+        let royalties = &get_creator_royalties(creator()).unwrap().balances[0];
+        assert_eq!(royalties.accrued, 1_000);
+        assert_eq!(royalties.paid, 1_000 - ledger::DEFAULT_FEE);
+    }
+
+    #[tokio::test]
+    async fn purchase_refunds_buyer_when_nft_leaves_seller_during_payment() {
+        let listing = list_nft(None);
+        let nft_id = listing.nft_id.clone();
+        let other = Principal::from_slice(&[4]);
+        ledger::during_transfer_from(move || {
+            record_nft_transfer(&nft_id, principal_account(other), None, None, time());
+        });
+
+        let result = purchase(listing.id.clone(), buyer()).await;
+
+        assert!(matches!(result, Err(IPMarketplaceError::OperationFailed)));
+        assert_eq!(nft_owner(&listing.nft_id), other);
+        assert!(matches!(listing_status(&listing.id), ListingStatus::Cancelled));
+
+        let refunds = ledger::transfers();
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].to, principal_account(buyer()));
+        assert_eq!(refunds[0].amount, Nat::from(PRICE - ledger::DEFAULT_FEE));
+        assert!(get_treasury_balances().is_empty());
+        assert!(get_creator_royalties(creator()).is_err());
+    }
+
+    #[tokio::test]
+    async fn purchase_of_stale_listing_takes_no_payment() {
+        let listing = list_nft(None);
+        record_nft_transfer(&listing.nft_id, principal_account(Principal::from_slice(&[4])), None, None, time());
+
+        let result = purchase(listing.id.clone(), buyer()).await;
+
+        assert!(matches!(result, Err(IPMarketplaceError::OperationFailed)));
+        assert!(matches!(listing_status(&listing.id), ListingStatus::Cancelled));
+        assert!(ledger::transfers_from().is_empty());
+    }
+
+    #[tokio::test]
+    async fn auction_settles_to_highest_bidder() {
+        let listing = list_nft(Some(ended_auction(Some(buyer()))));
+
+        let settled = settle_auction(listing.id.clone()).await.unwrap();
+
+        assert!(matches!(settled.status, ListingStatus::Sold));
+        assert_eq!(nft_owner(&listing.nft_id), buyer());
+        assert_eq!(ledger::transfers_from()[0].amount, Nat::from(PRICE));
+        assert_eq!(get_treasury_balances()[0].collected, 250);
+    }
+
+    #[tokio::test]
+    async fn auction_winner_who_cannot_pay_forfeits() {
+        let listing = list_nft(Some(ended_auction(Some(buyer()))));
+        ledger::push_transfer_from_result(Ok(Err(TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(0u64),
+        })));
+
+        let settled = settle_auction(listing.id.clone()).await.unwrap();
+
+        assert!(matches!(settled.status, ListingStatus::Expired));
+        assert_eq!(nft_owner(&listing.nft_id), seller());
+        assert!(ledger::transfers().is_empty());
+        assert!(get_treasury_balances().is_empty());
+    }
+
+    fn open_auction_data() -> AuctionData {
+        AuctionData {
+            auction_end: START_TIME + 1_000,
+            ..ended_auction(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn bid_is_refused_from_anonymous_and_seller() {
+        let listing = list_nft(Some(open_auction_data()));
+
+        let anonymous = bid(listing.id.clone(), Principal::anonymous(), PRICE + 100).await;
+        assert!(matches!(anonymous, Err(IPMarketplaceError::Unauthorized)));
+        let own = bid(listing.id.clone(), seller(), PRICE + 100).await;
+        assert!(matches!(own, Err(IPMarketplaceError::InvalidInput)));
+        assert!(get_auction_bids(listing.id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn bid_must_be_covered_by_allowance_and_balance() {
+        let listing = list_nft(Some(open_auction_data()));
+        let amount = PRICE + 100;
+
+        // The approval must also cover the ledger fee
+        ledger::set_allowance(buyer(), amount, None);
+        let result = bid(listing.id.clone(), buyer(), amount).await;
+        assert!(matches!(result, Err(IPMarketplaceError::InsufficientFunds)));
+
+        // and outlast the auction
+        ledger::set_allowance(buyer(), amount + ledger::DEFAULT_FEE, Some(START_TIME + 1_000));
+        let result = bid(listing.id.clone(), buyer(), amount).await;
+        assert!(matches!(result, Err(IPMarketplaceError::InsufficientFunds)));
+
+        ledger::set_allowance(buyer(), amount + ledger::DEFAULT_FEE, None);
+        ledger::set_balance(buyer(), amount);
+        let result = bid(listing.id.clone(), buyer(), amount).await;
+        assert!(matches!(result, Err(IPMarketplaceError::InsufficientFunds)));
+
+        ledger::set_balance(buyer(), amount + ledger::DEFAULT_FEE);
+        assert!(bid(listing.id.clone(), buyer(), amount).await.unwrap());
+        assert_eq!(get_auction_bids(listing.id)[0].bidder, buyer());
+    }
+
+    #[tokio::test]
+    async fn auction_falls_back_to_runner_up_when_winner_cannot_pay() {
+        let listing = list_nft(Some(open_auction_data()));
+        let winner = Principal::from_slice(&[5]);
+        bid(listing.id.clone(), buyer(), PRICE + 100).await.unwrap();
+        bid(listing.id.clone(), winner, PRICE + 500).await.unwrap();
+
+        set_time(START_TIME + 2_000);
+        ledger::push_transfer_from_result(Ok(Err(TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(0u64),
+        })));
+        let settled = settle_auction(listing.id.clone()).await.unwrap();
+
+        assert!(matches!(settled.status, ListingStatus::Sold));
+        assert_eq!(settled.price, PRICE + 100);
+        assert_eq!(nft_owner(&listing.nft_id), buyer());
+        let payments = ledger::transfers_from();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[1].from, principal_account(buyer()));
+        assert_eq!(payments[1].amount, Nat::from(PRICE + 100));
+        assert!(get_auction_bids(listing.id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn auction_without_bids_expires() {
+        let listing = list_nft(Some(ended_auction(None)));
+
+        let settled = settle_auction(listing.id.clone()).await.unwrap();
+
+        assert!(matches!(settled.status, ListingStatus::Expired));
+        assert!(ledger::transfers_from().is_empty());
+    }
+}
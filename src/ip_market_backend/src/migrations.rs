@@ -0,0 +1,556 @@
+use candid::CandidType;
+use ic_cdk::query;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+
+// Stored records are wrapped in an envelope: MAGIC, a little-endian u32 schema
+// version, then the candid payload. Records written before envelopes existed are
+// bare candid (starting with "DIDL") and are treated as version 0.
+const ENVELOPE_MAGIC: &[u8; 4] = b"VREC";
+const ENVELOPE_HEADER_LEN: usize = 8;
+pub const LEGACY_VERSION: u32 = 0;
+
+// A record type persisted in stable memory under a schema version
+pub trait VersionedRecord: CandidType + DeserializeOwned {
+    const KIND: &'static str;
+    const VERSION: u32;
+}
+
+impl VersionedRecord for IntellectualProperty {
+    const KIND: &'static str = "IntellectualProperty";
+    const VERSION: u32 = 3;
+}
+
+impl VersionedRecord for IPNft {
+    const KIND: &'static str = "IPNft";
+    const VERSION: u32 = 2;
+}
+
+impl VersionedRecord for UserProfile {
+    const KIND: &'static str = "UserProfile";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for MarketplaceListing {
+    const KIND: &'static str = "MarketplaceListing";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for NFTMetadata {
+    const KIND: &'static str = "NFTMetadata";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for RoleAssignments {
+    const KIND: &'static str = "RoleAssignments";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for CurrencyLedger {
+    const KIND: &'static str = "CurrencyLedger";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for PendingPayout {
+    const KIND: &'static str = "PendingPayout";
+    const VERSION: u32 = 2;
+}
+
+impl VersionedRecord for RoyaltyLedger {
+    const KIND: &'static str = "RoyaltyLedger";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for MarketplaceConfig {
+    const KIND: &'static str = "MarketplaceConfig";
+    const VERSION: u32 = 4;
+}
+
+impl VersionedRecord for TreasuryBalance {
+    const KIND: &'static str = "TreasuryBalance";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for PendingWithdrawal {
+    const KIND: &'static str = "PendingWithdrawal";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for Bid {
+    const KIND: &'static str = "Bid";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for CollectionSupply {
+    const KIND: &'static str = "CollectionSupply";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for FeeChangeRecord {
+    const KIND: &'static str = "FeeChangeRecord";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for TokenApprovals {
+    const KIND: &'static str = "TokenApprovals";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for CollectionApprovals {
+    const KIND: &'static str = "CollectionApprovals";
+    const VERSION: u32 = 1;
+}
+
+// ICRC-3 blocks
+impl VersionedRecord for Value {
+    const KIND: &'static str = "Value";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for StoredAsset {
+    const KIND: &'static str = "StoredAsset";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for StorageUsage {
+    const KIND: &'static str = "StorageUsage";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for BatchMintJob {
+    const KIND: &'static str = "BatchMintJob";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for Collection {
+    const KIND: &'static str = "Collection";
+    const VERSION: u32 = 1;
+}
+
+impl VersionedRecord for TrendingScore {
+    const KIND: &'static str = "TrendingScore";
+    const VERSION: u32 = 1;
+}
+
+// Rewrites a candid payload of `KIND` at `from_version` into `from_version + 1`
+pub struct Migration {
+    pub kind: &'static str,
+    pub from_version: u32,
+    pub migrate: fn(Vec<u8>) -> std::result::Result<Vec<u8>, String>,
+}
+
+// The migration registry. When a stored type changes shape, bump its VERSION,
+// keep the old struct around as e.g. `IPNftV1`, and add an entry that decodes
+// the old payload and re-encodes it as the new type.
+const MIGRATIONS: &[Migration] = &[
+    // Version 1 is the pre-envelope schema, so legacy payloads carry over as-is
+    Migration { kind: IntellectualProperty::KIND, from_version: LEGACY_VERSION, migrate: unchanged_payload },
+    Migration { kind: IPNft::KIND, from_version: LEGACY_VERSION, migrate: unchanged_payload },
+    Migration { kind: UserProfile::KIND, from_version: LEGACY_VERSION, migrate: unchanged_payload },
+    Migration { kind: MarketplaceListing::KIND, from_version: LEGACY_VERSION, migrate: unchanged_payload },
+    Migration { kind: NFTMetadata::KIND, from_version: LEGACY_VERSION, migrate: unchanged_payload },
+    Migration { kind: MarketplaceConfig::KIND, from_version: 1, migrate: marketplace_config_v1_to_v2 },
+    Migration { kind: MarketplaceConfig::KIND, from_version: 2, migrate: marketplace_config_v2_to_v3 },
+    Migration { kind: MarketplaceConfig::KIND, from_version: 3, migrate: marketplace_config_v3_to_v4 },
+    // v2 adds the optional owner_subaccount, which candid reads as null from v1
+    Migration { kind: IPNft::KIND, from_version: 1, migrate: unchanged_payload },
+    Migration { kind: IntellectualProperty::KIND, from_version: 1, migrate: intellectual_property_v1_to_v2 },
+    Migration { kind: IntellectualProperty::KIND, from_version: 2, migrate: intellectual_property_v2_to_v3 },
+    // v2 adds the optional fee and stalled_at, which candid reads as null from v1
+    Migration { kind: PendingPayout::KIND, from_version: 1, migrate: unchanged_payload },
+];
+
+fn unchanged_payload(payload: Vec<u8>) -> std::result::Result<Vec<u8>, String> {
+    Ok(payload)
+}
+
+#[derive(CandidType, Deserialize)]
+struct MarketplaceConfigV1 {
+    max_royalty_percentage: u8,
+}
+
+#[derive(CandidType, Deserialize)]
+struct MarketplaceConfigV2 {
+    max_royalty_percentage: u8,
+    platform_fee_bps: u16,
+}
+
+#[derive(CandidType, Deserialize)]
+struct MarketplaceConfigV3 {
+    max_royalty_percentage: u8,
+    platform_fee_bps: u16,
+    max_file_size_bytes: u64,
+    storage_quota_bytes: u64,
+}
+
+// v2 adds the platform fee, which starts at zero
+fn marketplace_config_v1_to_v2(payload: Vec<u8>) -> std::result::Result<Vec<u8>, String> {
+    let old: MarketplaceConfigV1 = candid::decode_one(&payload).map_err(|e| e.to_string())?;
+    candid::encode_one(MarketplaceConfigV2 {
+        max_royalty_percentage: old.max_royalty_percentage,
+        platform_fee_bps: 0,
+    })
+    .map_err(|e| e.to_string())
+}
+
+// v3 adds the on-chain storage limits, which start at the defaults
+fn marketplace_config_v2_to_v3(payload: Vec<u8>) -> std::result::Result<Vec<u8>, String> {
+    let old: MarketplaceConfigV2 = candid::decode_one(&payload).map_err(|e| e.to_string())?;
+    let defaults = MarketplaceConfig::default();
+    candid::encode_one(MarketplaceConfigV3 {
+        max_royalty_percentage: old.max_royalty_percentage,
+        platform_fee_bps: old.platform_fee_bps,
+        max_file_size_bytes: defaults.max_file_size_bytes,
+        storage_quota_bytes: defaults.storage_quota_bytes,
+    })
+    .map_err(|e| e.to_string())
+}
+
+// v4 adds the trending decay settings, which start at the defaults
+fn marketplace_config_v3_to_v4(payload: Vec<u8>) -> std::result::Result<Vec<u8>, String> {
+    let old: MarketplaceConfigV3 = candid::decode_one(&payload).map_err(|e| e.to_string())?;
+    let defaults = MarketplaceConfig::default();
+    candid::encode_one(MarketplaceConfig {
+        max_royalty_percentage: old.max_royalty_percentage,
+        platform_fee_bps: old.platform_fee_bps,
+        max_file_size_bytes: old.max_file_size_bytes,
+        storage_quota_bytes: old.storage_quota_bytes,
+        trending_half_life_nanos: defaults.trending_half_life_nanos,
+        trending_window_nanos: defaults.trending_window_nanos,
+    })
+    .map_err(|e| e.to_string())
+}
+
+#[derive(CandidType, Deserialize)]
+struct IntellectualPropertyV1 {
+    id: String,
+    title: String,
+    description: String,
+    ip_type: IPType,
+    owner: candid::Principal,
+    creator: candid::Principal,
+    creation_date: u64,
+    registration_date: u64,
+    metadata: IPMetadata,
+    verification_status: VerificationStatus,
+    nft_id: Option<String>,
+    image_url: Option<String>,
+    additional_files: Vec<FileMetadata>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IntellectualPropertyV2 {
+    id: String,
+    title: String,
+    description: String,
+    ip_type: IPType,
+    owner: candid::Principal,
+    creator: candid::Principal,
+    creation_date: u64,
+    registration_date: u64,
+    metadata: IPMetadata,
+    verification_status: VerificationStatus,
+    nft_ids: Vec<String>,
+    total_editions: Option<u32>,
+    image_url: Option<String>,
+    additional_files: Vec<FileMetadata>,
+}
+
+// v2 tracks every edition minted from the IP. v1 allowed a single NFT, so an
+// IP that already has one is capped at one edition.
+fn intellectual_property_v1_to_v2(payload: Vec<u8>) -> std::result::Result<Vec<u8>, String> {
+    let old: IntellectualPropertyV1 = candid::decode_one(&payload).map_err(|e| e.to_string())?;
+    let total_editions = old.nft_id.as_ref().map(|_| 1);
+    candid::encode_one(IntellectualPropertyV2 {
+        id: old.id,
+        title: old.title,
+        description: old.description,
+        ip_type: old.ip_type,
+        owner: old.owner,
+        creator: old.creator,
+        creation_date: old.creation_date,
+        registration_date: old.registration_date,
+        metadata: old.metadata,
+        verification_status: old.verification_status,
+        nft_ids: old.nft_id.into_iter().collect(),
+        total_editions,
+        image_url: old.image_url,
+        additional_files: old.additional_files,
+    })
+    .map_err(|e| e.to_string())
+}
+
+// v3 counts minted editions separately, since burns shrink nft_ids. Nothing
+// could be burned before v3.
+fn intellectual_property_v2_to_v3(payload: Vec<u8>) -> std::result::Result<Vec<u8>, String> {
+    let old: IntellectualPropertyV2 = candid::decode_one(&payload).map_err(|e| e.to_string())?;
+    candid::encode_one(IntellectualProperty {
+        id: old.id,
+        title: old.title,
+        description: old.description,
+        ip_type: old.ip_type,
+        owner: old.owner,
+        creator: old.creator,
+        creation_date: old.creation_date,
+        registration_date: old.registration_date,
+        metadata: old.metadata,
+        verification_status: old.verification_status,
+        minted_editions: old.nft_ids.len() as u32,
+        nft_ids: old.nft_ids,
+        total_editions: old.total_editions,
+        image_url: old.image_url,
+        additional_files: old.additional_files,
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn find_migration(kind: &str, from_version: u32) -> Option<&'static Migration> {
+    MIGRATIONS
+        .iter()
+        .find(|m| m.kind == kind && m.from_version == from_version)
+}
+
+// Splits stored bytes into (schema version, candid payload)
+pub fn split_envelope(bytes: &[u8]) -> (u32, &[u8]) {
+    if bytes.len() >= ENVELOPE_HEADER_LEN && &bytes[..4] == ENVELOPE_MAGIC {
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&bytes[4..ENVELOPE_HEADER_LEN]);
+        (u32::from_le_bytes(version), &bytes[ENVELOPE_HEADER_LEN..])
+    } else {
+        (LEGACY_VERSION, bytes)
+    }
+}
+
+pub fn encode_versioned<T: VersionedRecord>(record: &T) -> Vec<u8> {
+    let payload = candid::encode_one(record)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to encode {}: {}", T::KIND, e)));
+    let mut bytes = Vec::with_capacity(ENVELOPE_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(ENVELOPE_MAGIC);
+    bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+// Decodes a stored record, running any migrations needed to reach the current
+// schema. Traps rather than substituting a default: losing a record silently is
+// worse than failing the call.
+pub fn decode_versioned<T: VersionedRecord>(bytes: &[u8]) -> T {
+    let (version, payload) = split_envelope(bytes);
+    let payload = migrate_payload(T::KIND, version, T::VERSION, payload.to_vec())
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    candid::decode_one(&payload).unwrap_or_else(|e| {
+        ic_cdk::trap(&format!("failed to decode {} v{}: {}", T::KIND, T::VERSION, e))
+    })
+}
+
+fn migrate_payload(
+    kind: &str,
+    mut version: u32,
+    target: u32,
+    mut payload: Vec<u8>,
+) -> std::result::Result<Vec<u8>, String> {
+    if version > target {
+        return Err(format!("{} v{} is newer than this canister's v{}", kind, version, target));
+    }
+    while version < target {
+        let migration = find_migration(kind, version)
+            .ok_or_else(|| format!("no migration registered for {} v{}", kind, version))?;
+        payload = (migration.migrate)(payload)
+            .map_err(|e| format!("migrating {} v{} failed: {}", kind, version, e))?;
+        version += 1;
+    }
+    Ok(payload)
+}
+
+fn has_migration_path(kind: &str, from_version: u32, target: u32) -> bool {
+    from_version <= target
+        && (from_version..target).all(|version| find_migration(kind, version).is_some())
+}
+
+fn build_report(kind: &str, current_version: u32, versions: Vec<u32>) -> SchemaMigrationReport {
+    let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+    for version in versions {
+        *counts.entry(version).or_insert(0) += 1;
+    }
+
+    let mut pending = 0;
+    let mut unmigratable = 0;
+    for (&version, &count) in &counts {
+        if version != current_version {
+            pending += count;
+            if !has_migration_path(kind, version, current_version) {
+                unmigratable += count;
+            }
+        }
+    }
+
+    SchemaMigrationReport {
+        record_kind: kind.to_string(),
+        current_version,
+        versions: counts
+            .into_iter()
+            .map(|(version, count)| RecordVersionCount { version, count })
+            .collect(),
+        pending,
+        unmigratable,
+    }
+}
+
+fn outdated_keys<K: Clone>(records: &[(K, u32)], current_version: u32) -> Vec<K> {
+    records
+        .iter()
+        .filter(|(_, version)| *version != current_version)
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+// Called from post_upgrade: rewrites every record stored under an older schema
+// version so that later reads never pay for (or fail in) a migration.
+pub fn run_schema_migrations() -> Vec<(String, u64)> {
+    let ip_keys = outdated_keys(&ip_registry_record_versions(), IntellectualProperty::VERSION);
+    let nft_keys = outdated_keys(&nft_registry_record_versions(), IPNft::VERSION);
+    let user_keys = outdated_keys(&user_registry_record_versions(), UserProfile::VERSION);
+    let listing_keys = outdated_keys(&marketplace_record_versions(), MarketplaceListing::VERSION);
+    let metadata_keys = outdated_keys(&nft_metadata_record_versions(), NFTMetadata::VERSION);
+
+    let migrated = vec![
+        (IntellectualProperty::KIND.to_string(), ip_keys.len() as u64),
+        (IPNft::KIND.to_string(), nft_keys.len() as u64),
+        (UserProfile::KIND.to_string(), user_keys.len() as u64),
+        (MarketplaceListing::KIND.to_string(), listing_keys.len() as u64),
+        (NFTMetadata::KIND.to_string(), metadata_keys.len() as u64),
+    ];
+
+    // `get` decodes through the migration chain and `insert` re-encodes at the
+    // current version; the indexes are unchanged, since migrations keep owners,
+    // sellers and statuses
+    for key in ip_keys {
+        if let Some(record) = with_ip_registry(|registry| registry.get(&key)) {
+            insert_ip(record);
+        }
+    }
+    for key in nft_keys {
+        if let Some(record) = with_nft_registry(|registry| registry.get(&key)) {
+            insert_nft(record);
+        }
+    }
+    with_user_registry_mut(|registry| {
+        for key in user_keys {
+            if let Some(record) = registry.get(&key) {
+                registry.insert(key, record);
+            }
+        }
+    });
+    for key in listing_keys {
+        if let Some(record) = with_marketplace(|marketplace| marketplace.get(&key)) {
+            insert_listing(record);
+        }
+    }
+    with_nft_metadata_mut(|registry| {
+        for key in metadata_keys {
+            if let Some(record) = registry.get(&key) {
+                registry.insert(key, record);
+            }
+        }
+    });
+
+    // The config cell decoded (and migrated) its value on load; writing it back
+    // stores it at the current version
+    with_marketplace_config_mut(|_| ());
+
+    migrated
+}
+
+// Data fixes that must run over existing records once, such as backfilling
+// state that is kept incrementally from then on. Each is recorded by name when it
+// completes, so later upgrades skip it; add new steps at the end.
+const ONE_TIME_MIGRATIONS: &[(&str, fn())] = &[
+    ("collection_supply", crate::collections::rebuild_collection_supply),
+    ("reserved_collection_names", crate::collections::reserve_legacy_collection_names),
+    ("favorite_counts", crate::favorites::reconcile_favorite_counts),
+    ("trending_ranks", crate::trending::rebuild_trending_ranks),
+];
+
+// Called from post_upgrade: runs the one-time migrations not yet run and returns
+// their names
+pub fn run_one_time_migrations() -> Vec<&'static str> {
+    let mut ran = Vec::new();
+    for &(name, migrate) in ONE_TIME_MIGRATIONS {
+        if with_data_migrations(|done| done.contains_key(&name.to_string())) {
+            continue;
+        }
+        migrate();
+        with_data_migrations_mut(|done| done.insert(name.to_string(), time()));
+        ran.push(name);
+    }
+    ran
+}
+
+// Called from init: a new canister has no records to fix
+pub fn skip_one_time_migrations() {
+    let now = time();
+    with_data_migrations_mut(|done| {
+        for &(name, _) in ONE_TIME_MIGRATIONS {
+            done.insert(name.to_string(), now);
+        }
+    });
+}
+
+// Dry run: how many records of each kind sit at each schema version, and how many
+// the next upgrade would have to migrate
+#[query]
+pub fn get_schema_migration_report() -> Vec<SchemaMigrationReport> {
+    vec![
+        build_report(
+            IntellectualProperty::KIND,
+            IntellectualProperty::VERSION,
+            ip_registry_record_versions().into_iter().map(|(_, v)| v).collect(),
+        ),
+        build_report(
+            IPNft::KIND,
+            IPNft::VERSION,
+            nft_registry_record_versions().into_iter().map(|(_, v)| v).collect(),
+        ),
+        build_report(
+            UserProfile::KIND,
+            UserProfile::VERSION,
+            user_registry_record_versions().into_iter().map(|(_, v)| v).collect(),
+        ),
+        build_report(
+            MarketplaceListing::KIND,
+            MarketplaceListing::VERSION,
+            marketplace_record_versions().into_iter().map(|(_, v)| v).collect(),
+        ),
+        build_report(
+            NFTMetadata::KIND,
+            NFTMetadata::VERSION,
+            nft_metadata_record_versions().into_iter().map(|(_, v)| v).collect(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_time_migrations_run_once() {
+        let ran = run_one_time_migrations();
+        assert!(ran.contains(&"favorite_counts"));
+        assert_eq!(ran.len(), ONE_TIME_MIGRATIONS.len());
+
+        assert!(run_one_time_migrations().is_empty());
+    }
+
+    #[test]
+    fn new_canister_skips_one_time_migrations() {
+        skip_one_time_migrations();
+
+        assert!(run_one_time_migrations().is_empty());
+    }
+}
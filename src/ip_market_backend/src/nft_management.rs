@@ -1,15 +1,42 @@
-use ic_cdk::api::time;
 use ic_cdk::{query, update};
-use candid::Principal;
+use candid::{Nat, Principal};
 
 use crate::types::*;
 use crate::storage::*;
+use crate::runtime::{canister_id, time};
 use crate::utils::*;
+use crate::royalties::resolve_royalty_percentage;
+use crate::user_management::{add_nft_to_user, remove_nft_from_user};
+use crate::icrc37::clear_token_approvals;
+use crate::icrc7::{owner_account, take_value};
+use crate::http::{certify_nft, certify_nfts};
+use crate::assets::validate_image_reference;
+use crate::icrc3::{log_burn, log_mint, log_transfer};
+use crate::marketplace::{cancel_listings_for_nft, ensure_listings_cancellable};
+use crate::collections::{check_collection_mint, record_collection_burn, record_collection_mint, reserve_collection_supply};
+use crate::favorites::remove_nft_favorites;
+use crate::trending::remove_trending_nft;
+use crate::text_search::{index_nft_text, text_scores, unindex_nft_text, Corpus};
+use crate::rarity::{add_to_rarity, remove_from_rarity};
+
+// Most editions minted in one call, to stay well inside the instruction limit
+pub const MAX_EDITIONS_PER_MINT: u32 = 50;
 
 #[update]
 pub fn mint_ip_nft(request: MintNFTRequest) -> Result<IPNft> {
+    let mut nfts = mint_editions(request, 1)?;
+    Ok(nfts.remove(0))
+}
+
+#[update]
+pub fn mint_ip_nft_editions(request: MintNFTRequest, count: u32) -> Result<Vec<IPNft>> {
+    mint_editions(request, count)
+}
+
+// Mints `count` editions of an IP, numbered after those already minted. The
+// first mint fixes the IP's total_editions; later mints cannot exceed it.
+fn mint_editions(request: MintNFTRequest, count: u32) -> Result<Vec<IPNft>> {
     let caller = ic_cdk::caller();
-    let now = time();
     
     // Get IP record
     let ip = with_ip_registry(|registry| {
@@ -21,109 +48,191 @@ pub fn mint_ip_nft(request: MintNFTRequest) -> Result<IPNft> {
         return Err(IPMarketplaceError::Unauthorized);
     }
     
-    // Check if NFT already exists
-    if ip.nft_id.is_some() {
+    let plan = validate_mint(caller, &request, ip.total_editions, ip.minted_editions, count, 0)?;
+    if let Some(ref collection_name) = plan.collection_name {
+        reserve_collection_supply(collection_name, count);
+    }
+    Ok(commit_mint(caller, ip, &request, count, &plan, time()))
+}
+
+// The values a mint resolved to in validate_mint
+pub struct MintPlan {
+    pub total_editions: u32,
+    pub royalty_percentage: u8,
+    pub collection_name: Option<String>, // as stored on the collection
+}
+
+// Checks a mint of `count` editions against an IP with `minted` editions so
+// far and the given cap, and against the collection, where `reserved` editions
+// are already claimed by earlier items of the same batch. Shared with batch
+// minting.
+pub fn validate_mint(
+    caller: Principal,
+    request: &MintNFTRequest,
+    cap: Option<u32>,
+    minted: u32,
+    count: u32,
+    reserved: u32,
+) -> Result<MintPlan> {
+    if count == 0 || count > MAX_EDITIONS_PER_MINT {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    
+    // Check the edition cap
+    let total_editions = match (cap, request.total_editions) {
+        (Some(cap), Some(requested)) if cap != requested => {
+            return Err(IPMarketplaceError::InvalidInput);
+        }
+        (Some(cap), _) => cap,
+        (None, Some(requested)) => requested,
+        (None, None) => count,
+    };
+    if minted >= total_editions {
         return Err(IPMarketplaceError::AlreadyExists);
     }
+    if count > total_editions - minted {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
     
     // Validate image URL
-    if !validate_image_url(&request.image) {
+    if !validate_image_reference(&request.image) {
         return Err(IPMarketplaceError::InvalidInput);
     }
     
-    let nft_id = generate_id("NFT");
-    
-    // Generate token ID
-    let token_id = with_counter_mut(|counter| {
-        let current = *counter;
-        *counter = current + 1;
-        current
-    });
-    
-    // Calculate rarity score
-    let rarity_score = calculate_rarity_score(&request.attributes);
-    
-    // Create comprehensive NFT metadata
-    let metadata = NFTMetadata {
-        token_id: nft_id.clone(),
-        name: request.name.clone(),
-        description: request.description.clone(),
-        image: request.image.clone(),
-        external_url: request.external_url,
-        animation_url: request.animation_url,
-        background_color: request.background_color,
-        attributes: request.attributes,
-        ip_category: ip.metadata.category.clone(),
-        ip_type: format!("{:?}", ip.ip_type),
-        creator: caller.to_string(),
-        creation_date: format_timestamp(ip.creation_date),
-        jurisdiction: Some(ip.metadata.jurisdiction.clone()),
-        license_type: None, // To be set based on license terms
-        file_type: Some("image/png".to_string()), // Default, should be detected
-        file_size: None,
-        resolution: None,
-        duration: None,
-        minted_date: format_timestamp(now),
-        blockchain: "Internet Computer".to_string(),
-        token_standard: "ICRC-7".to_string(),
+    // Only the collection's owner and allowed minters may mint into it
+    let collection = match request.collection_name {
+        Some(ref name) => Some(check_collection_mint(caller, name, count, reserved)?),
+        None => None,
     };
+    let default_royalty = collection.as_ref().and_then(|c| c.default_royalty_percentage);
+    let royalty_percentage = resolve_royalty_percentage(request.royalty_percentage.or(default_royalty))?;
     
-    // Store metadata
-    with_nft_metadata_mut(|registry| {
-        registry.insert(nft_id.clone(), metadata);
-    });
+    Ok(MintPlan {
+        total_editions,
+        royalty_percentage,
+        collection_name: collection.map(|c| c.name),
+    })
+}
+
+// Mints `count` editions validated by validate_mint; `request.ip_id` is
+// ignored in favour of `ip`. Collection supply is reserved by the caller.
+pub fn commit_mint(
+    caller: Principal,
+    mut ip: IntellectualProperty,
+    request: &MintNFTRequest,
+    count: u32,
+    plan: &MintPlan,
+    now: u64,
+) -> Vec<IPNft> {
+    let minted = ip.minted_editions;
+    let first_new = ip.nft_ids.len();
     
-    // Create NFT
-    let nft = IPNft {
-        id: nft_id.clone(),
-        ip_id: request.ip_id.clone(),
-        token_id,
-        owner: caller,
-        creator: caller,
-        metadata_uri: format!("ic://{}/metadata/{}", ic_cdk::id(), nft_id),
-        minted_at: now,
-        royalty_percentage: request.royalty_percentage.unwrap_or(10),
-        is_transferable: true,
-        name: request.name,
-        description: request.description,
-        image: request.image,
-        collection_name: request.collection_name,
-        edition_number: request.edition_number,
-        total_editions: request.total_editions,
-        rarity_rank: None, // To be calculated globally
-        rarity_score: Some(rarity_score),
-        transfer_history: vec![TransferRecord {
-            from: Principal::anonymous(),
-            to: caller,
-            timestamp: now,
-            transaction_hash: None,
-            price: None,
-        }],
-        view_count: 0,
-        favorite_count: 0,
-    };
+    let mut nfts = Vec::with_capacity(count as usize);
+    for edition_number in minted + 1..=minted + count {
+        let nft_id = generate_id("NFT");
+        
+        // Generate token ID
+        let token_id = next_sequence(TOKEN_ID_SEQUENCE);
+        
+        // Create comprehensive NFT metadata
+        let metadata = NFTMetadata {
+            token_id: nft_id.clone(),
+            name: request.name.clone(),
+            description: request.description.clone(),
+            image: request.image.clone(),
+            external_url: request.external_url.clone(),
+            animation_url: request.animation_url.clone(),
+            background_color: request.background_color.clone(),
+            attributes: request.attributes.clone(),
+            ip_category: ip.metadata.category.clone(),
+            ip_type: format!("{:?}", ip.ip_type),
+            creator: caller.to_string(),
+            creation_date: format_timestamp(ip.creation_date),
+            jurisdiction: Some(ip.metadata.jurisdiction.clone()),
+            license_type: None, // To be set based on license terms
+            file_type: Some("image/png".to_string()), // Default, should be detected
+            file_size: None,
+            resolution: None,
+            duration: None,
+            minted_date: format_timestamp(now),
+            blockchain: "Internet Computer".to_string(),
+            token_standard: "ICRC-7".to_string(),
+        };
+        
+        // Store metadata
+        with_nft_metadata_mut(|registry| {
+            registry.insert(nft_id.clone(), metadata.clone());
+        });
+        
+        // Create NFT
+        let nft = IPNft {
+            id: nft_id.clone(),
+            ip_id: ip.id.clone(),
+            token_id,
+            owner: caller,
+            owner_subaccount: None,
+            creator: caller,
+            metadata_uri: format!("ic://{}/metadata/{}", canister_id(), nft_id),
+            minted_at: now,
+            royalty_percentage: plan.royalty_percentage,
+            is_transferable: true,
+            name: request.name.clone(),
+            description: request.description.clone(),
+            image: request.image.clone(),
+            collection_name: plan.collection_name.clone(),
+            edition_number: Some(edition_number),
+            total_editions: Some(plan.total_editions),
+            rarity_rank: None, // Set when the collection is next refreshed
+            rarity_score: None,
+            transfer_history: vec![TransferRecord {
+                from: Principal::anonymous(),
+                to: caller,
+                timestamp: now,
+                transaction_hash: None,
+                price: None,
+            }],
+            view_count: 0,
+            favorite_count: 0,
+        };
+        
+        // Store NFT
+        insert_nft(nft.clone());
+        index_nft_text(&nft, Some(&metadata));
+        with_token_index_mut(|index| {
+            index.insert(token_id, nft_id.clone());
+        });
+        log_mint(&nft);
+        
+        ip.nft_ids.push(nft_id);
+        nfts.push(nft);
+    }
     
-    // Store NFT
-    with_nft_registry_mut(|registry| {
-        registry.insert(nft_id.clone(), nft.clone());
-    });
+    let nft_ids = ip.nft_ids[first_new..].to_vec();
+    if let Some(ref collection_name) = plan.collection_name {
+        record_collection_mint(collection_name, &nfts);
+    }
+    certify_nfts(&nft_ids, plan.collection_name.as_deref());
     
-    // Update IP record with NFT ID
-    with_ip_registry_mut(|registry| {
-        let mut updated_ip = ip;
-        updated_ip.nft_id = Some(nft_id.clone());
-        registry.insert(request.ip_id, updated_ip);
-    });
+    // New editions shift the trait frequencies of the whole collection; their
+    // ranks are written once the collection is refreshed
+    if let Some(ref collection_name) = plan.collection_name {
+        add_to_rarity(collection_name, &nfts, &request.attributes);
+    }
+    
+    // Update IP record with the edition IDs
+    ip.total_editions = Some(plan.total_editions);
+    ip.minted_editions += count;
+    insert_ip(ip);
     
     // Update user profile
     with_user_registry_mut(|registry| {
         if let Some(mut user) = registry.get(&caller) {
-            user.owned_nfts.push(nft_id);
+            user.owned_nfts.extend(nft_ids);
             registry.insert(caller, user);
         }
     });
     
-    Ok(nft)
+    nfts
 }
 
 #[update]
@@ -132,7 +241,7 @@ pub fn transfer_nft(nft_id: String, to: Principal) -> Result<bool> {
     let now = time();
     
     // Get NFT
-    let mut nft = with_nft_registry(|registry| {
+    let nft = with_nft_registry(|registry| {
         registry.get(&nft_id)
     }).ok_or(IPMarketplaceError::NotFound)?;
     
@@ -145,39 +254,131 @@ pub fn transfer_nft(nft_id: String, to: Principal) -> Result<bool> {
         return Err(IPMarketplaceError::NFTNotTransferable);
     }
     
-    // Update NFT ownership
-    nft.owner = to;
-    nft.transfer_history.push(TransferRecord {
-        from: caller,
-        to,
+    // Listings do not follow the NFT to its new owner
+    ensure_listings_cancellable(&nft_id)?;
+    cancel_listings_for_nft(&nft_id);
+    
+    let to = Account { owner: to, subaccount: None };
+    let block_index = log_transfer(nft.token_id, &owner_account(&nft), &to, &None, None);
+    record_nft_transfer(&nft_id, to, None, Some(block_index.to_string()), now);
+    
+    Ok(true)
+}
+
+// Destroys an NFT: cancels its listings, removes it from the registries and
+// the owner's profile, and logs the burn. The IP keeps counting it as minted,
+// and the NFT's history stays queryable through get_burned_nft.
+#[update]
+pub fn burn_nft(nft_id: String) -> Result<bool> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    
+    // Get NFT
+    let nft = with_nft_registry(|registry| {
+        registry.get(&nft_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
+    
+    // Check ownership
+    if nft.owner != caller {
+        return Err(IPMarketplaceError::Unauthorized);
+    }
+    
+    ensure_listings_cancellable(&nft_id)?;
+    cancel_listings_for_nft(&nft_id);
+    let block_index = log_burn(&nft);
+    
+    // Keep the ownership history, ending with a transfer to the anonymous principal
+    let mut burned = nft.clone();
+    burned.transfer_history.push(TransferRecord {
+        from: nft.owner,
+        to: Principal::anonymous(),
         timestamp: now,
-        transaction_hash: None,
+        transaction_hash: Some(block_index.to_string()),
         price: None,
     });
+    with_burned_nfts_mut(|registry| {
+        registry.insert(nft_id.clone(), burned);
+    });
     
     // Update registries
-    with_nft_registry_mut(|registry| {
-        registry.insert(nft_id.clone(), nft);
+    remove_nft(&nft_id);
+    let metadata = with_nft_metadata_mut(|registry| {
+        registry.remove(&nft_id)
     });
-    
-    // Update user profiles
-    with_user_registry_mut(|registry| {
-        // Remove from sender
-        if let Some(mut sender) = registry.get(&caller) {
-            sender.owned_nfts.retain(|id| id != &nft_id);
-            registry.insert(caller, sender);
-        }
-        
-        // Add to receiver
-        if let Some(mut receiver) = registry.get(&to) {
-            receiver.owned_nfts.push(nft_id);
-            registry.insert(to, receiver);
-        }
+    with_token_index_mut(|index| {
+        index.remove(&nft.token_id);
     });
+    clear_token_approvals(nft.token_id);
+    unindex_nft_text(&nft, metadata.as_ref());
+    
+    // Update IP record
+    if let Some(mut ip) = with_ip_registry(|registry| registry.get(&nft.ip_id)) {
+        ip.nft_ids.retain(|id| id != &nft_id);
+        insert_ip(ip);
+    }
+    
+    remove_nft_from_user(nft.owner, &nft_id);
+    remove_nft_favorites(&nft_id);
+    remove_trending_nft(&nft_id);
+    if let Some(ref collection_name) = nft.collection_name {
+        record_collection_burn(collection_name);
+    }
+    certify_nft(&nft_id, nft.collection_name.as_deref());
+    
+    if let Some(ref collection_name) = nft.collection_name {
+        remove_from_rarity(collection_name, &nft_id);
+    }
     
     Ok(true)
 }
 
+// A burned NFT with its full transfer history
+#[query]
+pub fn get_burned_nft(nft_id: String) -> Result<IPNft> {
+    with_burned_nfts(|registry| {
+        registry.get(&nft_id)
+    }).ok_or(IPMarketplaceError::NotFound)
+}
+
+// Moves an NFT to a new owner account, appends the transfer record and updates
+// both users' profiles. Callers check authorization and cancel the NFT's
+// listings first; shared by direct transfers, ICRC-7 transfers and marketplace
+// sales.
+pub fn record_nft_transfer(
+    nft_id: &str,
+    to: Account,
+    price: Option<u64>,
+    transaction_hash: Option<String>,
+    now: u64,
+) -> Option<IPNft> {
+    let mut nft = with_nft_registry(|registry| {
+        registry.get(&nft_id.to_string())
+    })?;
+    
+    let from = nft.owner;
+    nft.owner = to.owner;
+    nft.owner_subaccount = to.subaccount;
+    nft.transfer_history.push(TransferRecord {
+        from,
+        to: to.owner,
+        timestamp: now,
+        transaction_hash,
+        price,
+    });
+    
+    // Update registries
+    insert_nft(nft.clone());
+    clear_token_approvals(nft.token_id);
+    
+    // Update user profiles
+    if from != to.owner {
+        remove_nft_from_user(from, nft_id);
+        add_nft_to_user(to.owner, nft_id.to_string());
+    }
+    
+    Some(nft)
+}
+
 #[query]
 pub fn get_nft_metadata(nft_id: String) -> Result<NFTMetadata> {
     with_nft_metadata(|registry| {
@@ -211,101 +412,166 @@ pub fn get_nft_by_id(nft_id: String) -> Result<IPNft> {
 
 #[query]
 pub fn get_user_nfts(user: Principal) -> Vec<IPNft> {
-    with_nft_registry(|registry| {
-        registry
-            .iter()
-            .filter(|(_, nft)| nft.owner == user)
-            .map(|(_, nft)| nft.clone())
-            .collect()
-    })
+    nfts_of_owner(user)
 }
 
-#[query]
-pub fn get_trending_nfts(limit: usize) -> Vec<IPNft> {
-    with_nft_registry(|registry| {
-        let mut nfts: Vec<IPNft> = registry
-            .iter()
-            .map(|(_, nft)| nft.clone())
-            .collect();
-        
-        // Sort by view count + favorite count for trending
-        nfts.sort_by(|a, b| (b.view_count + b.favorite_count).cmp(&(a.view_count + a.favorite_count)));
-        nfts.truncate(limit);
-        nfts
-    })
+#[derive(Clone, Copy, PartialEq)]
+enum NFTSortOrder {
+    TokenId,
+    PriceAsc,
+    PriceDesc,
+    Newest,
+    Oldest,
+    Rarity,
+    Relevance,
+}
+
+impl NFTSortOrder {
+    // Unsorted searches rank by relevance when there is query text
+    fn parse(sort_by: Option<&str>, has_query: bool) -> Result<Self> {
+        match sort_by {
+            None | Some("") if has_query => Ok(NFTSortOrder::Relevance),
+            None | Some("") => Ok(NFTSortOrder::TokenId),
+            Some("relevance") => Ok(NFTSortOrder::Relevance),
+            Some("price_asc") => Ok(NFTSortOrder::PriceAsc),
+            Some("price_desc") => Ok(NFTSortOrder::PriceDesc),
+            Some("newest") => Ok(NFTSortOrder::Newest),
+            Some("oldest") => Ok(NFTSortOrder::Oldest),
+            Some("rarity") => Ok(NFTSortOrder::Rarity),
+            Some(_) => Err(IPMarketplaceError::InvalidInput),
+        }
+    }
+
+    fn tag(self) -> u8 {
+        self as u8
+    }
 }
 
+// Where an NFT falls in a sort order: NFTs without a value (unlisted for the
+// price orders, unranked for rarity) come last, then by value, then by ID so
+// every NFT has a distinct position
+type NFTSortKey = (bool, u64, String);
+
+fn sort_key(order: NFTSortOrder, nft: &IPNft, price: Option<u64>, score: f64) -> NFTSortKey {
+    let (missing, value) = match order {
+        NFTSortOrder::TokenId => (false, nft.token_id),
+        NFTSortOrder::PriceAsc => price.map_or((true, 0), |price| (false, price)),
+        NFTSortOrder::PriceDesc => price.map_or((true, 0), |price| (false, u64::MAX - price)),
+        NFTSortOrder::Newest => (false, u64::MAX - nft.minted_at),
+        NFTSortOrder::Oldest => (false, nft.minted_at),
+        NFTSortOrder::Rarity => nft.rarity_rank.map_or((true, 0), |rank| (false, rank as u64)),
+        // Scores are never negative, so their bits order like the scores
+        NFTSortOrder::Relevance => (false, u64::MAX - score.to_bits()),
+    };
+    (missing, value, nft.id.clone())
+}
+
+// Cursors are the hex-encoded sort key of the last NFT on a page, tagged with
+// the order they belong to
+fn encode_cursor(order: NFTSortOrder, key: &NFTSortKey) -> String {
+    let (missing, value, nft_id) = key;
+    hex::encode(format!("{}:{}:{}:{}", order.tag(), *missing as u8, value, nft_id))
+}
+
+fn decode_cursor(order: NFTSortOrder, cursor: &str) -> Result<NFTSortKey> {
+    let decoded = hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(IPMarketplaceError::InvalidInput)?;
+    let mut parts = decoded.splitn(4, ':');
+    let mut next = || parts.next().ok_or(IPMarketplaceError::InvalidInput);
+    let (tag, missing, value, nft_id) = (next()?, next()?, next()?, next()?);
+
+    if tag != order.tag().to_string() {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+    let missing = match missing {
+        "0" => false,
+        "1" => true,
+        _ => return Err(IPMarketplaceError::InvalidInput),
+    };
+    let value = value.parse().map_err(|_| IPMarketplaceError::InvalidInput)?;
+    Ok((missing, value, nft_id.to_string()))
+}
+
+// Lowest asking price among each NFT's live listings
+fn live_listing_prices() -> std::collections::HashMap<String, u64> {
+    let mut prices = std::collections::HashMap::new();
+    let mut live = listings_with_status(&ListingStatus::Active);
+    live.extend(listings_with_status(&ListingStatus::InAuction));
+    for listing in live {
+        let price = prices.entry(listing.nft_id).or_insert(listing.price);
+        *price = (*price).min(listing.price);
+    }
+    prices
+}
+
+// NFTs matching every word of `query` and the filters, in `filters.sort_by`
+// order (by relevance if unset and there is query text), `limit` at a time.
+// Pass the previous page's next_cursor to continue after it.
 #[query]
-pub fn search_nfts(query: String, filters: NFTSearchFilters) -> Vec<IPNft> {
-    let query_lower = query.to_lowercase();
-    
-    with_nft_registry(|registry| {
-        registry
-            .iter()
-            .filter(|(_, nft)| {
-                let matches_query = nft.name.to_lowercase().contains(&query_lower) ||
-                                  nft.description.to_lowercase().contains(&query_lower);
-                
-                let matches_collection = match &filters.collection_name {
-                    Some(collection) => nft.collection_name.as_ref().map_or(false, |c| c == collection),
-                    None => true,
-                };
-                
-                let matches_price_range = match (filters.min_price, filters.max_price) {
-                    (Some(min), Some(max)) => {
-                        // Check if NFT is listed in marketplace within price range
-                        with_marketplace(|marketplace| {
-                            marketplace
-                                .iter()
-                                .any(|(_, listing)| {
-                                    listing.nft_id == nft.id && 
-                                    listing.price >= min && 
-                                    listing.price <= max &&
-                                    matches!(listing.status, ListingStatus::Active | ListingStatus::InAuction)
-                                })
-                        })
-                    },
-                    (Some(min), None) => {
-                        with_marketplace(|marketplace| {
-                            marketplace
-                                .iter()
-                                .any(|(_, listing)| {
-                                    listing.nft_id == nft.id && 
-                                    listing.price >= min &&
-                                    matches!(listing.status, ListingStatus::Active | ListingStatus::InAuction)
-                                })
-                        })
-                    },
-                    (None, Some(max)) => {
-                        with_marketplace(|marketplace| {
-                            marketplace
-                                .iter()
-                                .any(|(_, listing)| {
-                                    listing.nft_id == nft.id && 
-                                    listing.price <= max &&
-                                    matches!(listing.status, ListingStatus::Active | ListingStatus::InAuction)
-                                })
-                        })
-                    },
-                    (None, None) => true,
-                };
-                
-                matches_query && matches_collection && matches_price_range
-            })
-            .map(|(_, nft)| nft.clone())
-            .collect()
+pub fn search_nfts(
+    query: String,
+    filters: NFTSearchFilters,
+    cursor: Option<String>,
+    limit: Option<Nat>,
+) -> Result<NFTSearchPage> {
+    let scores = text_scores(Corpus::Nfts, &query);
+    let order = NFTSortOrder::parse(filters.sort_by.as_deref(), scores.is_some())?;
+    let after = cursor.map(|cursor| decode_cursor(order, &cursor)).transpose()?;
+    let prices = live_listing_prices();
+
+    // Query text narrows the candidates through the text index, or else a
+    // collection filter through the collection index
+    let candidates = match (&scores, &filters.collection_name) {
+        (Some(scores), _) => with_nft_registry(|registry| {
+            scores.keys().filter_map(|nft_id| registry.get(nft_id)).collect()
+        }),
+        (None, Some(collection_name)) => nfts_in_collection(collection_name),
+        (None, None) => with_nft_registry(|registry| registry.iter().map(|(_, nft)| nft).collect()),
+    };
+
+    let mut matches: Vec<(NFTSortKey, NFTSearchHit)> = candidates
+        .into_iter()
+        .filter(|nft| {
+            let matches_collection = match &filters.collection_name {
+                Some(collection) => nft.collection_name.as_ref() == Some(collection),
+                None => true,
+            };
+
+            // A price bound only matches NFTs listed within it
+            let matches_price_range = match (filters.min_price, filters.max_price) {
+                (None, None) => true,
+                (min, max) => prices.get(&nft.id).is_some_and(|&price| {
+                    min.is_none_or(|min| price >= min) && max.is_none_or(|max| price <= max)
+                }),
+            };
+
+            matches_collection && matches_price_range
+        })
+        .map(|nft| {
+            let score = scores.as_ref().and_then(|scores| scores.get(&nft.id)).copied().unwrap_or(0.0);
+            let key = sort_key(order, &nft, prices.get(&nft.id).copied(), score);
+            (key, NFTSearchHit { nft, score })
+        })
+        .collect();
+    matches.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let total_count = matches.len() as u64;
+    let start = after.map_or(0, |after| matches.partition_point(|(key, _)| *key <= after));
+    let end = start.saturating_add(take_value(limit).max(1)).min(matches.len());
+    let next_cursor = (end < matches.len()).then(|| encode_cursor(order, &matches[end - 1].0));
+
+    Ok(NFTSearchPage {
+        nfts: matches.drain(start..end).map(|(_, hit)| hit).collect(),
+        total_count,
+        next_cursor,
     })
 }
 
 #[query]
 pub fn get_nft_collection_stats(collection_name: String) -> CollectionStats {
-    let nfts: Vec<IPNft> = with_nft_registry(|registry| {
-        registry
-            .iter()
-            .filter(|(_, nft)| nft.collection_name.as_ref().map_or(false, |c| c == &collection_name))
-            .map(|(_, nft)| nft.clone())
-            .collect()
-    });
+    let nfts = nfts_in_collection(&collection_name);
     
     let total_supply = nfts.len() as u32;
     let unique_owners = nfts.iter()
@@ -314,16 +580,12 @@ pub fn get_nft_collection_stats(collection_name: String) -> CollectionStats {
         .len() as u32;
     
     // Get floor price from marketplace
-    let floor_price = with_marketplace(|marketplace| {
-        marketplace
-            .iter()
-            .filter(|(_, listing)| {
-                matches!(listing.status, ListingStatus::Active) &&
-                nfts.iter().any(|nft| nft.id == listing.nft_id)
-            })
-            .map(|(_, listing)| listing.price)
-            .min()
-    });
+    let floor_price = nfts
+        .iter()
+        .flat_map(|nft| listings_for_nft(&nft.id))
+        .filter(|listing| matches!(listing.status, ListingStatus::Active))
+        .map(|listing| listing.price)
+        .min();
     
     // Calculate total volume (simplified)
     let total_volume = nfts.iter()
@@ -341,38 +603,6 @@ pub fn get_nft_collection_stats(collection_name: String) -> CollectionStats {
     }
 }
 
-#[update]
-pub fn increment_nft_view(nft_id: String) -> Result<u64> {
-    with_nft_registry_mut(|registry| {
-        if let Some(mut nft) = registry.get(&nft_id) {
-            nft.view_count += 1;
-            let new_count = nft.view_count;
-            registry.insert(nft_id, nft);
-            Ok(new_count)
-        } else {
-            Err(IPMarketplaceError::NotFound)
-        }
-    })
-}
-
-#[update]
-pub fn toggle_nft_favorite(nft_id: String) -> Result<u64> {
-    let _caller = ic_cdk::caller();
-    
-    // In a real implementation, you'd track individual user favorites
-    // For simplicity, we're just incrementing/decrementing the total count
-    with_nft_registry_mut(|registry| {
-        if let Some(mut nft) = registry.get(&nft_id) {
-            nft.favorite_count += 1; // In reality, check if user already favorited
-            let new_count = nft.favorite_count;
-            registry.insert(nft_id, nft);
-            Ok(new_count)
-        } else {
-            Err(IPMarketplaceError::NotFound)
-        }
-    })
-}
-
 #[query]
 pub fn get_nft_history(nft_id: String) -> Result<Vec<TransferRecord>> {
     with_nft_registry(|registry| {
@@ -390,3 +620,37 @@ pub fn get_nfts_batch(nft_ids: Vec<String>) -> Vec<Option<IPNft>> {
             .collect()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::mint_test_nft;
+
+    #[test]
+    fn unsorted_search_pages_through_token_ids_in_numeric_order() {
+        let owner = Principal::from_slice(&[1]);
+        for token_id in (1..=12).rev() {
+            mint_test_nft(&format!("NFT_{}", token_id), token_id, owner, owner);
+        }
+
+        let mut token_ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let filters = NFTSearchFilters {
+                collection_name: None,
+                min_price: None,
+                max_price: None,
+                creator: None,
+                sort_by: None,
+            };
+            let page = search_nfts(String::new(), filters, cursor, Some(Nat::from(5u64))).unwrap();
+            assert_eq!(page.total_count, 12);
+            token_ids.extend(page.nfts.iter().map(|hit| hit.nft.token_id));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(token_ids, (1..=12).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,566 @@
+use ic_cdk::{query, update};
+use candid::{Nat, Principal};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::{canister_id, time};
+use crate::access_control::require_admin;
+use crate::ledger::{icrc1_balance_of, icrc1_fee, icrc1_transfer, icrc2_allowance, icrc2_transfer_from};
+use crate::royalties::record_royalty_paid;
+
+// Currency configuration
+#[update]
+pub fn set_currency_ledger(currency: String, ledger_canister_id: Principal) -> Result<CurrencyLedger> {
+    require_admin()?;
+
+    if currency.trim().is_empty() || ledger_canister_id == Principal::anonymous() {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    let config = CurrencyLedger {
+        currency: currency.clone(),
+        ledger_canister_id,
+        configured_by: ic_cdk::caller(),
+        configured_at: time(),
+    };
+
+    with_currency_ledgers_mut(|ledgers| {
+        ledgers.insert(currency, config.clone());
+    });
+
+    Ok(config)
+}
+
+#[update]
+pub fn remove_currency_ledger(currency: String) -> Result<bool> {
+    require_admin()?;
+
+    with_currency_ledgers_mut(|ledgers| {
+        ledgers.remove(&currency)
+    })
+    .map(|_| true)
+    .ok_or(IPMarketplaceError::NotFound)
+}
+
+#[query]
+pub fn get_currency_ledgers() -> Vec<CurrencyLedger> {
+    with_currency_ledgers(|ledgers| {
+        ledgers.iter().map(|(_, config)| config).collect()
+    })
+}
+
+pub fn ledger_for_currency(currency: &str) -> Result<Principal> {
+    with_currency_ledgers(|ledgers| {
+        ledgers.get(&currency.to_string())
+    })
+    .map(|config| config.ledger_canister_id)
+    .ok_or(IPMarketplaceError::InvalidInput)
+}
+
+pub fn principal_account(owner: Principal) -> Account {
+    Account {
+        owner,
+        subaccount: None,
+    }
+}
+
+// Buyers pay into this account; sale proceeds and royalties are paid out of it
+pub fn marketplace_account() -> Account {
+    principal_account(canister_id())
+}
+
+// ICRC-1 memos are capped at 32 bytes by default
+pub fn payment_memo(reference: &str) -> Option<Vec<u8>> {
+    let bytes = reference.as_bytes();
+    if bytes.len() <= 32 {
+        Some(bytes.to_vec())
+    } else {
+        None
+    }
+}
+
+// Pulls `amount` from `payer` into the marketplace account with ICRC-2
+// icrc2_transfer_from. The payer must have approved this canister as spender for
+// at least the amount plus the ledger fee. Returns the ledger block index.
+pub async fn collect_payment(
+    currency: &str,
+    payer: Principal,
+    amount: u64,
+    reference: &str,
+) -> Result<Nat> {
+    let ledger = ledger_for_currency(currency)?;
+
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: principal_account(payer),
+        to: marketplace_account(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: payment_memo(reference),
+        created_at_time: Some(time()),
+    };
+
+    let result = icrc2_transfer_from(ledger, args)
+        .await
+        .map_err(|(code, message)| {
+            ic_cdk::println!("icrc2_transfer_from on {} failed: {:?} {}", ledger, code, message);
+            IPMarketplaceError::OperationFailed
+        })?;
+
+    result.map_err(|e| {
+        ic_cdk::println!("icrc2_transfer_from on {} rejected: {:?}", ledger, e);
+        match e {
+            TransferFromError::InsufficientFunds { .. } |
+            TransferFromError::InsufficientAllowance { .. } => IPMarketplaceError::InsufficientFunds,
+            _ => IPMarketplaceError::OperationFailed,
+        }
+    })
+}
+
+// Checks that collect_payment could take `amount` from `payer` up to `until`: the
+// payer has approved this canister for the amount plus the ledger fee, without
+// the approval expiring first, and holds that much
+pub async fn ensure_can_pay(currency: &str, payer: Principal, amount: u64, until: u64) -> Result<()> {
+    let ledger = ledger_for_currency(currency)?;
+    let fee = currency_fee(currency).await?;
+    let required = Nat::from(amount) + Nat::from(fee);
+
+    let args = AllowanceArgs {
+        account: principal_account(payer),
+        spender: marketplace_account(),
+    };
+    let allowance = icrc2_allowance(ledger, args)
+        .await
+        .map_err(|(code, message)| {
+            ic_cdk::println!("icrc2_allowance on {} failed: {:?} {}", ledger, code, message);
+            IPMarketplaceError::OperationFailed
+        })?;
+    if allowance.allowance < required || allowance.expires_at.is_some_and(|expires_at| expires_at <= until) {
+        return Err(IPMarketplaceError::InsufficientFunds);
+    }
+
+    let balance = icrc1_balance_of(ledger, principal_account(payer))
+        .await
+        .map_err(|(code, message)| {
+            ic_cdk::println!("icrc1_balance_of on {} failed: {:?} {}", ledger, code, message);
+            IPMarketplaceError::OperationFailed
+        })?;
+    if balance < required {
+        return Err(IPMarketplaceError::InsufficientFunds);
+    }
+    Ok(())
+}
+
+// Payouts
+// Queues a payout of `amount` less `fee`, the ledger fee, which stays fixed so
+// that every attempt sends the same transfer
+pub fn queue_payout(
+    kind: PayoutKind,
+    recipient: Principal,
+    listing: &MarketplaceListing,
+    amount: u64,
+    fee: u64,
+    now: u64,
+) -> Option<String> {
+    if amount == 0 {
+        return None;
+    }
+
+    let payout = PendingPayout {
+        id: generate_id("PAYOUT"),
+        kind,
+        recipient,
+        currency: listing.currency.clone(),
+        amount,
+        fee: Some(fee),
+        listing_id: listing.id.clone(),
+        nft_id: listing.nft_id.clone(),
+        created_at: now,
+        attempts: 0,
+        last_error: None,
+        stalled_at: None,
+    };
+
+    let payout_id = payout.id.clone();
+    with_pending_payouts_mut(|payouts| {
+        payouts.insert(payout_id.clone(), payout);
+    });
+    Some(payout_id)
+}
+
+pub async fn ledger_fee(ledger: Principal) -> std::result::Result<u64, String> {
+    let fee = icrc1_fee(ledger)
+        .await
+        .map_err(|(code, message)| format!("icrc1_fee failed: {:?} {}", code, message))?;
+    u64::try_from(fee.0).map_err(|_| "ledger fee does not fit in u64".to_string())
+}
+
+pub async fn currency_fee(currency: &str) -> Result<u64> {
+    let ledger = ledger_for_currency(currency)?;
+    ledger_fee(ledger).await.map_err(|message| {
+        ic_cdk::println!("{}", message);
+        IPMarketplaceError::OperationFailed
+    })
+}
+
+// Sends one pending payout with icrc1_transfer, net of the ledger fee. Sent and
+// absorbed payouts are removed; failed ones stay queued with the error for the
+// next retry, which repeats the same transfer so the ledger can deduplicate it.
+// Past the ledger's deduplication window an earlier attempt can no longer be
+// ruled out, so the payout stalls until an admin resolves it.
+pub async fn send_payout(payout_id: String) -> Result<()> {
+    let _lock = CallLock::acquire(&payout_id).ok_or(IPMarketplaceError::OperationFailed)?;
+
+    let mut payout = with_pending_payouts(|payouts| {
+        payouts.get(&payout_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
+    if payout.stalled_at.is_some() {
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+
+    // Payouts queued before the fee was kept fix it before their next attempt
+    if payout.fee.is_none() {
+        payout.fee = Some(currency_fee(&payout.currency).await?);
+        with_pending_payouts_mut(|payouts| {
+            payouts.insert(payout_id.clone(), payout.clone());
+        });
+    }
+
+    match transfer_payout(&payout).await {
+        Ok(sent) => {
+            with_pending_payouts_mut(|payouts| {
+                payouts.remove(&payout_id);
+            });
+            record_payout_sent(&payout, sent);
+            Ok(())
+        }
+        Err(failure) => {
+            let (message, expired) = match failure {
+                PayoutFailure::Expired => ("created_at_time outside the ledger's window".to_string(), true),
+                PayoutFailure::Failed(message) => (message, false),
+            };
+            ic_cdk::println!("Payout {} failed: {}", payout_id, message);
+            with_pending_payouts_mut(|payouts| {
+                if let Some(mut pending) = payouts.get(&payout_id) {
+                    pending.attempts += 1;
+                    pending.last_error = Some(message);
+                    if expired {
+                        pending.stalled_at = Some(time());
+                    }
+                    payouts.insert(payout_id.clone(), pending);
+                }
+            });
+            Err(IPMarketplaceError::OperationFailed)
+        }
+    }
+}
+
+fn record_payout_sent(payout: &PendingPayout, sent: PayoutSent) {
+    match sent {
+        PayoutSent::Transferred { net_amount } if payout.kind == PayoutKind::Royalty => {
+            record_royalty_paid(payout.recipient, &payout.currency, net_amount);
+        }
+        PayoutSent::Transferred { .. } => {}
+        // The royalty stays accrued but is never paid
+        PayoutSent::Absorbed => {
+            ic_cdk::println!("Payout {} of {} is below the ledger fee and was absorbed", payout.id, payout.amount);
+        }
+    }
+}
+
+enum PayoutSent {
+    Transferred { net_amount: u64 },
+    // Too small to cover the ledger fee, so kept by the marketplace
+    Absorbed,
+}
+
+impl PayoutSent {
+    fn of(payout: &PendingPayout) -> Self {
+        let fee = payout.fee.unwrap_or(0);
+        // Amounts that cannot cover the ledger fee are absorbed by the marketplace
+        if payout.amount <= fee {
+            PayoutSent::Absorbed
+        } else {
+            PayoutSent::Transferred { net_amount: payout.amount - fee }
+        }
+    }
+}
+
+enum PayoutFailure {
+    Expired,
+    Failed(String),
+}
+
+async fn transfer_payout(payout: &PendingPayout) -> std::result::Result<PayoutSent, PayoutFailure> {
+    let ledger = ledger_for_currency(&payout.currency)
+        .map_err(|_| PayoutFailure::Failed(format!("no ledger configured for {}", payout.currency)))?;
+
+    let sent = PayoutSent::of(payout);
+    let PayoutSent::Transferred { net_amount } = sent else {
+        return Ok(sent);
+    };
+
+    let args = TransferArg {
+        from_subaccount: None,
+        to: principal_account(payout.recipient),
+        amount: Nat::from(net_amount),
+        fee: payout.fee.map(Nat::from),
+        memo: payment_memo(&payout.id),
+        created_at_time: Some(payout.created_at),
+    };
+
+    let result = icrc1_transfer(ledger, args)
+        .await
+        .map_err(|(code, message)| {
+            PayoutFailure::Failed(format!("icrc1_transfer failed: {:?} {}", code, message))
+        })?;
+
+    match result {
+        Ok(_) => Ok(sent),
+        // An earlier attempt already went through
+        Err(TransferError::Duplicate { .. }) => Ok(sent),
+        Err(TransferError::TooOld) => Err(PayoutFailure::Expired),
+        Err(e) => Err(PayoutFailure::Failed(format!("{:?}", e))),
+    }
+}
+
+// Payouts retried per sweep, to keep each timer callback within instruction limits
+const PAYOUT_RETRY_BATCH_SIZE: usize = 50;
+
+// How often unpaid payouts are retried
+pub const PAYOUT_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+// Retries queued payouts; run periodically from a timer
+pub fn retry_pending_payouts() {
+    let pending: Vec<String> = with_pending_payouts(|payouts| {
+        payouts
+            .iter()
+            .filter(|(id, payout)| payout.stalled_at.is_none() && !is_locked(id))
+            .map(|(id, _)| id)
+            .take(PAYOUT_RETRY_BATCH_SIZE)
+            .collect()
+    });
+
+    for payout_id in pending {
+        ic_cdk::spawn(async move {
+            let _ = send_payout(payout_id).await;
+        });
+    }
+}
+
+pub fn start_payout_retrier() {
+    ic_cdk_timers::set_timer_interval(PAYOUT_RETRY_INTERVAL, retry_pending_payouts);
+}
+
+#[query]
+pub fn get_pending_payouts(recipient: Option<Principal>) -> Vec<PendingPayout> {
+    with_pending_payouts(|payouts| {
+        payouts
+            .iter()
+            .filter(|(_, payout)| recipient.is_none_or(|r| payout.recipient == r))
+            .map(|(_, payout)| payout)
+            .collect()
+    })
+}
+
+// Closes a stalled payout once an admin has checked the ledger: `executed`
+// records it as sent, otherwise it is queued again under a fresh timestamp
+#[update]
+pub fn resolve_pending_payout(payout_id: String, executed: bool) -> Result<PendingPayout> {
+    require_admin()?;
+    resolve_payout(payout_id, executed)
+}
+
+fn resolve_payout(payout_id: String, executed: bool) -> Result<PendingPayout> {
+    let payout = with_pending_payouts(|payouts| payouts.get(&payout_id))
+        .ok_or(IPMarketplaceError::NotFound)?;
+    if is_locked(&payout_id) {
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+
+    with_pending_payouts_mut(|payouts| {
+        if executed {
+            payouts.remove(&payout_id);
+        } else {
+            let mut requeued = payout.clone();
+            requeued.created_at = time();
+            requeued.attempts = 0;
+            requeued.last_error = None;
+            requeued.stalled_at = None;
+            payouts.insert(payout_id.clone(), requeued);
+        }
+    });
+    if executed {
+        record_payout_sent(&payout, PayoutSent::of(&payout));
+    }
+    Ok(payout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::mock as ledger;
+    use crate::royalties::{get_creator_royalties, record_royalty_accrued};
+    use crate::runtime::mock::{set_time, START_TIME};
+
+    const CURRENCY: &str = "TEST";
+
+    fn recipient() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn queue_test_payout(kind: PayoutKind, amount: u64) -> String {
+        ledger::configure_currency(CURRENCY);
+        let listing = MarketplaceListing {
+            id: "LISTING_1".to_string(),
+            nft_id: "NFT_1".to_string(),
+            seller: recipient(),
+            price: amount,
+            currency: CURRENCY.to_string(),
+            listed_at: 0,
+            expires_at: None,
+            status: ListingStatus::Sold,
+            license_terms: None,
+            auction_data: None,
+        };
+        queue_payout(kind, recipient(), &listing, amount, ledger::DEFAULT_FEE, time()).unwrap()
+    }
+
+    fn pending(payout_id: &str) -> Option<PendingPayout> {
+        with_pending_payouts(|payouts| payouts.get(&payout_id.to_string()))
+    }
+
+    #[tokio::test]
+    async fn collect_payment_returns_ledger_block() {
+        ledger::configure_currency(CURRENCY);
+
+        let block = collect_payment(CURRENCY, recipient(), 500, "LISTING_1").await.unwrap();
+
+        assert_eq!(block, Nat::from(1u64));
+        let payment = &ledger::transfers_from()[0];
+        assert_eq!(payment.to, marketplace_account());
+        assert_eq!(payment.memo.as_deref(), Some("LISTING_1".as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn collect_payment_maps_ledger_errors() {
+        ledger::configure_currency(CURRENCY);
+        ledger::push_transfer_from_result(Ok(Err(TransferFromError::InsufficientFunds { balance: Nat::from(0u64) })));
+        ledger::push_transfer_from_result(Ok(Err(TransferFromError::TemporarilyUnavailable)));
+        ledger::push_transfer_from_result(Err(ledger::reject()));
+
+        let results = [
+            collect_payment(CURRENCY, recipient(), 500, "L").await,
+            collect_payment(CURRENCY, recipient(), 500, "L").await,
+            collect_payment(CURRENCY, recipient(), 500, "L").await,
+        ];
+
+        assert!(matches!(results[0], Err(IPMarketplaceError::InsufficientFunds)));
+        assert!(matches!(results[1], Err(IPMarketplaceError::OperationFailed)));
+        assert!(matches!(results[2], Err(IPMarketplaceError::OperationFailed)));
+        assert!(matches!(
+            collect_payment("UNKNOWN", recipient(), 500, "L").await,
+            Err(IPMarketplaceError::InvalidInput)
+        ));
+    }
+
+    #[tokio::test]
+    async fn duplicate_payout_counts_as_sent() {
+        let payout_id = queue_test_payout(PayoutKind::SellerProceeds, 1_000);
+        ledger::push_transfer_result(Ok(Err(TransferError::Duplicate { duplicate_of: Nat::from(7u64) })));
+
+        assert!(send_payout(payout_id.clone()).await.is_ok());
+        assert!(pending(&payout_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_payout_stalls_until_resolved() {
+        let payout_id = queue_test_payout(PayoutKind::SellerProceeds, 1_000);
+        ledger::push_transfer_result(Ok(Err(TransferError::TooOld)));
+        set_time(START_TIME + 1);
+
+        assert!(send_payout(payout_id.clone()).await.is_err());
+        let payout = pending(&payout_id).unwrap();
+        assert_eq!(payout.attempts, 1);
+        assert_eq!(payout.created_at, START_TIME);
+        assert_eq!(payout.stalled_at, Some(START_TIME + 1));
+
+        // Not retried while it may already have been paid
+        assert!(send_payout(payout_id.clone()).await.is_err());
+        assert_eq!(ledger::transfers().len(), 1);
+
+        resolve_payout(payout_id.clone(), false).unwrap();
+        assert!(send_payout(payout_id.clone()).await.is_ok());
+        assert_eq!(ledger::transfers()[1].created_at_time, Some(START_TIME + 1));
+        assert!(pending(&payout_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn resolving_a_royalty_as_executed_records_it_paid() {
+        let payout_id = queue_test_payout(PayoutKind::Royalty, 1_000);
+        record_royalty_accrued(recipient(), CURRENCY, 1_000);
+        ledger::push_transfer_result(Ok(Err(TransferError::TooOld)));
+        assert!(send_payout(payout_id.clone()).await.is_err());
+
+        resolve_payout(payout_id.clone(), true).unwrap();
+
+        assert!(pending(&payout_id).is_none());
+        let balance = &get_creator_royalties(recipient()).unwrap().balances[0];
+        assert_eq!(balance.paid, 1_000 - ledger::DEFAULT_FEE);
+    }
+
+    #[tokio::test]
+    async fn retry_sends_the_same_transfer_after_a_fee_change() {
+        let payout_id = queue_test_payout(PayoutKind::SellerProceeds, 1_000);
+        ledger::push_transfer_result(Err(ledger::reject()));
+        assert!(send_payout(payout_id.clone()).await.is_err());
+
+        ledger::set_fee(ledger::DEFAULT_FEE * 2);
+        set_time(START_TIME + 1);
+        assert!(send_payout(payout_id.clone()).await.is_ok());
+
+        let transfers = ledger::transfers();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].amount, transfers[1].amount);
+        assert_eq!(transfers[0].fee, transfers[1].fee);
+        assert_eq!(transfers[0].created_at_time, transfers[1].created_at_time);
+    }
+
+    #[tokio::test]
+    async fn failed_payout_stays_queued_with_its_timestamp() {
+        let payout_id = queue_test_payout(PayoutKind::SellerProceeds, 1_000);
+        ledger::push_transfer_result(Err(ledger::reject()));
+        set_time(START_TIME + 1);
+
+        assert!(send_payout(payout_id.clone()).await.is_err());
+        let payout = pending(&payout_id).unwrap();
+        assert_eq!(payout.attempts, 1);
+        assert_eq!(payout.created_at, START_TIME);
+        assert!(payout.last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn royalty_is_recorded_as_paid_net_of_the_ledger_fee() {
+        let payout_id = queue_test_payout(PayoutKind::Royalty, 1_000);
+        record_royalty_accrued(recipient(), CURRENCY, 1_000);
+
+        assert!(send_payout(payout_id).await.is_ok());
+
+        let balance = &get_creator_royalties(recipient()).unwrap().balances[0];
+        assert_eq!(balance.accrued, 1_000);
+        assert_eq!(balance.paid, 1_000 - ledger::DEFAULT_FEE);
+    }
+
+    #[tokio::test]
+    async fn royalty_below_the_ledger_fee_is_absorbed_not_paid() {
+        let payout_id = queue_test_payout(PayoutKind::Royalty, ledger::DEFAULT_FEE);
+        record_royalty_accrued(recipient(), CURRENCY, ledger::DEFAULT_FEE);
+
+        assert!(send_payout(payout_id.clone()).await.is_ok());
+
+        assert!(pending(&payout_id).is_none());
+        assert!(ledger::transfers().is_empty());
+        let balance = &get_creator_royalties(recipient()).unwrap().balances[0];
+        assert_eq!(balance.paid, 0);
+    }
+}
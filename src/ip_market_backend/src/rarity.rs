@@ -0,0 +1,304 @@
+use ic_cdk::query;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::types::*;
+use crate::storage::*;
+
+// Statistical rarity within a collection of N NFTs. An NFT scores the sum, over
+// every trait type in the collection, of N divided by the number of NFTs that
+// share its value; NFTs without the trait share a "missing" value. How many
+// traits an NFT has counts as one more trait, so unusually bare or busy NFTs
+// score higher. Rank 1 is the rarest.
+//
+// Minting and burning only update the tallies and mark the collection for a
+// refresh. Every mint shifts the scores of the whole collection, so a timer
+// reranks marked collections and writes the new scores and ranks a bounded
+// batch at a time; until then an NFT keeps its previous rank, or none.
+
+// NFTs rewritten per refresh tick
+const RARITY_REFRESH_BATCH_SIZE: usize = 500;
+
+// How often marked collections are reranked
+pub const RARITY_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+fn value_key(value: &AttributeValue) -> String {
+    match value {
+        AttributeValue::Text(t) => t.clone(),
+        AttributeValue::Number(n) => n.to_string(),
+        AttributeValue::Boolean(b) => b.to_string(),
+    }
+}
+
+// trait_type -> value; a repeated trait type keeps its first value
+fn trait_values(attributes: &[NFTAttribute]) -> BTreeMap<String, String> {
+    let mut values = BTreeMap::new();
+    for attribute in attributes {
+        values
+            .entry(attribute.trait_type.clone())
+            .or_insert_with(|| value_key(&attribute.value));
+    }
+    values
+}
+
+fn adjust(counter: &mut u32, add: bool) {
+    *counter = if add { *counter + 1 } else { counter.saturating_sub(1) };
+}
+
+fn tally(traits: &mut CollectionTraits, values: &BTreeMap<String, String>, add: bool) {
+    adjust(&mut traits.size, add);
+    adjust(traits.trait_counts.entry(values.len() as u32).or_insert(0), add);
+    for (trait_type, value) in values {
+        let by_value = traits.values.entry(trait_type.clone()).or_default();
+        adjust(by_value.entry(value.clone()).or_insert(0), add);
+    }
+
+    // Drop emptied tallies so removed values stop counting as trait types
+    if !add {
+        traits.trait_counts.retain(|_, count| *count > 0);
+        for by_value in traits.values.values_mut() {
+            by_value.retain(|_, count| *count > 0);
+        }
+        traits.values.retain(|_, by_value| !by_value.is_empty());
+    }
+}
+
+fn mark_for_refresh(collection_name: &str) {
+    with_rarity_refresh_mut(|refresh| refresh.dirty.insert(collection_name.to_string()));
+}
+
+// Counts new NFTs sharing these attributes in the collection
+pub fn add_to_rarity(collection_name: &str, nfts: &[IPNft], attributes: &[NFTAttribute]) {
+    let values = trait_values(attributes);
+    with_trait_counts_mut(|counts| {
+        let traits = counts.entry(collection_name.to_string()).or_default();
+        for nft in nfts {
+            let member = (nft.token_id, values.clone());
+            if traits.members.insert(nft.id.clone(), member).is_none() {
+                tally(traits, &values, true);
+            }
+        }
+    });
+    mark_for_refresh(collection_name);
+}
+
+pub fn remove_from_rarity(collection_name: &str, nft_id: &str) {
+    with_trait_counts_mut(|counts| {
+        let Some(traits) = counts.get_mut(collection_name) else {
+            return;
+        };
+        if let Some((_, values)) = traits.members.remove(nft_id) {
+            tally(traits, &values, false);
+        }
+        if traits.size == 0 {
+            counts.remove(collection_name);
+        }
+    });
+    mark_for_refresh(collection_name);
+}
+
+// `missing` holds, per trait type, how many NFTs lack it
+fn score(traits: &CollectionTraits, missing: &BTreeMap<&String, u32>, values: &BTreeMap<String, String>) -> f64 {
+    let size = traits.size as f64;
+
+    let mut score = 0.0;
+    for (trait_type, by_value) in &traits.values {
+        let sharing = match values.get(trait_type) {
+            Some(value) => by_value.get(value).copied().unwrap_or(0),
+            None => missing.get(trait_type).copied().unwrap_or(0),
+        };
+        score += size / sharing.max(1) as f64;
+    }
+    let sharing = traits.trait_counts.get(&(values.len() as u32)).copied().unwrap_or(0);
+    score + size / sharing.max(1) as f64
+}
+
+// Scores every NFT in the collection from the tallies, rarest first
+fn rank_collection(collection_name: &str) -> Vec<RarityWrite> {
+    with_trait_counts(|counts| {
+        let Some(traits) = counts.get(collection_name) else {
+            return Vec::new();
+        };
+        let missing: BTreeMap<&String, u32> = traits
+            .values
+            .iter()
+            .map(|(trait_type, by_value)| (trait_type, traits.size - by_value.values().sum::<u32>()))
+            .collect();
+
+        let mut scored: Vec<(f64, u64, &String)> = traits
+            .members
+            .iter()
+            .map(|(nft_id, (token_id, values))| (score(traits, &missing, values), *token_id, nft_id))
+            .collect();
+        // Ties go to the earlier token
+        scored.sort_by(|(a_score, a_token, _), (b_score, b_token, _)| {
+            b_score.total_cmp(a_score).then(a_token.cmp(b_token))
+        });
+
+        scored
+            .into_iter()
+            .enumerate()
+            .map(|(index, (score, _, nft_id))| RarityWrite {
+                nft_id: nft_id.clone(),
+                rank: index as u32 + 1,
+                score,
+            })
+            .collect()
+    })
+}
+
+fn write_rarity(write: RarityWrite) {
+    // Burned since the collection was ranked
+    let Some(mut nft) = with_nft_registry(|registry| registry.get(&write.nft_id)) else {
+        return;
+    };
+    if nft.rarity_rank != Some(write.rank) || nft.rarity_score != Some(write.score) {
+        nft.rarity_rank = Some(write.rank);
+        nft.rarity_score = Some(write.score);
+        insert_nft(nft);
+    }
+}
+
+// Writes up to a batch of new ranks, reranking the next marked collection once
+// the previous one is written; run periodically from a timer
+pub fn refresh_rarity() {
+    for _ in 0..RARITY_REFRESH_BATCH_SIZE {
+        let next = with_rarity_refresh_mut(|refresh| {
+            while refresh.writes.is_empty() {
+                let Some(collection_name) = refresh.dirty.pop_first() else {
+                    break;
+                };
+                refresh.writes.extend(rank_collection(&collection_name));
+            }
+            refresh.writes.pop_front()
+        });
+        let Some(write) = next else {
+            break;
+        };
+        write_rarity(write);
+    }
+}
+
+pub fn start_rarity_refresher() {
+    ic_cdk_timers::set_timer_interval(RARITY_REFRESH_INTERVAL, refresh_rarity);
+}
+
+// Called from post_upgrade: rebuilds the tallies and marks every collection for
+// a refresh
+pub fn rebuild_rarity() {
+    with_trait_counts_mut(|counts| counts.clear());
+    with_rarity_refresh_mut(|refresh| *refresh = RarityRefresh::default());
+
+    with_nft_registry(|registry| {
+        for (nft_id, nft) in registry.iter() {
+            let Some(ref collection_name) = nft.collection_name else {
+                continue;
+            };
+            let attributes = with_nft_metadata(|registry| registry.get(&nft_id))
+                .map(|metadata| metadata.attributes)
+                .unwrap_or_default();
+            add_to_rarity(collection_name, std::slice::from_ref(&nft), &attributes);
+        }
+    });
+}
+
+#[query]
+pub fn get_collection_traits(collection_name: String) -> Result<CollectionTraitReport> {
+    with_trait_counts(|counts| {
+        let traits = counts.get(&collection_name).ok_or(IPMarketplaceError::NotFound)?;
+        let size = traits.size as f64;
+
+        Ok(CollectionTraitReport {
+            collection_name: collection_name.clone(),
+            total_supply: traits.size,
+            traits: traits
+                .values
+                .iter()
+                .flat_map(|(trait_type, by_value)| {
+                    by_value.iter().map(move |(value, &count)| TraitFrequency {
+                        trait_type: trait_type.clone(),
+                        value: value.clone(),
+                        count,
+                        frequency: count as f64 / size,
+                    })
+                })
+                .collect(),
+            trait_counts: traits
+                .trait_counts
+                .iter()
+                .map(|(&trait_count, &count)| TraitCountFrequency {
+                    trait_count,
+                    count,
+                    frequency: count as f64 / size,
+                })
+                .collect(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::mint_test_nft;
+    use candid::Principal;
+
+    const COLLECTION: &str = "Test Collection";
+
+    fn color(value: &str) -> Vec<NFTAttribute> {
+        vec![NFTAttribute {
+            trait_type: "Color".to_string(),
+            value: AttributeValue::Text(value.to_string()),
+            display_type: None,
+            max_value: None,
+        }]
+    }
+
+    fn mint_colored(nft_id: &str, token_id: u64, value: &str) {
+        let owner = Principal::from_slice(&[1]);
+        let mut nft = mint_test_nft(nft_id, token_id, owner, owner);
+        nft.collection_name = Some(COLLECTION.to_string());
+        insert_nft(nft.clone());
+        add_to_rarity(COLLECTION, &[nft], &color(value));
+    }
+
+    fn rank(nft_id: &str) -> Option<u32> {
+        with_nft_registry(|registry| registry.get(&nft_id.to_string())).and_then(|nft| nft.rarity_rank)
+    }
+
+    #[test]
+    fn mints_are_ranked_by_the_refresh() {
+        mint_colored("NFT_1", 1, "Red");
+        mint_colored("NFT_2", 2, "Red");
+        mint_colored("NFT_3", 3, "Gold");
+        assert_eq!(rank("NFT_3"), None);
+
+        refresh_rarity();
+        assert_eq!(rank("NFT_3"), Some(1));
+        assert_eq!(rank("NFT_1"), Some(2));
+        assert_eq!(rank("NFT_2"), Some(3));
+
+        remove_nft("NFT_3");
+        remove_from_rarity(COLLECTION, "NFT_3");
+        mint_colored("NFT_4", 4, "Blue");
+        refresh_rarity();
+        assert_eq!(rank("NFT_4"), Some(1));
+        assert_eq!(with_trait_counts(|counts| counts[COLLECTION].size), 3);
+    }
+
+    #[test]
+    fn refresh_writes_a_bounded_batch() {
+        let total = RARITY_REFRESH_BATCH_SIZE as u64 + 10;
+        for token_id in 1..=total {
+            mint_colored(&format!("NFT_{}", token_id), token_id, "Red");
+        }
+
+        refresh_rarity();
+        let ranked = with_nft_registry(|registry| {
+            registry.iter().filter(|(_, nft)| nft.rarity_rank.is_some()).count()
+        });
+        assert_eq!(ranked, RARITY_REFRESH_BATCH_SIZE);
+
+        refresh_rarity();
+        assert_eq!(rank(&format!("NFT_{}", total)), Some(total as u32));
+    }
+}
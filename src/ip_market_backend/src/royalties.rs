@@ -0,0 +1,105 @@
+use ic_cdk::{query, update};
+use candid::Principal;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::access_control::require_admin;
+
+// Creators earn no royalty on their own sales
+pub fn royalty_amount(price: u64, royalty_percentage: u8, seller: Principal, creator: Principal) -> u64 {
+    if seller == creator {
+        return 0;
+    }
+    (price as u128 * royalty_percentage as u128 / 100) as u64
+}
+
+// Royalty applied when a mint request does not specify one
+pub const DEFAULT_ROYALTY_PERCENTAGE: u8 = 10;
+
+// Checks a requested royalty against the configured cap; the default is clamped
+// to the cap rather than rejected
+pub fn resolve_royalty_percentage(requested: Option<u8>) -> Result<u8> {
+    let cap = with_marketplace_config(|config| config.max_royalty_percentage);
+    match requested {
+        Some(royalty_percentage) if royalty_percentage > cap => Err(IPMarketplaceError::InvalidInput),
+        Some(royalty_percentage) => Ok(royalty_percentage),
+        None => Ok(DEFAULT_ROYALTY_PERCENTAGE.min(cap)),
+    }
+}
+
+fn with_creator_balance(creator: Principal, currency: &str, f: impl FnOnce(&mut RoyaltyBalance, &mut RoyaltyLedger)) {
+    with_royalty_ledgers_mut(|ledgers| {
+        let mut ledger = ledgers.get(&creator).unwrap_or(RoyaltyLedger {
+            creator,
+            balances: Vec::new(),
+            sales_count: 0,
+            updated_at: 0,
+        });
+
+        let index = match ledger.balances.iter().position(|b| b.currency == currency) {
+            Some(index) => index,
+            None => {
+                ledger.balances.push(RoyaltyBalance {
+                    currency: currency.to_string(),
+                    accrued: 0,
+                    paid: 0,
+                });
+                ledger.balances.len() - 1
+            }
+        };
+
+        let mut balance = ledger.balances[index].clone();
+        f(&mut balance, &mut ledger);
+        ledger.balances[index] = balance;
+        ledger.updated_at = time();
+        ledgers.insert(creator, ledger);
+    });
+}
+
+// Called when a sale settles and the royalty is queued for payout
+pub fn record_royalty_accrued(creator: Principal, currency: &str, amount: u64) {
+    with_creator_balance(creator, currency, |balance, ledger| {
+        balance.accrued += amount;
+        ledger.sales_count += 1;
+    });
+}
+
+// Called once the royalty payout has reached the creator's account, with the
+// amount received after the ledger fee
+pub fn record_royalty_paid(creator: Principal, currency: &str, amount: u64) {
+    with_creator_balance(creator, currency, |balance, _| {
+        balance.paid += amount;
+    });
+}
+
+#[query]
+pub fn get_creator_royalties(creator: Principal) -> Result<RoyaltyLedger> {
+    with_royalty_ledgers(|ledgers| {
+        ledgers.get(&creator)
+    }).ok_or(IPMarketplaceError::NotFound)
+}
+
+#[query]
+pub fn get_my_royalties() -> Result<RoyaltyLedger> {
+    get_creator_royalties(ic_cdk::caller())
+}
+
+#[update]
+pub fn set_max_royalty_percentage(max_royalty_percentage: u8) -> Result<MarketplaceConfig> {
+    require_admin()?;
+
+    if max_royalty_percentage > 100 {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    Ok(with_marketplace_config_mut(|config| {
+        config.max_royalty_percentage = max_royalty_percentage;
+        config.clone()
+    }))
+}
+
+#[query]
+pub fn get_marketplace_config() -> MarketplaceConfig {
+    with_marketplace_config(|config| config.clone())
+}
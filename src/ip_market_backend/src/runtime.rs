@@ -0,0 +1,41 @@
+// System API the canister logic reads its clock and own ID from and writes its
+// certified data through. Unit tests run outside a canister, where these calls
+// panic, and get a settable clock instead.
+
+#[cfg(not(test))]
+pub use ic_cdk::api::{set_certified_data, time};
+
+#[cfg(not(test))]
+pub fn canister_id() -> candid::Principal {
+    ic_cdk::id()
+}
+
+#[cfg(test)]
+pub use mock::*;
+
+#[cfg(test)]
+pub mod mock {
+    use candid::Principal;
+    use std::cell::Cell;
+
+    // Test clock start, in nanoseconds since the epoch
+    pub const START_TIME: u64 = 1_700_000_000_000_000_000;
+
+    thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(START_TIME) };
+    }
+
+    pub fn time() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    pub fn set_time(time: u64) {
+        NOW.with(|now| now.set(time));
+    }
+
+    pub fn canister_id() -> candid::Principal {
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1])
+    }
+
+    pub fn set_certified_data(_data: &[u8]) {}
+}
@@ -1,48 +1,433 @@
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable};
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use ic_certification::{Hash, RbTree};
 use candid::Principal;
 
 use crate::types::*;
+use crate::migrations::split_envelope;
 
 // Memory management
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// Each stable structure owns one virtual memory; never reuse or renumber these
+const IP_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(0);
+const NFT_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(1);
+const USER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(2);
+const MARKETPLACE_MEMORY_ID: MemoryId = MemoryId::new(3);
+const NFT_METADATA_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ID_COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const CURRENCY_LEDGERS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const PENDING_PAYOUTS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const ROYALTY_LEDGERS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const MARKETPLACE_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(10);
+const TREASURY_MEMORY_ID: MemoryId = MemoryId::new(11);
+const FEE_CHANGES_MEMORY_ID: MemoryId = MemoryId::new(12);
+const TOKEN_INDEX_MEMORY_ID: MemoryId = MemoryId::new(13);
+const TOKEN_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const COLLECTION_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const BLOCK_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(16);
+const BLOCK_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(17);
+const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const ASSET_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(19);
+const STORAGE_USAGE_MEMORY_ID: MemoryId = MemoryId::new(20);
+const BATCH_MINTS_MEMORY_ID: MemoryId = MemoryId::new(21);
+const COLLECTIONS_MEMORY_ID: MemoryId = MemoryId::new(22);
+const COLLECTION_NAMES_MEMORY_ID: MemoryId = MemoryId::new(23);
+const FAVORITES_MEMORY_ID: MemoryId = MemoryId::new(24);
+const NFT_FAVORITERS_MEMORY_ID: MemoryId = MemoryId::new(25);
+const VIEW_BUCKETS_MEMORY_ID: MemoryId = MemoryId::new(26);
+const LAST_VIEWS_MEMORY_ID: MemoryId = MemoryId::new(27);
+const TRENDING_NFTS_MEMORY_ID: MemoryId = MemoryId::new(28);
+const TRENDING_COLLECTIONS_MEMORY_ID: MemoryId = MemoryId::new(29);
+const OWNER_IPS_MEMORY_ID: MemoryId = MemoryId::new(30);
+const OWNER_NFTS_MEMORY_ID: MemoryId = MemoryId::new(31);
+const COLLECTION_NFTS_MEMORY_ID: MemoryId = MemoryId::new(32);
+const SELLER_LISTINGS_MEMORY_ID: MemoryId = MemoryId::new(33);
+const NFT_LISTINGS_MEMORY_ID: MemoryId = MemoryId::new(34);
+const STATUS_LISTINGS_MEMORY_ID: MemoryId = MemoryId::new(35);
+const IP_TERMS_MEMORY_ID: MemoryId = MemoryId::new(36);
+const IP_DOC_LENGTHS_MEMORY_ID: MemoryId = MemoryId::new(37);
+const NFT_TERMS_MEMORY_ID: MemoryId = MemoryId::new(38);
+const NFT_DOC_LENGTHS_MEMORY_ID: MemoryId = MemoryId::new(39);
+const PENDING_WITHDRAWALS_MEMORY_ID: MemoryId = MemoryId::new(40);
+const RECENT_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(41);
+const TRANSACTION_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(42);
+const BURNED_NFTS_MEMORY_ID: MemoryId = MemoryId::new(43);
+const AUCTION_BIDS_MEMORY_ID: MemoryId = MemoryId::new(44);
+const COLLECTION_SUPPLY_MEMORY_ID: MemoryId = MemoryId::new(45);
+const DATA_MIGRATIONS_MEMORY_ID: MemoryId = MemoryId::new(46);
+const RESERVED_COLLECTION_NAMES_MEMORY_ID: MemoryId = MemoryId::new(47);
+const COUNTED_FAVORITES_MEMORY_ID: MemoryId = MemoryId::new(48);
+const TRENDING_NFT_RANKS_MEMORY_ID: MemoryId = MemoryId::new(49);
+const TRENDING_COLLECTION_RANKS_MEMORY_ID: MemoryId = MemoryId::new(50);
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
     static IP_REGISTRY: RefCell<StableBTreeMap<String, IntellectualProperty, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(IP_REGISTRY_MEMORY_ID)),
         )
     );
 
     static NFT_REGISTRY: RefCell<StableBTreeMap<String, IPNft, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(NFT_REGISTRY_MEMORY_ID)),
         )
     );
 
     static USER_REGISTRY: RefCell<StableBTreeMap<Principal, UserProfile, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(USER_REGISTRY_MEMORY_ID)),
         )
     );
 
     static MARKETPLACE: RefCell<StableBTreeMap<String, MarketplaceListing, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MARKETPLACE_MEMORY_ID)),
         )
     );
 
     static NFT_METADATA: RefCell<StableBTreeMap<String, NFTMetadata, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(NFT_METADATA_MEMORY_ID)),
+        )
+    );
+
+    static ID_COUNTERS: RefCell<StableCell<IdCounters, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ID_COUNTERS_MEMORY_ID)),
+            IdCounters::default(),
+        ).expect("failed to initialize ID counters")
+    );
+
+    static ROLES: RefCell<StableBTreeMap<Principal, RoleAssignments, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ROLES_MEMORY_ID)),
+        )
+    );
+
+    static CURRENCY_LEDGERS: RefCell<StableBTreeMap<String, CurrencyLedger, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CURRENCY_LEDGERS_MEMORY_ID)),
+        )
+    );
+
+    static PENDING_PAYOUTS: RefCell<StableBTreeMap<String, PendingPayout, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_PAYOUTS_MEMORY_ID)),
+        )
+    );
+
+    static ROYALTY_LEDGERS: RefCell<StableBTreeMap<Principal, RoyaltyLedger, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ROYALTY_LEDGERS_MEMORY_ID)),
+        )
+    );
+
+    static MARKETPLACE_CONFIG: RefCell<StableCell<MarketplaceConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MARKETPLACE_CONFIG_MEMORY_ID)),
+            MarketplaceConfig::default(),
+        ).expect("failed to initialize marketplace config")
+    );
+
+    static TREASURY: RefCell<StableBTreeMap<String, TreasuryBalance, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TREASURY_MEMORY_ID)),
+        )
+    );
+
+    static PENDING_WITHDRAWALS: RefCell<StableBTreeMap<String, PendingWithdrawal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_WITHDRAWALS_MEMORY_ID)),
+        )
+    );
+
+    static FEE_CHANGES: RefCell<StableBTreeMap<u64, FeeChangeRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FEE_CHANGES_MEMORY_ID)),
+        )
+    );
+
+    // ICRC-7 token ID -> NFT ID
+    static TOKEN_INDEX: RefCell<StableBTreeMap<u64, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // ICRC-37 approvals, keyed by token ID and by owner
+    static TOKEN_APPROVALS: RefCell<StableBTreeMap<u64, TokenApprovals, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_APPROVALS_MEMORY_ID)),
+        )
+    );
+
+    static COLLECTION_APPROVALS: RefCell<StableBTreeMap<Principal, CollectionApprovals, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COLLECTION_APPROVALS_MEMORY_ID)),
+        )
+    );
+
+    // ICRC-3 blocks, append-only
+    static BLOCK_LOG: RefCell<StableLog<Value, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BLOCK_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(BLOCK_LOG_DATA_MEMORY_ID)),
+        ).expect("failed to initialize the block log")
+    );
+
+    // On-chain assets: records, raw chunk bytes keyed by (asset ID, chunk index),
+    // and per-user quota usage
+    static ASSETS: RefCell<StableBTreeMap<u64, StoredAsset, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ASSETS_MEMORY_ID)),
+        )
+    );
+
+    static ASSET_CHUNKS: RefCell<StableBTreeMap<(u64, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ASSET_CHUNKS_MEMORY_ID)),
+        )
+    );
+
+    static STORAGE_USAGE: RefCell<StableBTreeMap<Principal, StorageUsage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(STORAGE_USAGE_MEMORY_ID)),
+        )
+    );
+
+    // Batch mints, kept after completion so callers can read the results
+    static BATCH_MINTS: RefCell<StableBTreeMap<String, BatchMintJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BATCH_MINTS_MEMORY_ID)),
+        )
+    );
+
+    // Collections by ID, and their IDs by lowercased name
+    static COLLECTIONS: RefCell<StableBTreeMap<String, Collection, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COLLECTIONS_MEMORY_ID)),
+        )
+    );
+
+    static COLLECTION_NAMES: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COLLECTION_NAMES_MEMORY_ID)),
+        )
+    );
+
+    // Favorites as (user, NFT ID) -> favorited_at, and the same set keyed by NFT
+    // for listing an NFT's favoriters
+    static FAVORITES: RefCell<StableBTreeMap<(Principal, IdKey), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FAVORITES_MEMORY_ID)),
+        )
+    );
+
+    static NFT_FAVORITERS: RefCell<StableBTreeMap<(IdKey, Principal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(NFT_FAVORITERS_MEMORY_ID)),
+        )
+    );
+
+    // Counted views per (NFT ID, bucket start), and when each user's view of an
+    // NFT was last counted
+    static VIEW_BUCKETS: RefCell<StableBTreeMap<(IdKey, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(VIEW_BUCKETS_MEMORY_ID)),
+        )
+    );
+
+    static LAST_VIEWS: RefCell<StableBTreeMap<(Principal, IdKey), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(LAST_VIEWS_MEMORY_ID)),
+        )
+    );
+
+    // Decaying trending scores by NFT ID and by collection name
+    static TRENDING_NFTS: RefCell<StableBTreeMap<String, TrendingScore, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRENDING_NFTS_MEMORY_ID)),
+        )
+    );
+
+    static TRENDING_COLLECTIONS: RefCell<StableBTreeMap<String, TrendingScore, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRENDING_COLLECTIONS_MEMORY_ID)),
         )
     );
 
-    static COUNTER: RefCell<u64> = RefCell::new(0);
+    // Secondary indexes over the IP, NFT and listing registries, as
+    // (lookup key, record ID) -> (). They are written only alongside their
+    // records, by insert_ip, insert_nft, remove_nft and insert_listing.
+    static OWNER_IPS: RefCell<StableBTreeMap<(Principal, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(OWNER_IPS_MEMORY_ID)),
+        )
+    );
+
+    static OWNER_NFTS: RefCell<StableBTreeMap<(Principal, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(OWNER_NFTS_MEMORY_ID)),
+        )
+    );
+
+    static COLLECTION_NFTS: RefCell<StableBTreeMap<(IdKey, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COLLECTION_NFTS_MEMORY_ID)),
+        )
+    );
+
+    static SELLER_LISTINGS: RefCell<StableBTreeMap<(Principal, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SELLER_LISTINGS_MEMORY_ID)),
+        )
+    );
+
+    static NFT_LISTINGS: RefCell<StableBTreeMap<(IdKey, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(NFT_LISTINGS_MEMORY_ID)),
+        )
+    );
+
+    static STATUS_LISTINGS: RefCell<StableBTreeMap<(u8, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(STATUS_LISTINGS_MEMORY_ID)),
+        )
+    );
+
+    // Full-text postings as (term, document ID) -> term frequency, and each
+    // document's length in tokens, for IPs and for NFTs
+    static IP_TERMS: RefCell<StableBTreeMap<(IdKey, IdKey), u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(IP_TERMS_MEMORY_ID)),
+        )
+    );
+
+    static IP_DOC_LENGTHS: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(IP_DOC_LENGTHS_MEMORY_ID)),
+        )
+    );
+
+    static NFT_TERMS: RefCell<StableBTreeMap<(IdKey, IdKey), u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(NFT_TERMS_MEMORY_ID)),
+        )
+    );
+
+    static NFT_DOC_LENGTHS: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(NFT_DOC_LENGTHS_MEMORY_ID)),
+        )
+    );
+
+    // ICRC-7/ICRC-37 transaction hash -> block index, for deduplication
+    static RECENT_TRANSACTIONS: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RECENT_TRANSACTIONS_MEMORY_ID)),
+        )
+    );
+
+    // (created_at_time, transaction hash), to expire RECENT_TRANSACTIONS in order
+    static TRANSACTION_EXPIRY: RefCell<StableBTreeMap<(u64, [u8; 32]), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTION_EXPIRY_MEMORY_ID)),
+        )
+    );
+
+    // Burned NFTs as they were at the burn, ending with the transfer to the
+    // anonymous principal
+    static BURNED_NFTS: RefCell<StableBTreeMap<String, IPNft, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BURNED_NFTS_MEMORY_ID)),
+        )
+    );
+
+    // (listing ID, bid amount) -> bid; amounts rise with every bid, so the key
+    // orders an auction's bids
+    static AUCTION_BIDS: RefCell<StableBTreeMap<(IdKey, u64), Bid, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(AUCTION_BIDS_MEMORY_ID)),
+        )
+    );
+
+    // Collection name as stored on NFTs -> live supply and first NFT
+    static COLLECTION_SUPPLY: RefCell<StableBTreeMap<String, CollectionSupply, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COLLECTION_SUPPLY_MEMORY_ID)),
+        )
+    );
+
+    // One-time data migrations already run -> when they completed
+    static DATA_MIGRATIONS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DATA_MIGRATIONS_MEMORY_ID)),
+        )
+    );
+
+    // Collection name keys used by NFTs minted before collections existed, which
+    // no new collection may take
+    static RESERVED_COLLECTION_NAMES: RefCell<StableBTreeMap<String, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RESERVED_COLLECTION_NAMES_MEMORY_ID)),
+        )
+    );
+
+    // (NFT ID, user) pairs whose favorite has counted towards trending, kept
+    // after an unfavorite so favoriting again does not count twice
+    static COUNTED_FAVORITES: RefCell<StableBTreeMap<(IdKey, Principal), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COUNTED_FAVORITES_MEMORY_ID)),
+        )
+    );
+
+    // (rank, key) for every entry of TRENDING_NFTS and TRENDING_COLLECTIONS,
+    // ascending in current trending score
+    static TRENDING_NFT_RANKS: RefCell<StableBTreeMap<(u64, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRENDING_NFT_RANKS_MEMORY_ID)),
+        )
+    );
+
+    static TRENDING_COLLECTION_RANKS: RefCell<StableBTreeMap<(u64, IdKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRENDING_COLLECTION_RANKS_MEMORY_ID)),
+        )
+    );
+
+    // Listings and payouts awaiting a ledger call. Heap-only: upgrades cannot
+    // happen while calls are outstanding.
+    static CALL_LOCKS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+
+    // SHA-256 of every certified HTTP response body, keyed by path. Heap-only:
+    // rebuilt from the registries in post_upgrade.
+    static HTTP_ASSETS: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // Trait tallies per collection name, for rarity scoring. Heap-only: rebuilt
+    // from the registries in post_upgrade.
+    static TRAIT_COUNTS: RefCell<BTreeMap<String, CollectionTraits>> = const { RefCell::new(BTreeMap::new()) };
+
+    // Rank refreshes not yet written. Heap-only: every collection is marked for
+    // refresh when the tallies are rebuilt in post_upgrade.
+    static RARITY_REFRESH: RefCell<RarityRefresh> = RefCell::new(RarityRefresh::default());
+
+    // Scan positions of the view and trending pruners. Heap-only: scans restart
+    // from the beginning after an upgrade.
+    static PRUNE_CURSORS: RefCell<PruneCursors> = RefCell::new(PruneCursors::default());
+
+    // Token totals of the full-text index. Heap-only: recounted from the
+    // document lengths in post_upgrade.
+    static TEXT_INDEX_TOTALS: RefCell<TextIndexTotals> = const {
+        RefCell::new(TextIndexTotals { ip_tokens: 0, nft_tokens: 0 })
+    };
 }
 
 // Storage access functions
@@ -50,7 +435,8 @@ pub fn with_ip_registry<R>(f: impl FnOnce(&StableBTreeMap<String, IntellectualPr
     IP_REGISTRY.with(|registry| f(&registry.borrow()))
 }
 
-pub fn with_ip_registry_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, IntellectualProperty, Memory>) -> R) -> R {
+// Writes go through the index-maintaining functions below
+fn with_ip_registry_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, IntellectualProperty, Memory>) -> R) -> R {
     IP_REGISTRY.with(|registry| f(&mut registry.borrow_mut()))
 }
 
@@ -58,7 +444,8 @@ pub fn with_nft_registry<R>(f: impl FnOnce(&StableBTreeMap<String, IPNft, Memory
     NFT_REGISTRY.with(|registry| f(&registry.borrow()))
 }
 
-pub fn with_nft_registry_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, IPNft, Memory>) -> R) -> R {
+// Writes go through the index-maintaining functions below
+fn with_nft_registry_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, IPNft, Memory>) -> R) -> R {
     NFT_REGISTRY.with(|registry| f(&mut registry.borrow_mut()))
 }
 
@@ -74,7 +461,8 @@ pub fn with_marketplace<R>(f: impl FnOnce(&StableBTreeMap<String, MarketplaceLis
     MARKETPLACE.with(|registry| f(&registry.borrow()))
 }
 
-pub fn with_marketplace_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, MarketplaceListing, Memory>) -> R) -> R {
+// Writes go through the index-maintaining functions below
+fn with_marketplace_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, MarketplaceListing, Memory>) -> R) -> R {
     MARKETPLACE.with(|registry| f(&mut registry.borrow_mut()))
 }
 
@@ -86,19 +474,724 @@ pub fn with_nft_metadata_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, NFTMe
     NFT_METADATA.with(|registry| f(&mut registry.borrow_mut()))
 }
 
-pub fn with_counter<R>(f: impl FnOnce(&u64) -> R) -> R {
-    COUNTER.with(|counter| f(&counter.borrow()))
+pub fn with_roles<R>(f: impl FnOnce(&StableBTreeMap<Principal, RoleAssignments, Memory>) -> R) -> R {
+    ROLES.with(|roles| f(&roles.borrow()))
 }
 
-pub fn with_counter_mut<R>(f: impl FnOnce(&mut u64) -> R) -> R {
-    COUNTER.with(|counter| f(&mut counter.borrow_mut()))
+pub fn with_roles_mut<R>(f: impl FnOnce(&mut StableBTreeMap<Principal, RoleAssignments, Memory>) -> R) -> R {
+    ROLES.with(|roles| f(&mut roles.borrow_mut()))
 }
 
-// Helper function to generate unique IDs
-pub fn generate_id(prefix: &str) -> String {
-    with_counter_mut(|counter| {
+pub fn with_currency_ledgers<R>(f: impl FnOnce(&StableBTreeMap<String, CurrencyLedger, Memory>) -> R) -> R {
+    CURRENCY_LEDGERS.with(|ledgers| f(&ledgers.borrow()))
+}
+
+pub fn with_currency_ledgers_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, CurrencyLedger, Memory>) -> R) -> R {
+    CURRENCY_LEDGERS.with(|ledgers| f(&mut ledgers.borrow_mut()))
+}
+
+pub fn with_pending_payouts<R>(f: impl FnOnce(&StableBTreeMap<String, PendingPayout, Memory>) -> R) -> R {
+    PENDING_PAYOUTS.with(|payouts| f(&payouts.borrow()))
+}
+
+pub fn with_pending_payouts_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, PendingPayout, Memory>) -> R) -> R {
+    PENDING_PAYOUTS.with(|payouts| f(&mut payouts.borrow_mut()))
+}
+
+pub fn with_royalty_ledgers<R>(f: impl FnOnce(&StableBTreeMap<Principal, RoyaltyLedger, Memory>) -> R) -> R {
+    ROYALTY_LEDGERS.with(|ledgers| f(&ledgers.borrow()))
+}
+
+pub fn with_royalty_ledgers_mut<R>(f: impl FnOnce(&mut StableBTreeMap<Principal, RoyaltyLedger, Memory>) -> R) -> R {
+    ROYALTY_LEDGERS.with(|ledgers| f(&mut ledgers.borrow_mut()))
+}
+
+pub fn with_marketplace_config<R>(f: impl FnOnce(&MarketplaceConfig) -> R) -> R {
+    MARKETPLACE_CONFIG.with(|cell| f(cell.borrow().get()))
+}
+
+pub fn with_marketplace_config_mut<R>(f: impl FnOnce(&mut MarketplaceConfig) -> R) -> R {
+    MARKETPLACE_CONFIG.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut config = cell.get().clone();
+        let result = f(&mut config);
+        cell.set(config).expect("failed to persist marketplace config");
+        result
+    })
+}
+
+pub fn with_treasury<R>(f: impl FnOnce(&StableBTreeMap<String, TreasuryBalance, Memory>) -> R) -> R {
+    TREASURY.with(|treasury| f(&treasury.borrow()))
+}
+
+pub fn with_treasury_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, TreasuryBalance, Memory>) -> R) -> R {
+    TREASURY.with(|treasury| f(&mut treasury.borrow_mut()))
+}
+
+pub fn with_pending_withdrawals<R>(f: impl FnOnce(&StableBTreeMap<String, PendingWithdrawal, Memory>) -> R) -> R {
+    PENDING_WITHDRAWALS.with(|withdrawals| f(&withdrawals.borrow()))
+}
+
+pub fn with_pending_withdrawals_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, PendingWithdrawal, Memory>) -> R) -> R {
+    PENDING_WITHDRAWALS.with(|withdrawals| f(&mut withdrawals.borrow_mut()))
+}
+
+pub fn with_fee_changes<R>(f: impl FnOnce(&StableBTreeMap<u64, FeeChangeRecord, Memory>) -> R) -> R {
+    FEE_CHANGES.with(|changes| f(&changes.borrow()))
+}
+
+pub fn with_fee_changes_mut<R>(f: impl FnOnce(&mut StableBTreeMap<u64, FeeChangeRecord, Memory>) -> R) -> R {
+    FEE_CHANGES.with(|changes| f(&mut changes.borrow_mut()))
+}
+
+pub fn with_token_index<R>(f: impl FnOnce(&StableBTreeMap<u64, String, Memory>) -> R) -> R {
+    TOKEN_INDEX.with(|index| f(&index.borrow()))
+}
+
+pub fn with_token_index_mut<R>(f: impl FnOnce(&mut StableBTreeMap<u64, String, Memory>) -> R) -> R {
+    TOKEN_INDEX.with(|index| f(&mut index.borrow_mut()))
+}
+
+pub fn with_token_approvals<R>(f: impl FnOnce(&StableBTreeMap<u64, TokenApprovals, Memory>) -> R) -> R {
+    TOKEN_APPROVALS.with(|approvals| f(&approvals.borrow()))
+}
+
+pub fn with_token_approvals_mut<R>(f: impl FnOnce(&mut StableBTreeMap<u64, TokenApprovals, Memory>) -> R) -> R {
+    TOKEN_APPROVALS.with(|approvals| f(&mut approvals.borrow_mut()))
+}
+
+pub fn with_collection_approvals<R>(f: impl FnOnce(&StableBTreeMap<Principal, CollectionApprovals, Memory>) -> R) -> R {
+    COLLECTION_APPROVALS.with(|approvals| f(&approvals.borrow()))
+}
+
+pub fn with_collection_approvals_mut<R>(f: impl FnOnce(&mut StableBTreeMap<Principal, CollectionApprovals, Memory>) -> R) -> R {
+    COLLECTION_APPROVALS.with(|approvals| f(&mut approvals.borrow_mut()))
+}
+
+pub fn with_block_log<R>(f: impl FnOnce(&StableLog<Value, Memory, Memory>) -> R) -> R {
+    BLOCK_LOG.with(|log| f(&log.borrow()))
+}
+
+pub fn with_assets<R>(f: impl FnOnce(&StableBTreeMap<u64, StoredAsset, Memory>) -> R) -> R {
+    ASSETS.with(|assets| f(&assets.borrow()))
+}
+
+pub fn with_assets_mut<R>(f: impl FnOnce(&mut StableBTreeMap<u64, StoredAsset, Memory>) -> R) -> R {
+    ASSETS.with(|assets| f(&mut assets.borrow_mut()))
+}
+
+pub fn with_asset_chunks<R>(f: impl FnOnce(&StableBTreeMap<(u64, u32), Vec<u8>, Memory>) -> R) -> R {
+    ASSET_CHUNKS.with(|chunks| f(&chunks.borrow()))
+}
+
+pub fn with_asset_chunks_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(u64, u32), Vec<u8>, Memory>) -> R) -> R {
+    ASSET_CHUNKS.with(|chunks| f(&mut chunks.borrow_mut()))
+}
+
+pub fn with_storage_usage<R>(f: impl FnOnce(&StableBTreeMap<Principal, StorageUsage, Memory>) -> R) -> R {
+    STORAGE_USAGE.with(|usage| f(&usage.borrow()))
+}
+
+pub fn with_storage_usage_mut<R>(f: impl FnOnce(&mut StableBTreeMap<Principal, StorageUsage, Memory>) -> R) -> R {
+    STORAGE_USAGE.with(|usage| f(&mut usage.borrow_mut()))
+}
+
+pub fn with_collections<R>(f: impl FnOnce(&StableBTreeMap<String, Collection, Memory>) -> R) -> R {
+    COLLECTIONS.with(|collections| f(&collections.borrow()))
+}
+
+pub fn with_collections_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, Collection, Memory>) -> R) -> R {
+    COLLECTIONS.with(|collections| f(&mut collections.borrow_mut()))
+}
+
+pub fn with_collection_names<R>(f: impl FnOnce(&StableBTreeMap<String, String, Memory>) -> R) -> R {
+    COLLECTION_NAMES.with(|names| f(&names.borrow()))
+}
+
+pub fn with_collection_names_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, String, Memory>) -> R) -> R {
+    COLLECTION_NAMES.with(|names| f(&mut names.borrow_mut()))
+}
+
+pub fn with_favorites<R>(f: impl FnOnce(&StableBTreeMap<(Principal, IdKey), u64, Memory>) -> R) -> R {
+    FAVORITES.with(|favorites| f(&favorites.borrow()))
+}
+
+pub fn with_favorites_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(Principal, IdKey), u64, Memory>) -> R) -> R {
+    FAVORITES.with(|favorites| f(&mut favorites.borrow_mut()))
+}
+
+pub fn with_nft_favoriters<R>(f: impl FnOnce(&StableBTreeMap<(IdKey, Principal), u64, Memory>) -> R) -> R {
+    NFT_FAVORITERS.with(|favoriters| f(&favoriters.borrow()))
+}
+
+pub fn with_nft_favoriters_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(IdKey, Principal), u64, Memory>) -> R) -> R {
+    NFT_FAVORITERS.with(|favoriters| f(&mut favoriters.borrow_mut()))
+}
+
+pub fn with_view_buckets<R>(f: impl FnOnce(&StableBTreeMap<(IdKey, u64), u64, Memory>) -> R) -> R {
+    VIEW_BUCKETS.with(|buckets| f(&buckets.borrow()))
+}
+
+pub fn with_view_buckets_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(IdKey, u64), u64, Memory>) -> R) -> R {
+    VIEW_BUCKETS.with(|buckets| f(&mut buckets.borrow_mut()))
+}
+
+pub fn with_last_views<R>(f: impl FnOnce(&StableBTreeMap<(Principal, IdKey), u64, Memory>) -> R) -> R {
+    LAST_VIEWS.with(|views| f(&views.borrow()))
+}
+
+pub fn with_last_views_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(Principal, IdKey), u64, Memory>) -> R) -> R {
+    LAST_VIEWS.with(|views| f(&mut views.borrow_mut()))
+}
+
+pub fn with_trending_nfts<R>(f: impl FnOnce(&StableBTreeMap<String, TrendingScore, Memory>) -> R) -> R {
+    TRENDING_NFTS.with(|scores| f(&scores.borrow()))
+}
+
+pub fn with_trending_nfts_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, TrendingScore, Memory>) -> R) -> R {
+    TRENDING_NFTS.with(|scores| f(&mut scores.borrow_mut()))
+}
+
+pub fn with_trending_collections<R>(f: impl FnOnce(&StableBTreeMap<String, TrendingScore, Memory>) -> R) -> R {
+    TRENDING_COLLECTIONS.with(|scores| f(&scores.borrow()))
+}
+
+pub fn with_trending_collections_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, TrendingScore, Memory>) -> R) -> R {
+    TRENDING_COLLECTIONS.with(|scores| f(&mut scores.borrow_mut()))
+}
+
+pub fn with_batch_mints<R>(f: impl FnOnce(&StableBTreeMap<String, BatchMintJob, Memory>) -> R) -> R {
+    BATCH_MINTS.with(|jobs| f(&jobs.borrow()))
+}
+
+pub fn with_batch_mints_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, BatchMintJob, Memory>) -> R) -> R {
+    BATCH_MINTS.with(|jobs| f(&mut jobs.borrow_mut()))
+}
+
+pub fn with_http_assets<R>(f: impl FnOnce(&RbTree<String, Hash>) -> R) -> R {
+    HTTP_ASSETS.with(|assets| f(&assets.borrow()))
+}
+
+pub fn with_http_assets_mut<R>(f: impl FnOnce(&mut RbTree<String, Hash>) -> R) -> R {
+    HTTP_ASSETS.with(|assets| f(&mut assets.borrow_mut()))
+}
+
+pub fn with_trait_counts<R>(f: impl FnOnce(&BTreeMap<String, CollectionTraits>) -> R) -> R {
+    TRAIT_COUNTS.with(|counts| f(&counts.borrow()))
+}
+
+pub fn with_trait_counts_mut<R>(f: impl FnOnce(&mut BTreeMap<String, CollectionTraits>) -> R) -> R {
+    TRAIT_COUNTS.with(|counts| f(&mut counts.borrow_mut()))
+}
+
+pub fn with_rarity_refresh_mut<R>(f: impl FnOnce(&mut RarityRefresh) -> R) -> R {
+    RARITY_REFRESH.with(|refresh| f(&mut refresh.borrow_mut()))
+}
+
+pub fn with_prune_cursors_mut<R>(f: impl FnOnce(&mut PruneCursors) -> R) -> R {
+    PRUNE_CURSORS.with(|cursors| f(&mut cursors.borrow_mut()))
+}
+
+// Looks at up to `limit` entries from `cursor` on and returns the keys that
+// match `expired`, with the key the next scan starts from. The scan wraps to the
+// start of the map once it reaches the end.
+pub fn scan_expired<K, V>(
+    map: &StableBTreeMap<K, V, Memory>,
+    cursor: Option<K>,
+    limit: usize,
+    expired: impl Fn(&K, &V) -> bool,
+) -> (Vec<K>, Option<K>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let start = cursor.map_or(Bound::Unbounded, Bound::Included);
+    let mut keys = Vec::new();
+    for (seen, (key, value)) in map.range((start, Bound::Unbounded)).enumerate() {
+        if seen == limit {
+            return (keys, Some(key));
+        }
+        if expired(&key, &value) {
+            keys.push(key);
+        }
+    }
+    (keys, None)
+}
+
+pub fn with_ip_terms<R>(f: impl FnOnce(&StableBTreeMap<(IdKey, IdKey), u32, Memory>) -> R) -> R {
+    IP_TERMS.with(|terms| f(&terms.borrow()))
+}
+
+pub fn with_ip_terms_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(IdKey, IdKey), u32, Memory>) -> R) -> R {
+    IP_TERMS.with(|terms| f(&mut terms.borrow_mut()))
+}
+
+pub fn with_ip_doc_lengths<R>(f: impl FnOnce(&StableBTreeMap<String, u32, Memory>) -> R) -> R {
+    IP_DOC_LENGTHS.with(|lengths| f(&lengths.borrow()))
+}
+
+pub fn with_ip_doc_lengths_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, u32, Memory>) -> R) -> R {
+    IP_DOC_LENGTHS.with(|lengths| f(&mut lengths.borrow_mut()))
+}
+
+pub fn with_nft_terms<R>(f: impl FnOnce(&StableBTreeMap<(IdKey, IdKey), u32, Memory>) -> R) -> R {
+    NFT_TERMS.with(|terms| f(&terms.borrow()))
+}
+
+pub fn with_nft_terms_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(IdKey, IdKey), u32, Memory>) -> R) -> R {
+    NFT_TERMS.with(|terms| f(&mut terms.borrow_mut()))
+}
+
+pub fn with_nft_doc_lengths<R>(f: impl FnOnce(&StableBTreeMap<String, u32, Memory>) -> R) -> R {
+    NFT_DOC_LENGTHS.with(|lengths| f(&lengths.borrow()))
+}
+
+pub fn with_nft_doc_lengths_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, u32, Memory>) -> R) -> R {
+    NFT_DOC_LENGTHS.with(|lengths| f(&mut lengths.borrow_mut()))
+}
+
+pub fn with_recent_transactions<R>(f: impl FnOnce(&StableBTreeMap<[u8; 32], u64, Memory>) -> R) -> R {
+    RECENT_TRANSACTIONS.with(|transactions| f(&transactions.borrow()))
+}
+
+pub fn with_recent_transactions_mut<R>(f: impl FnOnce(&mut StableBTreeMap<[u8; 32], u64, Memory>) -> R) -> R {
+    RECENT_TRANSACTIONS.with(|transactions| f(&mut transactions.borrow_mut()))
+}
+
+pub fn with_burned_nfts<R>(f: impl FnOnce(&StableBTreeMap<String, IPNft, Memory>) -> R) -> R {
+    BURNED_NFTS.with(|burned| f(&burned.borrow()))
+}
+
+pub fn with_burned_nfts_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, IPNft, Memory>) -> R) -> R {
+    BURNED_NFTS.with(|burned| f(&mut burned.borrow_mut()))
+}
+
+pub fn with_auction_bids<R>(f: impl FnOnce(&StableBTreeMap<(IdKey, u64), Bid, Memory>) -> R) -> R {
+    AUCTION_BIDS.with(|bids| f(&bids.borrow()))
+}
+
+pub fn with_auction_bids_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(IdKey, u64), Bid, Memory>) -> R) -> R {
+    AUCTION_BIDS.with(|bids| f(&mut bids.borrow_mut()))
+}
+
+pub fn with_collection_supply<R>(f: impl FnOnce(&StableBTreeMap<String, CollectionSupply, Memory>) -> R) -> R {
+    COLLECTION_SUPPLY.with(|supply| f(&supply.borrow()))
+}
+
+pub fn with_collection_supply_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, CollectionSupply, Memory>) -> R) -> R {
+    COLLECTION_SUPPLY.with(|supply| f(&mut supply.borrow_mut()))
+}
+
+pub fn with_counted_favorites<R>(f: impl FnOnce(&StableBTreeMap<(IdKey, Principal), (), Memory>) -> R) -> R {
+    COUNTED_FAVORITES.with(|counted| f(&counted.borrow()))
+}
+
+pub fn with_counted_favorites_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(IdKey, Principal), (), Memory>) -> R) -> R {
+    COUNTED_FAVORITES.with(|counted| f(&mut counted.borrow_mut()))
+}
+
+pub fn with_trending_nft_ranks<R>(f: impl FnOnce(&StableBTreeMap<(u64, IdKey), (), Memory>) -> R) -> R {
+    TRENDING_NFT_RANKS.with(|ranks| f(&ranks.borrow()))
+}
+
+pub fn with_trending_nft_ranks_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(u64, IdKey), (), Memory>) -> R) -> R {
+    TRENDING_NFT_RANKS.with(|ranks| f(&mut ranks.borrow_mut()))
+}
+
+pub fn with_trending_collection_ranks<R>(f: impl FnOnce(&StableBTreeMap<(u64, IdKey), (), Memory>) -> R) -> R {
+    TRENDING_COLLECTION_RANKS.with(|ranks| f(&ranks.borrow()))
+}
+
+pub fn with_trending_collection_ranks_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(u64, IdKey), (), Memory>) -> R) -> R {
+    TRENDING_COLLECTION_RANKS.with(|ranks| f(&mut ranks.borrow_mut()))
+}
+
+pub fn with_reserved_collection_names<R>(f: impl FnOnce(&StableBTreeMap<String, (), Memory>) -> R) -> R {
+    RESERVED_COLLECTION_NAMES.with(|names| f(&names.borrow()))
+}
+
+pub fn with_reserved_collection_names_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, (), Memory>) -> R) -> R {
+    RESERVED_COLLECTION_NAMES.with(|names| f(&mut names.borrow_mut()))
+}
+
+pub fn with_data_migrations<R>(f: impl FnOnce(&StableBTreeMap<String, u64, Memory>) -> R) -> R {
+    DATA_MIGRATIONS.with(|migrations| f(&migrations.borrow()))
+}
+
+pub fn with_data_migrations_mut<R>(f: impl FnOnce(&mut StableBTreeMap<String, u64, Memory>) -> R) -> R {
+    DATA_MIGRATIONS.with(|migrations| f(&mut migrations.borrow_mut()))
+}
+
+pub fn with_transaction_expiry_mut<R>(f: impl FnOnce(&mut StableBTreeMap<(u64, [u8; 32]), (), Memory>) -> R) -> R {
+    TRANSACTION_EXPIRY.with(|expiry| f(&mut expiry.borrow_mut()))
+}
+
+pub fn with_text_index_totals<R>(f: impl FnOnce(&TextIndexTotals) -> R) -> R {
+    TEXT_INDEX_TOTALS.with(|totals| f(&totals.borrow()))
+}
+
+pub fn with_text_index_totals_mut<R>(f: impl FnOnce(&mut TextIndexTotals) -> R) -> R {
+    TEXT_INDEX_TOTALS.with(|totals| f(&mut totals.borrow_mut()))
+}
+
+// Secondary index maintenance. Each write diffs the old record against the new
+// one and touches only the index entries that changed, in the same message as
+// the record itself.
+
+fn listing_status_code(status: &ListingStatus) -> u8 {
+    match status {
+        ListingStatus::Active => 0,
+        ListingStatus::Sold => 1,
+        ListingStatus::Cancelled => 2,
+        ListingStatus::Expired => 3,
+        ListingStatus::InAuction => 4,
+    }
+}
+
+// Collection names from before collections were validated may be too long for
+// a stable key; NFTs in those collections are found by scanning instead
+fn collection_index_key(collection_name: &str) -> Option<IdKey> {
+    (collection_name.len() <= MAX_ID_KEY_SIZE as usize).then(|| IdKey(collection_name.to_string()))
+}
+
+fn ids_by_principal(map: &StableBTreeMap<(Principal, IdKey), (), Memory>, principal: Principal) -> Vec<String> {
+    map.range((principal, IdKey(String::new()))..)
+        .take_while(|((key, _), _)| *key == principal)
+        .map(|((_, id), _)| id.0)
+        .collect()
+}
+
+fn ids_by_id(map: &StableBTreeMap<(IdKey, IdKey), (), Memory>, key: &IdKey) -> Vec<String> {
+    map.range((key.clone(), IdKey(String::new()))..)
+        .take_while(|((k, _), _)| k == key)
+        .map(|((_, id), _)| id.0)
+        .collect()
+}
+
+pub fn insert_ip(ip: IntellectualProperty) -> Option<IntellectualProperty> {
+    let key = (ip.owner, IdKey(ip.id.clone()));
+    let previous = with_ip_registry_mut(|registry| registry.insert(ip.id.clone(), ip));
+    OWNER_IPS.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(ref previous) = previous {
+            if previous.owner != key.0 {
+                index.remove(&(previous.owner, key.1.clone()));
+            }
+        }
+        index.insert(key, ());
+    });
+    previous
+}
+
+fn unindex_nft(nft: &IPNft) {
+    OWNER_NFTS.with(|index| index.borrow_mut().remove(&(nft.owner, IdKey(nft.id.clone()))));
+    if let Some(collection) = nft.collection_name.as_deref().and_then(collection_index_key) {
+        COLLECTION_NFTS.with(|index| index.borrow_mut().remove(&(collection, IdKey(nft.id.clone()))));
+    }
+}
+
+fn index_nft(nft: &IPNft) {
+    OWNER_NFTS.with(|index| index.borrow_mut().insert((nft.owner, IdKey(nft.id.clone())), ()));
+    if let Some(collection) = nft.collection_name.as_deref().and_then(collection_index_key) {
+        COLLECTION_NFTS.with(|index| index.borrow_mut().insert((collection, IdKey(nft.id.clone())), ()));
+    }
+}
+
+pub fn insert_nft(nft: IPNft) -> Option<IPNft> {
+    let previous = with_nft_registry_mut(|registry| registry.insert(nft.id.clone(), nft.clone()));
+    match previous {
+        Some(ref previous)
+            if previous.owner == nft.owner && previous.collection_name == nft.collection_name => {}
+        Some(ref previous) => {
+            unindex_nft(previous);
+            index_nft(&nft);
+        }
+        None => index_nft(&nft),
+    }
+    previous
+}
+
+pub fn remove_nft(nft_id: &str) -> Option<IPNft> {
+    let removed = with_nft_registry_mut(|registry| registry.remove(&nft_id.to_string()));
+    if let Some(ref nft) = removed {
+        unindex_nft(nft);
+    }
+    removed
+}
+
+fn unindex_listing(listing: &MarketplaceListing) {
+    let id = IdKey(listing.id.clone());
+    SELLER_LISTINGS.with(|index| index.borrow_mut().remove(&(listing.seller, id.clone())));
+    NFT_LISTINGS.with(|index| index.borrow_mut().remove(&(IdKey(listing.nft_id.clone()), id.clone())));
+    STATUS_LISTINGS.with(|index| index.borrow_mut().remove(&(listing_status_code(&listing.status), id)));
+}
+
+fn index_listing(listing: &MarketplaceListing) {
+    let id = IdKey(listing.id.clone());
+    SELLER_LISTINGS.with(|index| index.borrow_mut().insert((listing.seller, id.clone()), ()));
+    NFT_LISTINGS.with(|index| index.borrow_mut().insert((IdKey(listing.nft_id.clone()), id.clone()), ()));
+    STATUS_LISTINGS.with(|index| index.borrow_mut().insert((listing_status_code(&listing.status), id), ()));
+}
+
+pub fn insert_listing(listing: MarketplaceListing) -> Option<MarketplaceListing> {
+    let previous = with_marketplace_mut(|marketplace| marketplace.insert(listing.id.clone(), listing.clone()));
+    match previous {
+        Some(ref previous)
+            if previous.seller == listing.seller
+                && previous.nft_id == listing.nft_id
+                && listing_status_code(&previous.status) == listing_status_code(&listing.status) => {}
+        Some(ref previous) => {
+            unindex_listing(previous);
+            index_listing(&listing);
+        }
+        None => index_listing(&listing),
+    }
+    previous
+}
+
+// Index lookups, each in record ID order
+
+pub fn ips_of_owner(owner: Principal) -> Vec<IntellectualProperty> {
+    let ids = OWNER_IPS.with(|index| ids_by_principal(&index.borrow(), owner));
+    with_ip_registry(|registry| ids.iter().filter_map(|id| registry.get(id)).collect())
+}
+
+pub fn nfts_of_owner(owner: Principal) -> Vec<IPNft> {
+    let ids = OWNER_NFTS.with(|index| ids_by_principal(&index.borrow(), owner));
+    with_nft_registry(|registry| ids.iter().filter_map(|id| registry.get(id)).collect())
+}
+
+pub fn nfts_in_collection(collection_name: &str) -> Vec<IPNft> {
+    let Some(key) = collection_index_key(collection_name) else {
+        return with_nft_registry(|registry| {
+            registry
+                .iter()
+                .filter(|(_, nft)| nft.collection_name.as_deref() == Some(collection_name))
+                .map(|(_, nft)| nft)
+                .collect()
+        });
+    };
+    let ids = COLLECTION_NFTS.with(|index| ids_by_id(&index.borrow(), &key));
+    with_nft_registry(|registry| ids.iter().filter_map(|id| registry.get(id)).collect())
+}
+
+pub fn listings_of_seller(seller: Principal) -> Vec<MarketplaceListing> {
+    let ids = SELLER_LISTINGS.with(|index| ids_by_principal(&index.borrow(), seller));
+    with_marketplace(|marketplace| ids.iter().filter_map(|id| marketplace.get(id)).collect())
+}
+
+pub fn listings_for_nft(nft_id: &str) -> Vec<MarketplaceListing> {
+    let ids = NFT_LISTINGS.with(|index| ids_by_id(&index.borrow(), &IdKey(nft_id.to_string())));
+    with_marketplace(|marketplace| ids.iter().filter_map(|id| marketplace.get(id)).collect())
+}
+
+pub fn listing_ids_with_status(status: &ListingStatus) -> Vec<String> {
+    let code = listing_status_code(status);
+    STATUS_LISTINGS.with(|index| {
+        index
+            .borrow()
+            .range((code, IdKey(String::new()))..)
+            .take_while(|((c, _), _)| *c == code)
+            .map(|((_, id), _)| id.0)
+            .collect()
+    })
+}
+
+pub fn listings_with_status(status: &ListingStatus) -> Vec<MarketplaceListing> {
+    let ids = listing_ids_with_status(status);
+    with_marketplace(|marketplace| ids.iter().filter_map(|id| marketplace.get(id)).collect())
+}
+
+// Called from post_upgrade: fills the indexes for records written before they
+// existed. Every record has exactly one owner or seller entry, so a count
+// mismatch means the indexes need rebuilding.
+pub fn rebuild_secondary_indexes() {
+    let ips = with_ip_registry(|registry| registry.len());
+    if OWNER_IPS.with(|index| index.borrow().len()) != ips {
+        OWNER_IPS.with(|index| index.borrow_mut().clear_new());
+        let owned: Vec<(Principal, String)> = with_ip_registry(|registry| {
+            registry.iter().map(|(id, ip)| (ip.owner, id)).collect()
+        });
+        OWNER_IPS.with(|index| {
+            let mut index = index.borrow_mut();
+            for (owner, id) in owned {
+                index.insert((owner, IdKey(id)), ());
+            }
+        });
+    }
+
+    let nfts = with_nft_registry(|registry| registry.len());
+    if OWNER_NFTS.with(|index| index.borrow().len()) != nfts {
+        OWNER_NFTS.with(|index| index.borrow_mut().clear_new());
+        COLLECTION_NFTS.with(|index| index.borrow_mut().clear_new());
+        let all: Vec<IPNft> = with_nft_registry(|registry| registry.iter().map(|(_, nft)| nft).collect());
+        for nft in &all {
+            index_nft(nft);
+        }
+    }
+
+    let listings = with_marketplace(|marketplace| marketplace.len());
+    if SELLER_LISTINGS.with(|index| index.borrow().len()) != listings {
+        SELLER_LISTINGS.with(|index| index.borrow_mut().clear_new());
+        NFT_LISTINGS.with(|index| index.borrow_mut().clear_new());
+        STATUS_LISTINGS.with(|index| index.borrow_mut().clear_new());
+        let all: Vec<MarketplaceListing> = with_marketplace(|marketplace| {
+            marketplace.iter().map(|(_, listing)| listing).collect()
+        });
+        for listing in &all {
+            index_listing(listing);
+        }
+    }
+}
+
+// Called from post_upgrade: fills the token index for NFTs minted before it existed
+pub fn rebuild_token_index() {
+    let indexed = with_token_index(|index| index.len());
+    let minted = with_nft_registry(|registry| registry.len());
+    if indexed == minted {
+        return;
+    }
+    let entries: Vec<(u64, String)> = with_nft_registry(|registry| {
+        registry.iter().map(|(id, nft)| (nft.token_id, id)).collect()
+    });
+    with_token_index_mut(|index| {
+        for (token_id, nft_id) in entries {
+            index.insert(token_id, nft_id);
+        }
+    });
+}
+
+// Held while a listing or payout waits on an inter-canister call; released on
+// drop so every early return unlocks it
+pub struct CallLock {
+    key: String,
+}
+
+impl CallLock {
+    pub fn acquire(key: &str) -> Option<CallLock> {
+        CALL_LOCKS.with(|locks| {
+            if locks.borrow_mut().insert(key.to_string()) {
+                Some(CallLock { key: key.to_string() })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Drop for CallLock {
+    fn drop(&mut self) {
+        CALL_LOCKS.with(|locks| {
+            locks.borrow_mut().remove(&self.key);
+        });
+    }
+}
+
+pub fn is_locked(key: &str) -> bool {
+    CALL_LOCKS.with(|locks| locks.borrow().contains(key))
+}
+
+pub fn with_id_counters<R>(f: impl FnOnce(&IdCounters) -> R) -> R {
+    ID_COUNTERS.with(|cell| f(cell.borrow().get()))
+}
+
+pub fn with_id_counters_mut<R>(f: impl FnOnce(&mut IdCounters) -> R) -> R {
+    ID_COUNTERS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut counters = cell.get().clone();
+        let result = f(&mut counters);
+        cell.set(counters).expect("failed to persist ID counters");
+        result
+    })
+}
+
+// Raw views over the registries, used to inspect stored schema versions without
+// decoding (and therefore without migrating) the records
+fn record_versions<K: Storable + Ord + Clone>(memory_id: MemoryId) -> Vec<(K, u32)> {
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(memory_id));
+    let raw: StableBTreeMap<K, RawRecord, Memory> = StableBTreeMap::load(memory);
+    raw.iter()
+        .map(|(key, record)| (key, split_envelope(&record.0).0))
+        .collect()
+}
+
+pub fn ip_registry_record_versions() -> Vec<(String, u32)> {
+    record_versions(IP_REGISTRY_MEMORY_ID)
+}
+
+pub fn nft_registry_record_versions() -> Vec<(String, u32)> {
+    record_versions(NFT_REGISTRY_MEMORY_ID)
+}
+
+pub fn user_registry_record_versions() -> Vec<(Principal, u32)> {
+    record_versions(USER_REGISTRY_MEMORY_ID)
+}
+
+pub fn marketplace_record_versions() -> Vec<(String, u32)> {
+    record_versions(MARKETPLACE_MEMORY_ID)
+}
+
+pub fn nft_metadata_record_versions() -> Vec<(String, u32)> {
+    record_versions(NFT_METADATA_MEMORY_ID)
+}
+
+// Sequence kinds that are not ID prefixes
+pub const TOKEN_ID_SEQUENCE: &str = "TOKEN";
+
+// Returns the next value of the given sequence, starting at 0
+pub fn next_sequence(kind: &str) -> u64 {
+    with_id_counters_mut(|ids| {
+        let counter = ids.counters.entry(kind.to_string()).or_insert(0);
         let current = *counter;
         *counter = current + 1;
-        format!("{}_{}", prefix, current)
+        current
     })
 }
+
+// Helper function to generate unique IDs
+pub fn generate_id(prefix: &str) -> String {
+    format!("{}_{}", prefix, next_sequence(prefix))
+}
+
+// Moves a sequence forward so it never hands out `value` or anything below it
+fn ensure_sequence_above(ids: &mut IdCounters, kind: &str, value: u64) {
+    let counter = ids.counters.entry(kind.to_string()).or_insert(0);
+    if *counter <= value {
+        *counter = value + 1;
+    }
+}
+
+fn id_suffix(id: &str, prefix: &str) -> Option<u64> {
+    id.strip_prefix(prefix)?.strip_prefix('_')?.parse().ok()
+}
+
+// Called from post_upgrade: makes sure every sequence is ahead of the keys already
+// stored, so canisters upgraded from the heap counter never reissue an existing ID.
+pub fn reconcile_id_counters() {
+    let max_ip = with_ip_registry(|registry| {
+        registry.iter().filter_map(|(id, _)| id_suffix(&id, "IP")).max()
+    });
+    let (max_nft, max_token) = with_nft_registry(|registry| {
+        let max_nft = registry.iter().filter_map(|(id, _)| id_suffix(&id, "NFT")).max();
+        let max_token = registry.iter().map(|(_, nft)| nft.token_id).max();
+        (max_nft, max_token)
+    });
+    let max_listing = with_marketplace(|marketplace| {
+        marketplace.iter().filter_map(|(id, _)| id_suffix(&id, "LISTING")).max()
+    });
+
+    with_id_counters_mut(|ids| {
+        if let Some(max) = max_ip {
+            ensure_sequence_above(ids, "IP", max);
+        }
+        if let Some(max) = max_nft {
+            ensure_sequence_above(ids, "NFT", max);
+        }
+        if let Some(max) = max_token {
+            ensure_sequence_above(ids, TOKEN_ID_SEQUENCE, max);
+        }
+        if let Some(max) = max_listing {
+            ensure_sequence_above(ids, "LISTING", max);
+        }
+    });
+}
@@ -0,0 +1,36 @@
+use candid::Principal;
+
+use crate::types::*;
+use crate::storage::*;
+
+// Records shared by the unit tests
+
+// Stores a transferable NFT with a 10% royalty and indexes its token ID
+pub fn mint_test_nft(nft_id: &str, token_id: u64, owner: Principal, creator: Principal) -> IPNft {
+    let nft = IPNft {
+        id: nft_id.to_string(),
+        ip_id: "IP_1".to_string(),
+        token_id,
+        owner,
+        owner_subaccount: None,
+        creator,
+        metadata_uri: String::new(),
+        minted_at: 0,
+        royalty_percentage: 10,
+        is_transferable: true,
+        name: "Test NFT".to_string(),
+        description: String::new(),
+        image: String::new(),
+        collection_name: None,
+        edition_number: None,
+        total_editions: None,
+        rarity_rank: None,
+        rarity_score: None,
+        transfer_history: Vec::new(),
+        view_count: 0,
+        favorite_count: 0,
+    };
+    insert_nft(nft.clone());
+    with_token_index_mut(|index| index.insert(token_id, nft_id.to_string()));
+    nft
+}
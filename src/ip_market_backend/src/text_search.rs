@@ -0,0 +1,356 @@
+use ic_stable_structures::StableBTreeMap;
+use std::collections::BTreeMap;
+
+use crate::types::*;
+use crate::storage::*;
+
+// Full-text search over IPs and NFTs. Text is split into lowercase
+// alphanumeric tokens, without stemming, and each document's token counts are
+// kept in an inverted index. A query token of at least MIN_PREFIX_CHARS
+// characters also matches terms it is a prefix of, so "photo" finds
+// "photography", up to MAX_PREFIX_EXPANSIONS terms and MAX_PREFIX_POSTINGS
+// postings; shorter tokens match only the same term, since they would expand
+// to much of the index. A document must match every query token and is
+// scored with BM25, counting titles twice.
+//
+// IPs are indexed on title, description, tags, category and genre; NFTs on
+// name, description, IP category and attributes. Neither changes after
+// registration or minting, so documents are only added and removed.
+
+// BM25 term-frequency saturation and length normalisation
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+// Score factor for a term that only extends a query token
+const PREFIX_MATCH_WEIGHT: f64 = 0.5;
+
+// Shortest query token, in characters, that also matches longer terms
+const MIN_PREFIX_CHARS: usize = 3;
+
+// Most distinct longer terms one query token expands to, taken in term order
+const MAX_PREFIX_EXPANSIONS: usize = 50;
+
+// Most postings those longer terms add between them; the term that would go
+// past this is left out whole
+const MAX_PREFIX_POSTINGS: usize = 2_000;
+
+// Longer tokens are cut to this many bytes, to fit a stable key
+const MAX_TERM_BYTES: usize = 64;
+
+// Times a title token counts towards term frequency
+const TITLE_WEIGHT: u32 = 2;
+
+#[derive(Clone, Copy)]
+pub enum Corpus {
+    Ips,
+    Nfts,
+}
+
+fn with_terms<R>(corpus: Corpus, f: impl FnOnce(&StableBTreeMap<(IdKey, IdKey), u32, Memory>) -> R) -> R {
+    match corpus {
+        Corpus::Ips => with_ip_terms(f),
+        Corpus::Nfts => with_nft_terms(f),
+    }
+}
+
+fn with_terms_mut<R>(corpus: Corpus, f: impl FnOnce(&mut StableBTreeMap<(IdKey, IdKey), u32, Memory>) -> R) -> R {
+    match corpus {
+        Corpus::Ips => with_ip_terms_mut(f),
+        Corpus::Nfts => with_nft_terms_mut(f),
+    }
+}
+
+fn with_doc_lengths<R>(corpus: Corpus, f: impl FnOnce(&StableBTreeMap<String, u32, Memory>) -> R) -> R {
+    match corpus {
+        Corpus::Ips => with_ip_doc_lengths(f),
+        Corpus::Nfts => with_nft_doc_lengths(f),
+    }
+}
+
+fn with_doc_lengths_mut<R>(corpus: Corpus, f: impl FnOnce(&mut StableBTreeMap<String, u32, Memory>) -> R) -> R {
+    match corpus {
+        Corpus::Ips => with_ip_doc_lengths_mut(f),
+        Corpus::Nfts => with_nft_doc_lengths_mut(f),
+    }
+}
+
+fn adjust_total_tokens(corpus: Corpus, length: u32, add: bool) {
+    with_text_index_totals_mut(|totals| {
+        let total = match corpus {
+            Corpus::Ips => &mut totals.ip_tokens,
+            Corpus::Nfts => &mut totals.nft_tokens,
+        };
+        *total = if add { *total + length as u64 } else { total.saturating_sub(length as u64) };
+    });
+}
+
+fn truncate_term(mut term: String) -> String {
+    if term.len() > MAX_TERM_BYTES {
+        let mut end = MAX_TERM_BYTES;
+        while !term.is_char_boundary(end) {
+            end -= 1;
+        }
+        term.truncate(end);
+    }
+    term
+}
+
+// Lowercase runs of letters and digits
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| truncate_term(token.to_lowercase()))
+        .collect()
+}
+
+fn count_terms(counts: &mut BTreeMap<String, u32>, text: &str, weight: u32) {
+    for term in tokenize(text) {
+        *counts.entry(term).or_insert(0) += weight;
+    }
+}
+
+fn ip_terms(ip: &IntellectualProperty) -> BTreeMap<String, u32> {
+    let mut counts = BTreeMap::new();
+    count_terms(&mut counts, &ip.title, TITLE_WEIGHT);
+    count_terms(&mut counts, &ip.description, 1);
+    for tag in &ip.metadata.tags {
+        count_terms(&mut counts, tag, 1);
+    }
+    count_terms(&mut counts, &ip.metadata.category, 1);
+    if let Some(ref genre) = ip.metadata.genre {
+        count_terms(&mut counts, genre, 1);
+    }
+    counts
+}
+
+fn nft_terms(nft: &IPNft, metadata: Option<&NFTMetadata>) -> BTreeMap<String, u32> {
+    let mut counts = BTreeMap::new();
+    count_terms(&mut counts, &nft.name, TITLE_WEIGHT);
+    count_terms(&mut counts, &nft.description, 1);
+    if let Some(metadata) = metadata {
+        count_terms(&mut counts, &metadata.ip_category, 1);
+        for attribute in &metadata.attributes {
+            count_terms(&mut counts, &attribute.trait_type, 1);
+            if let AttributeValue::Text(ref value) = attribute.value {
+                count_terms(&mut counts, value, 1);
+            }
+        }
+    }
+    counts
+}
+
+fn add_document(corpus: Corpus, doc_id: &str, terms: BTreeMap<String, u32>) {
+    let length: u32 = terms.values().sum();
+    with_terms_mut(corpus, |postings| {
+        for (term, count) in terms {
+            postings.insert((IdKey(term), IdKey(doc_id.to_string())), count);
+        }
+    });
+    let previous = with_doc_lengths_mut(corpus, |lengths| lengths.insert(doc_id.to_string(), length));
+    if let Some(previous) = previous {
+        adjust_total_tokens(corpus, previous, false);
+    }
+    adjust_total_tokens(corpus, length, true);
+}
+
+fn remove_document(corpus: Corpus, doc_id: &str, terms: BTreeMap<String, u32>) {
+    with_terms_mut(corpus, |postings| {
+        for term in terms.into_keys() {
+            postings.remove(&(IdKey(term), IdKey(doc_id.to_string())));
+        }
+    });
+    if let Some(length) = with_doc_lengths_mut(corpus, |lengths| lengths.remove(&doc_id.to_string())) {
+        adjust_total_tokens(corpus, length, false);
+    }
+}
+
+pub fn index_ip_text(ip: &IntellectualProperty) {
+    add_document(Corpus::Ips, &ip.id, ip_terms(ip));
+}
+
+pub fn index_nft_text(nft: &IPNft, metadata: Option<&NFTMetadata>) {
+    add_document(Corpus::Nfts, &nft.id, nft_terms(nft, metadata));
+}
+
+pub fn unindex_nft_text(nft: &IPNft, metadata: Option<&NFTMetadata>) {
+    remove_document(Corpus::Nfts, &nft.id, nft_terms(nft, metadata));
+}
+
+// (term, document ID, term frequency) for every term `token` matches. The
+// token's own term always matches in full, ahead of any expansions.
+fn postings_for_token(corpus: Corpus, token: &str) -> Vec<(String, String, u32)> {
+    let expand = token.chars().count() >= MIN_PREFIX_CHARS;
+    let start = (IdKey(token.to_string()), IdKey(String::new()));
+    with_terms(corpus, |postings| {
+        let mut matched: Vec<(String, String, u32)> = Vec::new();
+        let mut expansions = 0;
+        let mut expanded_postings = 0;
+        let mut term_start = 0;
+        for ((IdKey(term), IdKey(doc_id)), count) in postings.range(start..) {
+            if term != token {
+                if !expand || !term.starts_with(token) {
+                    break;
+                }
+                if matched.last().is_none_or(|(last, _, _)| *last != term) {
+                    if expansions == MAX_PREFIX_EXPANSIONS {
+                        break;
+                    }
+                    expansions += 1;
+                    term_start = matched.len();
+                }
+                if expanded_postings == MAX_PREFIX_POSTINGS {
+                    matched.truncate(term_start);
+                    break;
+                }
+                expanded_postings += 1;
+            }
+            matched.push((term, doc_id, count));
+        }
+        matched
+    })
+}
+
+// BM25 relevance of every document matching all tokens of `query`, or None if
+// the query has no tokens and so matches everything
+pub fn text_scores(corpus: Corpus, query: &str) -> Option<BTreeMap<String, f64>> {
+    let mut tokens = tokenize(query);
+    if tokens.is_empty() {
+        return None;
+    }
+    tokens.sort();
+    tokens.dedup();
+
+    let documents = with_doc_lengths(corpus, |lengths| lengths.len()) as f64;
+    let total_tokens = with_text_index_totals(|totals| match corpus {
+        Corpus::Ips => totals.ip_tokens,
+        Corpus::Nfts => totals.nft_tokens,
+    }) as f64;
+    let average_length = if documents > 0.0 { total_tokens / documents } else { 0.0 };
+
+    let mut scores: Option<BTreeMap<String, f64>> = None;
+    for token in tokens {
+        let postings = postings_for_token(corpus, &token);
+
+        let mut frequencies: BTreeMap<&str, f64> = BTreeMap::new();
+        for (term, _, _) in &postings {
+            *frequencies.entry(term.as_str()).or_insert(0.0) += 1.0;
+        }
+
+        let mut token_scores: BTreeMap<String, f64> = BTreeMap::new();
+        for (term, doc_id, count) in &postings {
+            let document_frequency = frequencies[term.as_str()];
+            let idf = (1.0 + (documents - document_frequency + 0.5) / (document_frequency + 0.5)).ln();
+            let length = with_doc_lengths(corpus, |lengths| lengths.get(doc_id)).unwrap_or(0) as f64;
+            let norm = if average_length > 0.0 { length / average_length } else { 1.0 };
+            let tf = *count as f64;
+            let weight = if *term == token { 1.0 } else { PREFIX_MATCH_WEIGHT };
+            let score = weight * idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * norm));
+
+            // A document matching several expansions of a token keeps the best
+            let best = token_scores.entry(doc_id.clone()).or_insert(0.0);
+            *best = best.max(score);
+        }
+
+        scores = Some(match scores {
+            None => token_scores,
+            Some(previous) => previous
+                .into_iter()
+                .filter_map(|(doc_id, score)| token_scores.get(&doc_id).map(|s| (doc_id, score + s)))
+                .collect(),
+        });
+    }
+    scores
+}
+
+// Called from post_upgrade: indexes records stored before the index existed
+// and recounts the heap token totals
+pub fn rebuild_text_index() {
+    let ips = with_ip_registry(|registry| registry.len());
+    if with_ip_doc_lengths(|lengths| lengths.len()) != ips {
+        with_ip_terms_mut(|postings| postings.clear_new());
+        with_ip_doc_lengths_mut(|lengths| lengths.clear_new());
+        let all: Vec<IntellectualProperty> = with_ip_registry(|registry| registry.iter().map(|(_, ip)| ip).collect());
+        for ip in &all {
+            add_document(Corpus::Ips, &ip.id, ip_terms(ip));
+        }
+    }
+
+    let nfts = with_nft_registry(|registry| registry.len());
+    if with_nft_doc_lengths(|lengths| lengths.len()) != nfts {
+        with_nft_terms_mut(|postings| postings.clear_new());
+        with_nft_doc_lengths_mut(|lengths| lengths.clear_new());
+        let all: Vec<IPNft> = with_nft_registry(|registry| registry.iter().map(|(_, nft)| nft).collect());
+        for nft in &all {
+            let metadata = with_nft_metadata(|registry| registry.get(&nft.id));
+            add_document(Corpus::Nfts, &nft.id, nft_terms(nft, metadata.as_ref()));
+        }
+    }
+
+    let ip_tokens = with_ip_doc_lengths(|lengths| lengths.iter().map(|(_, length)| length as u64).sum());
+    let nft_tokens = with_nft_doc_lengths(|lengths| lengths.iter().map(|(_, length)| length as u64).sum());
+    with_text_index_totals_mut(|totals| {
+        *totals = TextIndexTotals { ip_tokens, nft_tokens };
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::mint_test_nft;
+    use candid::Principal;
+
+    fn index_named(nft_id: &str, token_id: u64, name: &str) {
+        let owner = Principal::from_slice(&[1]);
+        let mut nft = mint_test_nft(nft_id, token_id, owner, owner);
+        nft.name = name.to_string();
+        index_nft_text(&nft, None);
+    }
+
+    #[test]
+    fn prefix_expansion_is_capped() {
+        index_named("NFT_EXACT", 0, "photo");
+        for n in 1..=60 {
+            index_named(&format!("NFT_{}", n), n, &format!("photo{:02}", n));
+        }
+
+        let scores = text_scores(Corpus::Nfts, "photo").unwrap();
+        assert_eq!(scores.len(), 1 + MAX_PREFIX_EXPANSIONS);
+        assert!(scores.contains_key("NFT_EXACT"));
+        assert!(scores.contains_key("NFT_50") && !scores.contains_key("NFT_51"));
+    }
+
+    #[test]
+    fn prefix_postings_are_capped_at_a_term_boundary() {
+        let owner = Principal::from_slice(&[1]);
+        let nft = mint_test_nft("NFT_0", 0, owner, owner);
+        let index = |nft_id: String, name: &str| {
+            let mut nft = nft.clone();
+            nft.id = nft_id;
+            nft.name = name.to_string();
+            index_nft_text(&nft, None);
+        };
+        for n in 0..MAX_PREFIX_POSTINGS - 100 {
+            index(format!("NFT_A{}", n), "photoa");
+        }
+        for n in 0..200 {
+            index(format!("NFT_B{}", n), "photob");
+        }
+
+        // photob would take the postings past the cap, so none of it counts
+        let scores = text_scores(Corpus::Nfts, "photo").unwrap();
+        assert_eq!(scores.len(), MAX_PREFIX_POSTINGS - 100);
+        assert!(scores.keys().all(|nft_id| nft_id.starts_with("NFT_A")));
+    }
+
+    #[test]
+    fn short_tokens_match_only_the_same_term() {
+        index_named("NFT_1", 1, "photography");
+        index_named("NFT_2", 2, "ph");
+
+        let scores = text_scores(Corpus::Nfts, "ph").unwrap();
+        assert_eq!(scores.keys().collect::<Vec<_>>(), ["NFT_2"]);
+
+        let scores = text_scores(Corpus::Nfts, "pho").unwrap();
+        assert_eq!(scores.keys().collect::<Vec<_>>(), ["NFT_1"]);
+    }
+}
@@ -0,0 +1,357 @@
+use ic_cdk::{query, update};
+use candid::{Nat, Principal};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::access_control::require_admin;
+use crate::ledger::icrc1_transfer;
+use crate::payments::*;
+use crate::royalties::royalty_amount;
+
+// Upper bound on the platform fee an admin can set (25%)
+pub const MAX_PLATFORM_FEE_BPS: u16 = 2_500;
+
+pub fn platform_fee_amount(price: u64, platform_fee_bps: u16) -> u64 {
+    (price as u128 * platform_fee_bps as u128 / 10_000) as u64
+}
+
+// Splits a sale price into platform fee, creator royalty and seller proceeds. The
+// fee comes off the top; the royalty is capped at what remains.
+pub fn sale_breakdown(
+    listing: &MarketplaceListing,
+    price: u64,
+    royalty_percentage: u8,
+    creator: Principal,
+    transaction_hash: Option<String>,
+) -> SaleBreakdown {
+    let platform_fee_bps = with_marketplace_config(|config| config.platform_fee_bps);
+    let platform_fee = platform_fee_amount(price, platform_fee_bps);
+    let royalty = royalty_amount(price, royalty_percentage, listing.seller, creator).min(price - platform_fee);
+
+    SaleBreakdown {
+        listing_id: listing.id.clone(),
+        nft_id: listing.nft_id.clone(),
+        currency: listing.currency.clone(),
+        price,
+        platform_fee,
+        royalty,
+        seller_proceeds: price - platform_fee - royalty,
+        transaction_hash,
+    }
+}
+
+pub fn credit_treasury(currency: &str, amount: u64) {
+    if amount == 0 {
+        return;
+    }
+    with_treasury_mut(|treasury| {
+        let mut balance = treasury.get(&currency.to_string()).unwrap_or(TreasuryBalance {
+            currency: currency.to_string(),
+            collected: 0,
+            withdrawn: 0,
+            updated_at: 0,
+        });
+        balance.collected += amount;
+        balance.updated_at = time();
+        treasury.insert(currency.to_string(), balance);
+    });
+}
+
+#[update]
+pub fn set_platform_fee(platform_fee_bps: u16) -> Result<FeeChangeRecord> {
+    require_admin()?;
+
+    if platform_fee_bps > MAX_PLATFORM_FEE_BPS {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    let previous_bps = with_marketplace_config_mut(|config| {
+        let previous = config.platform_fee_bps;
+        config.platform_fee_bps = platform_fee_bps;
+        previous
+    });
+
+    let record = FeeChangeRecord {
+        id: next_sequence("FEE_CHANGE"),
+        previous_bps,
+        new_bps: platform_fee_bps,
+        changed_by: ic_cdk::caller(),
+        changed_at: time(),
+    };
+
+    with_fee_changes_mut(|changes| {
+        changes.insert(record.id, record.clone());
+    });
+
+    Ok(record)
+}
+
+#[query]
+pub fn get_fee_change_history() -> Vec<FeeChangeRecord> {
+    with_fee_changes(|changes| {
+        changes.iter().map(|(_, record)| record).collect()
+    })
+}
+
+#[query]
+pub fn get_treasury_balances() -> Vec<TreasuryBalance> {
+    with_treasury(|treasury| {
+        treasury.iter().map(|(_, balance)| balance).collect()
+    })
+}
+
+// Sends collected fees out of the marketplace account. `amount` is deducted from
+// the treasury in full; the recipient receives it net of the ledger fee. If the
+// ledger call fails without a verdict the amount stays reserved and the
+// withdrawal is retried from a timer; see get_pending_withdrawals.
+#[update]
+pub async fn withdraw_treasury(currency: String, to: Account, amount: u64) -> Result<Nat> {
+    require_admin()?;
+    withdraw(currency, to, amount).await
+}
+
+async fn withdraw(currency: String, to: Account, amount: u64) -> Result<Nat> {
+    let ledger = ledger_for_currency(&currency)?;
+    let _lock = CallLock::acquire(&treasury_lock(&currency)).ok_or(IPMarketplaceError::OperationFailed)?;
+
+    let fee = ledger_fee(ledger).await.map_err(|e| {
+        ic_cdk::println!("Treasury withdrawal failed: {}", e);
+        IPMarketplaceError::OperationFailed
+    })?;
+    if amount <= fee {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    // Reserve the amount before the ledger call so concurrent withdrawals cannot
+    // overdraw
+    with_treasury_mut(|treasury| {
+        let mut balance = treasury.get(&currency).ok_or(IPMarketplaceError::NotFound)?;
+        if balance.collected - balance.withdrawn < amount {
+            return Err(IPMarketplaceError::InsufficientFunds);
+        }
+        balance.withdrawn += amount;
+        balance.updated_at = time();
+        treasury.insert(currency.clone(), balance);
+        Ok(())
+    })?;
+
+    let withdrawal = PendingWithdrawal {
+        id: generate_id("WITHDRAWAL"),
+        currency,
+        to,
+        amount,
+        fee,
+        created_at: time(),
+        attempts: 0,
+        last_error: None,
+    };
+    with_pending_withdrawals_mut(|withdrawals| {
+        withdrawals.insert(withdrawal.id.clone(), withdrawal.clone());
+    });
+
+    send_withdrawal(ledger, withdrawal).await
+}
+
+fn treasury_lock(currency: &str) -> String {
+    format!("TREASURY:{}", currency)
+}
+
+fn release_reservation(withdrawal: &PendingWithdrawal) {
+    with_treasury_mut(|treasury| {
+        if let Some(mut balance) = treasury.get(&withdrawal.currency) {
+            balance.withdrawn -= withdrawal.amount;
+            balance.updated_at = time();
+            treasury.insert(withdrawal.currency.clone(), balance);
+        }
+    });
+}
+
+// Attempts a pending withdrawal. It is settled by the ledger's answer: a block
+// index or Duplicate completes it, a rejection releases the reservation. With no
+// answer the transfer may still have executed, so it stays pending for a retry.
+async fn send_withdrawal(ledger: Principal, mut withdrawal: PendingWithdrawal) -> Result<Nat> {
+    let args = TransferArg {
+        from_subaccount: None,
+        to: withdrawal.to.clone(),
+        amount: Nat::from(withdrawal.amount - withdrawal.fee),
+        fee: Some(Nat::from(withdrawal.fee)),
+        memo: payment_memo(&withdrawal.id),
+        created_at_time: Some(withdrawal.created_at),
+    };
+
+    let error = match icrc1_transfer(ledger, args).await {
+        Ok(Ok(block_index)) | Ok(Err(TransferError::Duplicate { duplicate_of: block_index })) => {
+            with_pending_withdrawals_mut(|withdrawals| {
+                withdrawals.remove(&withdrawal.id);
+            });
+            return Ok(block_index);
+        }
+        // Past the ledger's deduplication window an earlier attempt can no
+        // longer be ruled out; an admin settles it with resolve_pending_withdrawal
+        Ok(Err(TransferError::TooOld)) => "created_at_time outside the ledger's window".to_string(),
+        // A rejection only proves nothing was sent if no earlier attempt is
+        // still unaccounted for
+        Ok(Err(e)) if withdrawal.attempts == 0 => {
+            ic_cdk::println!("Treasury withdrawal rejected: {:?}", e);
+            release_reservation(&withdrawal);
+            with_pending_withdrawals_mut(|withdrawals| {
+                withdrawals.remove(&withdrawal.id);
+            });
+            return Err(match e {
+                TransferError::InsufficientFunds { .. } => IPMarketplaceError::InsufficientFunds,
+                _ => IPMarketplaceError::OperationFailed,
+            });
+        }
+        Ok(Err(e)) => format!("{:?}", e),
+        Err((code, message)) => format!("icrc1_transfer failed: {:?} {}", code, message),
+    };
+
+    ic_cdk::println!("Treasury withdrawal {} is pending: {}", withdrawal.id, error);
+    withdrawal.attempts += 1;
+    withdrawal.last_error = Some(error);
+    with_pending_withdrawals_mut(|withdrawals| {
+        withdrawals.insert(withdrawal.id.clone(), withdrawal);
+    });
+    Err(IPMarketplaceError::OperationFailed)
+}
+
+// Withdrawals retried per sweep, to keep each timer callback within instruction limits
+const WITHDRAWAL_RETRY_BATCH_SIZE: usize = 20;
+
+// How often pending withdrawals are retried; well inside the ledger's 24 hour
+// deduplication window
+pub const WITHDRAWAL_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+// Retries pending withdrawals; run periodically from a timer
+pub fn retry_pending_withdrawals() {
+    let pending: Vec<PendingWithdrawal> = with_pending_withdrawals(|withdrawals| {
+        withdrawals
+            .iter()
+            .map(|(_, withdrawal)| withdrawal)
+            .filter(|withdrawal| !is_locked(&treasury_lock(&withdrawal.currency)))
+            .take(WITHDRAWAL_RETRY_BATCH_SIZE)
+            .collect()
+    });
+
+    for withdrawal in pending {
+        ic_cdk::spawn(async move {
+            let _ = retry_withdrawal(withdrawal).await;
+        });
+    }
+}
+
+async fn retry_withdrawal(withdrawal: PendingWithdrawal) -> Result<Nat> {
+    let ledger = ledger_for_currency(&withdrawal.currency)?;
+    let _lock = CallLock::acquire(&treasury_lock(&withdrawal.currency)).ok_or(IPMarketplaceError::OperationFailed)?;
+    send_withdrawal(ledger, withdrawal).await
+}
+
+pub fn start_withdrawal_retrier() {
+    ic_cdk_timers::set_timer_interval(WITHDRAWAL_RETRY_INTERVAL, retry_pending_withdrawals);
+}
+
+#[query]
+pub fn get_pending_withdrawals() -> Vec<PendingWithdrawal> {
+    with_pending_withdrawals(|withdrawals| {
+        withdrawals.iter().map(|(_, withdrawal)| withdrawal).collect()
+    })
+}
+
+// Closes a withdrawal the retries cannot settle, once an admin has checked the
+// ledger: `executed` keeps the amount withdrawn, otherwise it returns to the
+// treasury
+#[update]
+pub fn resolve_pending_withdrawal(withdrawal_id: String, executed: bool) -> Result<PendingWithdrawal> {
+    require_admin()?;
+
+    let withdrawal = with_pending_withdrawals(|withdrawals| withdrawals.get(&withdrawal_id))
+        .ok_or(IPMarketplaceError::NotFound)?;
+    if is_locked(&treasury_lock(&withdrawal.currency)) {
+        return Err(IPMarketplaceError::OperationFailed);
+    }
+
+    if !executed {
+        release_reservation(&withdrawal);
+    }
+    with_pending_withdrawals_mut(|withdrawals| {
+        withdrawals.remove(&withdrawal_id);
+    });
+    Ok(withdrawal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::mock as ledger;
+
+    const CURRENCY: &str = "TEST";
+
+    fn admin_account() -> Account {
+        principal_account(Principal::from_slice(&[1]))
+    }
+
+    fn fund_treasury(amount: u64) {
+        ledger::configure_currency(CURRENCY);
+        credit_treasury(CURRENCY, amount);
+    }
+
+    fn withdrawn() -> u64 {
+        get_treasury_balances()[0].withdrawn
+    }
+
+    #[tokio::test]
+    async fn unanswered_withdrawal_stays_reserved_and_retries_identically() {
+        fund_treasury(1_000);
+        ledger::push_transfer_result(Err(ledger::reject()));
+
+        let result = withdraw(CURRENCY.to_string(), admin_account(), 600).await;
+
+        assert!(matches!(result, Err(IPMarketplaceError::OperationFailed)));
+        assert_eq!(withdrawn(), 600);
+        let pending = get_pending_withdrawals();
+        assert_eq!(pending.len(), 1);
+
+        // The first attempt went through after all; the ledger reports the retry
+        // as a duplicate
+        ledger::push_transfer_result(Ok(Err(TransferError::Duplicate { duplicate_of: Nat::from(5u64) })));
+        let block = retry_withdrawal(pending[0].clone()).await.unwrap();
+
+        assert_eq!(block, Nat::from(5u64));
+        assert_eq!(withdrawn(), 600);
+        assert!(get_pending_withdrawals().is_empty());
+        let attempts = ledger::transfers();
+        assert_eq!(attempts[0].created_at_time, attempts[1].created_at_time);
+        assert_eq!(attempts[0].memo, attempts[1].memo);
+        assert!(matches!(
+            withdraw(CURRENCY.to_string(), admin_account(), 600).await,
+            Err(IPMarketplaceError::InsufficientFunds)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_withdrawal_releases_reservation() {
+        fund_treasury(1_000);
+        ledger::push_transfer_result(Ok(Err(TransferError::TemporarilyUnavailable)));
+
+        let result = withdraw(CURRENCY.to_string(), admin_account(), 600).await;
+
+        assert!(matches!(result, Err(IPMarketplaceError::OperationFailed)));
+        assert_eq!(withdrawn(), 0);
+        assert!(get_pending_withdrawals().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_retry_stays_pending() {
+        fund_treasury(1_000);
+        ledger::push_transfer_result(Err(ledger::reject()));
+        let _ = withdraw(CURRENCY.to_string(), admin_account(), 600).await;
+
+        ledger::push_transfer_result(Ok(Err(TransferError::TemporarilyUnavailable)));
+        let pending = get_pending_withdrawals().remove(0);
+        assert!(retry_withdrawal(pending).await.is_err());
+
+        assert_eq!(withdrawn(), 600);
+        assert_eq!(get_pending_withdrawals()[0].attempts, 2);
+    }
+}
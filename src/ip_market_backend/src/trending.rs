@@ -0,0 +1,288 @@
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::time::Duration;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::access_control::require_admin;
+
+// Trending scores. Each counted view, favorite, bid and sale adds its weight to
+// the NFT's score and to its collection's, and scores halve every
+// trending_half_life_nanos. A score is stored as of its last activity and
+// decayed when read, so nothing is rescored as time passes; queries walk an
+// index of ranks instead of sorting every score. Anything idle for longer than
+// trending_window_nanos drops out and is pruned.
+
+pub const VIEW_WEIGHT: f64 = 1.0;
+pub const FAVORITE_WEIGHT: f64 = 3.0;
+pub const BID_WEIGHT: f64 = 5.0;
+pub const SALE_WEIGHT: f64 = 10.0;
+
+pub const MAX_TRENDING_RESULTS: usize = 100;
+
+// How often idle scores are pruned
+pub const TRENDING_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Scores of each map looked at per prune
+const TRENDING_PRUNE_BATCH_SIZE: usize = 1000;
+
+fn decayed(score: &TrendingScore, now: u64, half_life_nanos: u64) -> f64 {
+    let elapsed = now.saturating_sub(score.updated_at) as f64;
+    score.score * 0.5f64.powf(elapsed / half_life_nanos as f64)
+}
+
+fn bumped(previous: Option<TrendingScore>, weight: f64, now: u64, half_life_nanos: u64) -> TrendingScore {
+    let carried = previous.map_or(0.0, |score| decayed(&score, now, half_life_nanos));
+    TrendingScore {
+        score: carried + weight,
+        updated_at: now,
+    }
+}
+
+// Where a score sorts among all others at any moment. Decay scales every score
+// by the same factor, so log2(score) + updated_at / half-life orders scores by
+// their current value without rescoring; the f64 is mapped to bits that sort
+// the same way as unsigned integers.
+fn rank(score: &TrendingScore, half_life_nanos: u64) -> u64 {
+    let key = score.score.log2() + score.updated_at as f64 / half_life_nanos as f64;
+    let bits = key.to_bits();
+    if key.is_sign_negative() {
+        !bits
+    } else {
+        bits | 1 << 63
+    }
+}
+
+// The two sets of scores, each kept alongside an index of its ranks
+#[derive(Clone, Copy)]
+enum Board {
+    Nfts,
+    Collections,
+}
+
+impl Board {
+    fn scores<R>(self, f: impl FnOnce(&StableBTreeMap<String, TrendingScore, Memory>) -> R) -> R {
+        match self {
+            Board::Nfts => with_trending_nfts(f),
+            Board::Collections => with_trending_collections(f),
+        }
+    }
+
+    fn scores_mut<R>(self, f: impl FnOnce(&mut StableBTreeMap<String, TrendingScore, Memory>) -> R) -> R {
+        match self {
+            Board::Nfts => with_trending_nfts_mut(f),
+            Board::Collections => with_trending_collections_mut(f),
+        }
+    }
+
+    fn ranks<R>(self, f: impl FnOnce(&StableBTreeMap<(u64, IdKey), (), Memory>) -> R) -> R {
+        match self {
+            Board::Nfts => with_trending_nft_ranks(f),
+            Board::Collections => with_trending_collection_ranks(f),
+        }
+    }
+
+    fn ranks_mut<R>(self, f: impl FnOnce(&mut StableBTreeMap<(u64, IdKey), (), Memory>) -> R) -> R {
+        match self {
+            Board::Nfts => with_trending_nft_ranks_mut(f),
+            Board::Collections => with_trending_collection_ranks_mut(f),
+        }
+    }
+
+    fn bump(self, key: String, weight: f64, now: u64, half_life_nanos: u64) {
+        let previous = self.scores(|scores| scores.get(&key));
+        if let Some(previous) = &previous {
+            self.ranks_mut(|ranks| ranks.remove(&(rank(previous, half_life_nanos), IdKey(key.clone()))));
+        }
+        let score = bumped(previous, weight, now, half_life_nanos);
+        self.ranks_mut(|ranks| ranks.insert((rank(&score, half_life_nanos), IdKey(key.clone())), ()));
+        self.scores_mut(|scores| scores.insert(key, score));
+    }
+
+    fn remove(self, key: &str, half_life_nanos: u64) {
+        if let Some(previous) = self.scores_mut(|scores| scores.remove(&key.to_string())) {
+            self.ranks_mut(|ranks| ranks.remove(&(rank(&previous, half_life_nanos), IdKey(key.to_string()))));
+        }
+    }
+
+    // Highest current scores first, walking the rank index down and skipping
+    // anything idle past the window
+    fn top(self, limit: usize) -> Vec<(String, f64)> {
+        let now = time();
+        let (half_life_nanos, window_nanos) = with_marketplace_config(|config| {
+            (config.trending_half_life_nanos, config.trending_window_nanos)
+        });
+
+        self.ranks(|ranks| {
+            self.scores(|scores| {
+                ranks
+                    .iter()
+                    .rev()
+                    .filter_map(|((_, IdKey(key)), ())| {
+                        let score = scores.get(&key)?;
+                        (now < score.updated_at.saturating_add(window_nanos))
+                            .then(|| (key, decayed(&score, now, half_life_nanos)))
+                    })
+                    .take(limit.min(MAX_TRENDING_RESULTS))
+                    .collect()
+            })
+        })
+    }
+
+    fn rebuild_ranks(self, half_life_nanos: u64) {
+        let ranked: Vec<(u64, IdKey)> = self.scores(|scores| {
+            scores
+                .iter()
+                .map(|(key, score)| (rank(&score, half_life_nanos), IdKey(key)))
+                .collect()
+        });
+        self.ranks_mut(|ranks| {
+            ranks.clear_new();
+            for entry in ranked {
+                ranks.insert(entry, ());
+            }
+        });
+    }
+}
+
+// Adds activity on an NFT to its score and its collection's
+pub fn record_activity(nft_id: &str, weight: f64) {
+    let now = time();
+    let half_life_nanos = with_marketplace_config(|config| config.trending_half_life_nanos);
+    let collection_name = with_nft_registry(|registry| registry.get(&nft_id.to_string()))
+        .and_then(|nft| nft.collection_name);
+
+    Board::Nfts.bump(nft_id.to_string(), weight, now, half_life_nanos);
+    if let Some(collection_name) = collection_name {
+        Board::Collections.bump(collection_name, weight, now, half_life_nanos);
+    }
+}
+
+pub fn remove_trending_nft(nft_id: &str) {
+    let half_life_nanos = with_marketplace_config(|config| config.trending_half_life_nanos);
+    Board::Nfts.remove(nft_id, half_life_nanos);
+}
+
+// Ranks depend on the half-life, so both indexes are rebuilt when it changes.
+// Also run once as a migration to index scores stored before ranks existed.
+pub fn rebuild_trending_ranks() {
+    let half_life_nanos = with_marketplace_config(|config| config.trending_half_life_nanos);
+    Board::Nfts.rebuild_ranks(half_life_nanos);
+    Board::Collections.rebuild_ranks(half_life_nanos);
+}
+
+#[query]
+pub fn get_trending_nfts(limit: usize) -> Vec<IPNft> {
+    let ranked = Board::Nfts.top(limit);
+    with_nft_registry(|registry| {
+        ranked.iter().filter_map(|(nft_id, _)| registry.get(nft_id)).collect()
+    })
+}
+
+#[query]
+pub fn get_trending_collections(limit: usize) -> Vec<TrendingCollection> {
+    Board::Collections
+        .top(limit)
+        .into_iter()
+        .map(|(collection_name, score)| TrendingCollection { collection_name, score })
+        .collect()
+}
+
+#[update]
+pub fn set_trending_params(half_life_nanos: u64, window_nanos: u64) -> Result<MarketplaceConfig> {
+    require_admin()?;
+
+    if half_life_nanos == 0 || window_nanos == 0 {
+        return Err(IPMarketplaceError::InvalidInput);
+    }
+
+    let (config, half_life_changed) = with_marketplace_config_mut(|config| {
+        let half_life_changed = config.trending_half_life_nanos != half_life_nanos;
+        config.trending_half_life_nanos = half_life_nanos;
+        config.trending_window_nanos = window_nanos;
+        (config.clone(), half_life_changed)
+    });
+    if half_life_changed {
+        rebuild_trending_ranks();
+    }
+    Ok(config)
+}
+
+// Removes scores idle for longer than the window, scanning a bounded slice of
+// each map per call
+pub fn prune_trending() {
+    let now = time();
+    let (half_life_nanos, window_nanos) = with_marketplace_config(|config| {
+        (config.trending_half_life_nanos, config.trending_window_nanos)
+    });
+    let is_idle = |score: &TrendingScore| now >= score.updated_at.saturating_add(window_nanos);
+
+    let cursor = with_prune_cursors_mut(|cursors| cursors.trending_nfts.take());
+    let (idle_nfts, next) = Board::Nfts.scores(|scores| {
+        scan_expired(scores, cursor, TRENDING_PRUNE_BATCH_SIZE, |_, score| is_idle(score))
+    });
+    for nft_id in idle_nfts {
+        Board::Nfts.remove(&nft_id, half_life_nanos);
+    }
+    with_prune_cursors_mut(|cursors| cursors.trending_nfts = next);
+
+    let cursor = with_prune_cursors_mut(|cursors| cursors.trending_collections.take());
+    let (idle_collections, next) = Board::Collections.scores(|scores| {
+        scan_expired(scores, cursor, TRENDING_PRUNE_BATCH_SIZE, |_, score| is_idle(score))
+    });
+    for collection_name in idle_collections {
+        Board::Collections.remove(&collection_name, half_life_nanos);
+    }
+    with_prune_cursors_mut(|cursors| cursors.trending_collections = next);
+}
+
+pub fn start_trending_pruner() {
+    ic_cdk_timers::set_timer_interval(TRENDING_PRUNE_INTERVAL, prune_trending);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use crate::runtime::mock::{set_time, START_TIME};
+    use crate::test_fixtures::mint_test_nft;
+
+    fn trending_ids(limit: usize) -> Vec<String> {
+        get_trending_nfts(limit).into_iter().map(|nft| nft.id).collect()
+    }
+
+    #[test]
+    fn trending_follows_decayed_scores() {
+        let user = Principal::from_slice(&[1]);
+        for (i, nft_id) in ["NFT_A", "NFT_B", "NFT_C"].into_iter().enumerate() {
+            mint_test_nft(nft_id, i as u64 + 1, user, user);
+        }
+        with_marketplace_config_mut(|config| config.trending_half_life_nanos = 1000);
+
+        set_time(START_TIME);
+        record_activity("NFT_A", SALE_WEIGHT);
+        set_time(START_TIME + 1000);
+        record_activity("NFT_B", FAVORITE_WEIGHT);
+        assert_eq!(trending_ids(10), ["NFT_A", "NFT_B"]);
+
+        // A has decayed to 2.5 and B to 1.5, below C's fresh 3
+        set_time(START_TIME + 2000);
+        record_activity("NFT_C", FAVORITE_WEIGHT);
+        assert_eq!(trending_ids(10), ["NFT_C", "NFT_A", "NFT_B"]);
+        assert_eq!(trending_ids(1), ["NFT_C"]);
+
+        // Bumping B carries its decayed score forward
+        record_activity("NFT_B", FAVORITE_WEIGHT);
+        assert_eq!(trending_ids(10), ["NFT_B", "NFT_C", "NFT_A"]);
+
+        // A slower half-life leaves A's sale ahead
+        with_marketplace_config_mut(|config| config.trending_half_life_nanos = 100_000);
+        rebuild_trending_ranks();
+        assert_eq!(trending_ids(10), ["NFT_A", "NFT_B", "NFT_C"]);
+
+        remove_trending_nft("NFT_A");
+        assert_eq!(trending_ids(10), ["NFT_B", "NFT_C"]);
+        assert_eq!(with_trending_nft_ranks(|ranks| ranks.len()), 2);
+    }
+}
@@ -1,8 +1,11 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use serde::{Deserialize, Serialize};
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::Storable;
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::migrations::{decode_versioned, encode_versioned};
 
 // Enhanced NFT Metadata structure following ERC-721 and ERC-1155 standards
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -69,7 +72,12 @@ pub struct IntellectualProperty {
     pub registration_date: u64,
     pub metadata: IPMetadata,
     pub verification_status: VerificationStatus,
-    pub nft_id: Option<String>,
+    // Every edition minted from this IP and not burned, in edition order
+    pub nft_ids: Vec<String>,
+    // Supply cap, fixed by the first mint
+    pub total_editions: Option<u32>,
+    // Editions ever minted, including burned ones; burning does not free supply
+    pub minted_editions: u32,
     // Enhanced fields for NFT creation
     pub image_url: Option<String>,
     pub additional_files: Vec<FileMetadata>,
@@ -85,7 +93,7 @@ pub struct FileMetadata {
     pub uploaded_at: u64,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum IPType {
     Patent,
     Trademark,
@@ -120,7 +128,7 @@ pub struct IPMetadata {
     pub software_used: Vec<String>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum VerificationStatus {
     Pending,
     Verified,
@@ -135,6 +143,7 @@ pub struct IPNft {
     pub ip_id: String,
     pub token_id: u64,
     pub owner: Principal,
+    pub owner_subaccount: Option<Vec<u8>>, // ICRC-7 subaccount of the owner, if not the default
     pub creator: Principal,
     pub metadata_uri: String,
     pub minted_at: u64,
@@ -213,6 +222,15 @@ pub struct AuctionData {
     pub min_bid_increment: u64,
 }
 
+// A bid on an auction, kept until the auction settles so a winner who cannot
+// pay can be passed over for the next-highest bidder
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Bid {
+    pub bidder: Principal,
+    pub amount: u64,
+    pub placed_at: u64,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum ListingStatus {
     Active,
@@ -252,7 +270,8 @@ pub struct MintNFTRequest {
     pub image: String,
     pub attributes: Vec<NFTAttribute>,
     pub collection_name: Option<String>,
-    pub edition_number: Option<u32>,
+    // Required to match the IP's cap once set; defaults to the number of
+    // editions in the first mint
     pub total_editions: Option<u32>,
     pub royalty_percentage: Option<u8>,
     pub external_url: Option<String>,
@@ -298,7 +317,227 @@ pub struct NFTSearchFilters {
     pub min_price: Option<u64>,
     pub max_price: Option<u64>,
     pub creator: Option<Principal>,
-    pub sort_by: Option<String>, // "price_asc", "price_desc", "newest", "oldest", "rarity"
+    pub sort_by: Option<String>, // "price_asc", "price_desc", "newest", "oldest", "rarity", "relevance"
+}
+
+// A search result with its relevance to the query text; 0 when the query has
+// no searchable terms
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IPSearchHit {
+    pub ip: IntellectualProperty,
+    pub score: f64,
+}
+
+// Inclusive bounds on a timestamp, in nanoseconds
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DateRange {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+// Structured IP search. Text fields match ignoring case and surrounding
+// whitespace; unset filters match everything.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IPSearchFilters {
+    pub query: Option<String>,
+    pub ip_type: Option<IPType>,
+    pub category: Option<String>,
+    pub tags_any: Vec<String>, // at least one of these tags
+    pub tags_all: Vec<String>, // every one of these tags
+    pub jurisdiction: Option<String>,
+    pub verification_status: Option<VerificationStatus>,
+    pub created: Option<DateRange>,
+    pub registered: Option<DateRange>,
+    pub expires: Option<DateRange>, // IPs without an expiry date never match
+    pub has_nft: Option<bool>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IPTypeFacetCount {
+    pub ip_type: IPType,
+    pub count: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct VerificationFacetCount {
+    pub status: VerificationStatus,
+    pub count: u64,
+}
+
+// Matching IPs per value of each dimension, counted under every filter except
+// the dimension's own, so each count is what selecting that value would return
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IPSearchFacets {
+    pub ip_types: Vec<IPTypeFacetCount>,
+    pub categories: Vec<FacetCount>,
+    pub tags: Vec<FacetCount>,
+    pub jurisdictions: Vec<FacetCount>,
+    pub verification_statuses: Vec<VerificationFacetCount>,
+    pub with_nft: u64,
+    pub without_nft: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IPSearchResults {
+    pub hits: Vec<IPSearchHit>,
+    pub total_count: u64, // matches across all pages
+    pub facets: IPSearchFacets,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NFTSearchHit {
+    pub nft: IPNft,
+    pub score: f64,
+}
+
+// Tokens indexed per corpus, for the average document length in BM25 scoring.
+// Heap only.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TextIndexTotals {
+    pub ip_tokens: u64,
+    pub nft_tokens: u64,
+}
+
+// One page of search_nfts results. Pass next_cursor back to get the next page;
+// it is None on the last page.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NFTSearchPage {
+    pub nfts: Vec<NFTSearchHit>,
+    pub total_count: u64, // matches across all pages
+    pub next_cursor: Option<String>,
+}
+
+// A named collection NFTs can be minted into. Only the owner and the allowed
+// minters may mint into it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Collection {
+    pub id: String,
+    pub name: String, // unique ignoring case; stored on each NFT as collection_name
+    pub symbol: String,
+    pub owner: Principal,
+    pub description: String,
+    pub banner_url: Option<String>,
+    pub default_royalty_percentage: Option<u8>, // used when a mint gives none
+    pub max_supply: Option<u32>,
+    pub allowed_minters: Vec<Principal>, // besides the owner
+    pub minted: u32, // editions minted or reserved by batches, including burned ones
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Live size of a collection name and the first NFT minted under it, kept as NFTs
+// are minted and burned so collection metadata never loads the collection
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CollectionSupply {
+    pub total_supply: u32, // NFTs not burned
+    pub first_image: String, // of the first NFT minted, even if since burned
+    pub first_royalty_percentage: u8,
+    pub first_creator: Principal,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct CreateCollectionRequest {
+    pub name: String,
+    pub symbol: String,
+    pub description: String,
+    pub banner_url: Option<String>,
+    pub default_royalty_percentage: Option<u8>,
+    pub max_supply: Option<u32>,
+    pub allowed_minters: Vec<Principal>,
+}
+
+// Fields left as None are unchanged. The name cannot change, since NFTs refer
+// to the collection by name.
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct UpdateCollectionRequest {
+    pub symbol: Option<String>,
+    pub description: Option<String>,
+    pub banner_url: Option<String>,
+    pub default_royalty_percentage: Option<u8>,
+    pub max_supply: Option<u32>,
+    pub allowed_minters: Option<Vec<Principal>>,
+}
+
+// A trending score as of updated_at; it decays from there until the next
+// activity
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TrendingScore {
+    pub score: f64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TrendingCollection {
+    pub collection_name: String,
+    pub score: f64, // decayed to the time of the query
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ViewBucket {
+    pub bucket_start: u64, // nanoseconds
+    pub views: u64,
+}
+
+// Where each pruning timer resumes its scan; None starts from the beginning
+#[derive(Clone, Debug, Default)]
+pub struct PruneCursors {
+    pub last_views: Option<(Principal, IdKey)>,
+    pub view_buckets: Option<(IdKey, u64)>,
+    pub trending_nfts: Option<String>,
+    pub trending_collections: Option<String>,
+}
+
+// Trait tallies for one collection, used to score rarity
+#[derive(Clone, Debug, Default)]
+pub struct CollectionTraits {
+    pub size: u32, // NFTs in the collection
+    pub values: BTreeMap<String, BTreeMap<String, u32>>, // trait_type -> value -> NFTs
+    pub trait_counts: BTreeMap<u32, u32>, // number of traits -> NFTs
+    pub members: BTreeMap<String, (u64, BTreeMap<String, String>)>, // nft_id -> (token_id, trait_type -> value)
+}
+
+// A rank and score waiting to be written to an NFT
+#[derive(Clone, Debug)]
+pub struct RarityWrite {
+    pub nft_id: String,
+    pub rank: u32,
+    pub score: f64,
+}
+
+// Collections whose ranks are out of date, and the writes of the one being refreshed
+#[derive(Clone, Debug, Default)]
+pub struct RarityRefresh {
+    pub dirty: BTreeSet<String>,
+    pub writes: VecDeque<RarityWrite>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TraitFrequency {
+    pub trait_type: String,
+    pub value: String,
+    pub count: u32,
+    pub frequency: f64, // share of the collection's NFTs, 0.0 to 1.0
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TraitCountFrequency {
+    pub trait_count: u32,
+    pub count: u32,
+    pub frequency: f64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CollectionTraitReport {
+    pub collection_name: String,
+    pub total_supply: u32,
+    pub traits: Vec<TraitFrequency>,
+    pub trait_counts: Vec<TraitCountFrequency>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -322,100 +561,970 @@ pub struct MarketplaceStats {
     pub average_sale_price: Option<u64>,
 }
 
-// Error types
-#[derive(CandidType, Serialize, Deserialize, Debug)]
-pub enum IPMarketplaceError {
-    NotFound,
-    Unauthorized,
-    AlreadyExists,
-    InvalidInput,
-    InsufficientFunds,
-    OperationFailed,
-    NotImplemented,
-    InvalidFileFormat,
-    FileTooLarge,
-    AuctionEnded,
-    BidTooLow,
-    NFTNotTransferable,
+// Monotonic ID sequences, one per entity kind (keyed by ID prefix, e.g. "IP", "NFT")
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IdCounters {
+    pub counters: BTreeMap<String, u64>,
 }
 
-pub type Result<T> = std::result::Result<T, IPMarketplaceError>;
+// Access control
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Owner,     // manages every role, including other owners
+    Admin,     // platform settings, grants Verifier/Moderator
+    Verifier,  // reviews IP registrations
+    Moderator, // user reputation and listing cleanup
+}
 
-// Storable implementations
-impl Storable for IntellectualProperty {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleGrant {
+    pub role: Role,
+    pub granted_by: Principal,
+    pub granted_at: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoleAssignments {
+    pub grants: Vec<RoleGrant>,
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleHolder {
+    pub principal: Principal,
+    pub grants: Vec<RoleGrant>,
 }
 
-impl Storable for IPNft {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    pub owners: Vec<Principal>, // defaults to the installing principal when empty
+    pub admins: Vec<Principal>,
+    pub verifiers: Vec<Principal>,
+    pub moderators: Vec<Principal>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
-    }
+// Payments: each listing currency settles on a configured ICRC-1/ICRC-2 ledger
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CurrencyLedger {
+    pub currency: String,
+    pub ledger_canister_id: Principal,
+    pub configured_by: Principal,
+    pub configured_at: u64,
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+// ICRC-1 account
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>, // 32 bytes when present
 }
 
-impl Storable for UserProfile {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
+// ICRC-2 icrc2_transfer_from arguments and errors
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap_or_else(|_| {
-            // Return a default UserProfile if decoding fails
-            UserProfile {
-                principal: Principal::anonymous(),
-                username: "unknown".to_string(),
-                email: None,
-                bio: None,
-                reputation_score: 0,
-                verified: false,
-                created_at: 0,
-                owned_ips: Vec::new(),
-                owned_nfts: Vec::new(),
-                avatar_url: None,
-                banner_url: None,
-                social_links: Vec::new(),
-                total_sales: 0,
-                total_purchases: 0,
-            }
-        })
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+// ICRC-2 icrc2_allowance arguments and result
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
 }
 
-impl Storable for MarketplaceListing {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
-    }
+// ICRC-1 icrc1_transfer arguments and errors
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
 }
 
-impl Storable for NFTMetadata {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
+// Sale proceeds held in the marketplace account until sent to their recipient
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PayoutKind {
+    SellerProceeds,
+    Royalty,
+    // Returns a buyer's payment when the sale could not complete
+    Refund,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingPayout {
+    pub id: String,
+    pub kind: PayoutKind,
+    pub recipient: Principal,
+    pub currency: String,
+    pub amount: u64, // gross; the recipient receives it less `fee`
+    pub fee: Option<u64>, // ledger fee when queued; None only for payouts queued before it was kept
+    pub listing_id: String,
+    pub nft_id: String,
+    pub created_at: u64, // also the ICRC-1 created_at_time, so retries deduplicate
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub stalled_at: Option<u64>, // set on TooOld; no longer retried until resolve_pending_payout
+}
+
+// Royalties earned by a creator, per currency
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoyaltyBalance {
+    pub currency: String,
+    pub accrued: u64, // earned on settled sales
+    pub paid: u64,    // received by the creator, net of ledger fees
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoyaltyLedger {
+    pub creator: Principal,
+    pub balances: Vec<RoyaltyBalance>,
+    pub sales_count: u64,
+    pub updated_at: u64,
+}
+
+// Platform-wide settings adjustable by admins
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MarketplaceConfig {
+    pub max_royalty_percentage: u8,
+    pub platform_fee_bps: u16, // basis points of each sale price kept by the treasury
+    pub max_file_size_bytes: u64, // largest single on-chain asset
+    pub storage_quota_bytes: u64, // on-chain asset bytes each user may store
+    pub trending_half_life_nanos: u64, // activity loses half its trending weight this often
+    pub trending_window_nanos: u64, // items with no activity for this long drop out of trending
+}
+
+impl Default for MarketplaceConfig {
+    fn default() -> Self {
+        MarketplaceConfig {
+            max_royalty_percentage: 25,
+            platform_fee_bps: 0,
+            max_file_size_bytes: 50 * 1024 * 1024,
+            storage_quota_bytes: 500 * 1024 * 1024,
+            trending_half_life_nanos: 24 * 3600 * 1_000_000_000,
+            trending_window_nanos: 7 * 24 * 3600 * 1_000_000_000,
+        }
     }
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+// Platform fees collected per currency; the funds stay in the marketplace account
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TreasuryBalance {
+    pub currency: String,
+    pub collected: u64,
+    pub withdrawn: u64,
+    pub updated_at: u64,
+}
+
+// A treasury withdrawal whose ledger transfer has not been confirmed. Its amount
+// stays reserved, and every attempt reuses the same created_at_time and memo so
+// the ledger deduplicates a transfer that already went through.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingWithdrawal {
+    pub id: String,
+    pub currency: String,
+    pub to: Account,
+    pub amount: u64, // reserved from the treasury; the recipient receives it less `fee`
+    pub fee: u64,
+    pub created_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FeeChangeRecord {
+    pub id: u64,
+    pub previous_bps: u16,
+    pub new_bps: u16,
+    pub changed_by: Principal,
+    pub changed_at: u64,
+}
+
+// How a sale price was divided
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SaleBreakdown {
+    pub listing_id: String,
+    pub nft_id: String,
+    pub currency: String,
+    pub price: u64,
+    pub platform_fee: u64,
+    pub royalty: u64,
+    pub seller_proceeds: u64,
+    pub transaction_hash: Option<String>, // ledger block index of the buyer's payment
+}
+
+// ICRC-7 / ICRC-3 generic value
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+// ICRC-7 icrc7_transfer arguments and errors
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Icrc7TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type Icrc7TransferResult = std::result::Result<Nat, Icrc7TransferError>;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+// ICRC-37 approvals
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+// Approvals on one token, granted by whoever owned it at the time
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TokenApprovals {
+    pub token_id: u64,
+    pub approvals: Vec<ApprovalInfo>,
+}
+
+// Approvals covering every token held by `owner`; they survive transfers
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CollectionApprovals {
+    pub owner: Principal,
+    pub approvals: Vec<ApprovalInfo>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApproveTokenArg {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveTokenResult = std::result::Result<Nat, ApproveTokenError>;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveCollectionResult = std::result::Result<Nat, ApproveCollectionError>;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevokeTokenApprovalArg {
+    pub spender: Option<Account>, // None revokes every approval on the token
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeTokenApprovalResult = std::result::Result<Nat, RevokeTokenApprovalError>;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevokeCollectionApprovalArg {
+    pub spender: Option<Account>, // None revokes every collection approval
+    pub from_subaccount: Option<Vec<u8>>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeCollectionApprovalResult = std::result::Result<Nat, RevokeCollectionApprovalError>;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Icrc37TransferFromArg {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum Icrc37TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type Icrc37TransferFromResult = std::result::Result<Nat, Icrc37TransferFromError>;
+
+// ICRC-3 block log
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GetBlocksRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksRequest>) -> (GetBlocksResult) query);
+
+// Always empty: the log is never archived to other canisters
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksRequest>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>, // CBOR-encoded hash tree
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+// HTTP gateway interface
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+// Identifies the next chunk of an asset streamed over several responses
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackToken {
+    pub asset_id: u64,
+    pub chunk_index: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+candid::define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+// On-chain asset storage
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AssetStatus {
+    Uploading,
+    Complete,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StoredAsset {
+    pub id: u64,
+    pub owner: Principal,
+    pub file_name: String,
+    pub content_type: String,
+    pub file_size: u64,
+    pub file_hash: String, // hex SHA-256 declared by the uploader, verified on finalize
+    pub chunk_size: u64,
+    pub chunk_count: u32,
+    pub chunks_received: u32,
+    pub status: AssetStatus,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+// Bytes counted against a user's quota, including uploads in progress
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StorageUsage {
+    pub bytes_used: u64,
+    pub asset_count: u32,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BeginAssetUploadRequest {
+    pub file_name: String,
+    pub file_type: String, // MIME type
+    pub file_size: u64,
+    pub file_hash: String, // hex SHA-256 of the full file
+}
+
+// One item of batch_register_and_mint: an IP to register and the editions to
+// mint from it. `nft.ip_id` is ignored in favour of the new IP's ID.
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct BatchMintItem {
+    pub ip: RegisterIPRequest,
+    pub nft: MintNFTRequest,
+    pub editions: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct BatchItemError {
+    pub index: u32, // position in the submitted batch
+    pub error: IPMarketplaceError,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub enum BatchMintError {
+    // The batch was empty or had more than max_items items
+    InvalidBatchSize { max_items: u32 },
+    // Nothing was stored; lists every item that failed validation
+    InvalidItems(Vec<BatchItemError>),
+}
+
+// An item that passed validation, with the values resolved at submission
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct ValidatedBatchItem {
+    pub index: u32,
+    pub item: BatchMintItem,
+    pub total_editions: u32,
+    pub royalty_percentage: u8,
+    pub collection_name: Option<String>, // as stored on the collection
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BatchMintResult {
+    pub index: u32,
+    pub ip_id: String,
+    pub nft_ids: Vec<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum BatchMintStatus {
+    InProgress,
+    Complete,
+}
+
+// A validated batch being committed in chunks
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct BatchMintJob {
+    pub id: String,
+    pub owner: Principal,
+    pub total_items: u32,
+    pub pending: Vec<ValidatedBatchItem>, // not yet committed, in submission order
+    pub results: Vec<BatchMintResult>,
+    pub status: BatchMintStatus,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+// A BatchMintJob as returned to callers, without the pending items
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BatchMintProgress {
+    pub id: String,
+    pub owner: Principal,
+    pub total_items: u32,
+    pub committed_items: u32,
+    pub results: Vec<BatchMintResult>,
+    pub status: BatchMintStatus,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+// Stored bytes of a registry record, left undecoded
+pub struct RawRecord(pub Vec<u8>);
+
+// A string ID inside a composite stable-map key. Tuple keys need bounded parts,
+// so IDs longer than MAX_ID_KEY_SIZE bytes cannot be used.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IdKey(pub String);
+
+pub const MAX_ID_KEY_SIZE: u32 = 128;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RecordVersionCount {
+    pub version: u32,
+    pub count: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SchemaMigrationReport {
+    pub record_kind: String,
+    pub current_version: u32,
+    pub versions: Vec<RecordVersionCount>,
+    pub pending: u64, // records not yet at current_version
+    pub unmigratable: u64, // pending records with no registered migration path
+}
+
+// Error types
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub enum IPMarketplaceError {
+    NotFound,
+    Unauthorized,
+    AlreadyExists,
+    InvalidInput,
+    InsufficientFunds,
+    OperationFailed,
+    NotImplemented,
+    InvalidFileFormat,
+    FileTooLarge,
+    AuctionEnded,
+    BidTooLow,
+    NFTNotTransferable,
+}
+
+pub type Result<T> = std::result::Result<T, IPMarketplaceError>;
+
+// Storable implementations
+impl Storable for IntellectualProperty {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for IPNft {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UserProfile {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MarketplaceListing {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for NFTMetadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RoleAssignments {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CurrencyLedger {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PendingPayout {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RoyaltyLedger {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MarketplaceConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TreasuryBalance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PendingWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CollectionSupply {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Bid {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for FeeChangeRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TokenApprovals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CollectionApprovals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Value {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StoredAsset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for BatchMintJob {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Collection {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TrendingScore {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StorageUsage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for IdCounters {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RawRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawRecord(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for IdKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        if self.0.len() > MAX_ID_KEY_SIZE as usize {
+            ic_cdk::trap(&format!("ID too long for a stable key: {}", self.0));
+        }
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        IdKey(String::from_utf8(bytes.into_owned()).expect("stable key is not UTF-8"))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_ID_KEY_SIZE,
+        is_fixed_size: false,
+    };
 }
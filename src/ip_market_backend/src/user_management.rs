@@ -1,9 +1,10 @@
-use ic_cdk::api::time;
 use ic_cdk::{query, update};
 use candid::Principal;
 
 use crate::types::*;
 use crate::storage::*;
+use crate::runtime::time;
+use crate::access_control::require_moderator;
 
 #[update]
 pub fn create_user_profile(request: CreateUserRequest) -> Result<UserProfile> {
@@ -58,7 +59,8 @@ pub fn get_my_profile() -> Result<UserProfile> {
 
 #[update]
 pub fn update_user_reputation(user: Principal, score_change: i32) -> Result<u32> {
-    // In production, this should check for admin privileges
+    require_moderator()?;
+    
     with_user_registry_mut(|registry| {
         if let Some(mut user_profile) = registry.get(&user) {
             if score_change < 0 && (-score_change) as u32 > user_profile.reputation_score {
@@ -0,0 +1,142 @@
+use ic_cdk::{query, update};
+use candid::Principal;
+use std::time::Duration;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::runtime::time;
+use crate::trending::{record_activity, VIEW_WEIGHT};
+
+// View counting. A signed-in user's view of an NFT counts at most once per
+// VIEW_DEDUP_WINDOW_NANOS and anonymous views are not counted, so repeated calls
+// cannot inflate an NFT. Counted views go into hourly buckets kept for
+// VIEW_RETENTION_NANOS, so rankings can look at recent activity; view_count on
+// the NFT stays the all-time total.
+
+const HOUR_NANOS: u64 = 3600 * 1_000_000_000;
+pub const VIEW_DEDUP_WINDOW_NANOS: u64 = HOUR_NANOS;
+pub const VIEW_BUCKET_NANOS: u64 = HOUR_NANOS;
+pub const VIEW_RETENTION_NANOS: u64 = 30 * 24 * HOUR_NANOS;
+
+// How often expired dedup entries and buckets are pruned
+pub const VIEW_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Entries of each map looked at per prune
+const VIEW_PRUNE_BATCH_SIZE: usize = 1000;
+
+fn bucket_start(timestamp: u64) -> u64 {
+    timestamp - timestamp % VIEW_BUCKET_NANOS
+}
+
+// Returns the NFT's view count, which only changes if this view counts
+#[update]
+pub fn increment_nft_view(nft_id: String) -> Result<u64> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    let mut nft = with_nft_registry(|registry| {
+        registry.get(&nft_id)
+    }).ok_or(IPMarketplaceError::NotFound)?;
+
+    if caller == Principal::anonymous() {
+        return Ok(nft.view_count);
+    }
+
+    let dedup_key = (caller, IdKey(nft_id.clone()));
+    let last_counted = with_last_views(|views| views.get(&dedup_key));
+    if last_counted.is_some_and(|at| now < at + VIEW_DEDUP_WINDOW_NANOS) {
+        return Ok(nft.view_count);
+    }
+    with_last_views_mut(|views| views.insert(dedup_key, now));
+
+    let bucket_key = (IdKey(nft_id.clone()), bucket_start(now));
+    with_view_buckets_mut(|buckets| {
+        let views = buckets.get(&bucket_key).unwrap_or(0);
+        buckets.insert(bucket_key, views + 1);
+    });
+
+    nft.view_count += 1;
+    let new_count = nft.view_count;
+    insert_nft(nft);
+    record_activity(&nft_id, VIEW_WEIGHT);
+    Ok(new_count)
+}
+
+fn buckets_since(nft_id: &str, since: u64) -> Vec<ViewBucket> {
+    let start = (IdKey(nft_id.to_string()), bucket_start(since));
+    with_view_buckets(|buckets| {
+        buckets
+            .range(start..)
+            .take_while(|((id, _), _)| id.0 == nft_id)
+            .map(|((_, bucket_start), views)| ViewBucket { bucket_start, views })
+            .collect()
+    })
+}
+
+#[query]
+pub fn get_nft_view_history(nft_id: String, since: Option<u64>) -> Vec<ViewBucket> {
+    let since = since.unwrap_or_else(|| time().saturating_sub(VIEW_RETENTION_NANOS));
+    buckets_since(&nft_id, since)
+}
+
+// Removes dedup entries past the window and buckets past retention, scanning a
+// bounded slice of each map per call
+pub fn prune_views() {
+    let now = time();
+
+    let cursor = with_prune_cursors_mut(|cursors| cursors.last_views.take());
+    let (expired_views, next) = with_last_views(|views| {
+        scan_expired(views, cursor, VIEW_PRUNE_BATCH_SIZE, |_, at| now >= at + VIEW_DEDUP_WINDOW_NANOS)
+    });
+    with_last_views_mut(|views| {
+        for key in expired_views {
+            views.remove(&key);
+        }
+    });
+    with_prune_cursors_mut(|cursors| cursors.last_views = next);
+
+    let cutoff = bucket_start(now.saturating_sub(VIEW_RETENTION_NANOS));
+    let cursor = with_prune_cursors_mut(|cursors| cursors.view_buckets.take());
+    let (expired_buckets, next) = with_view_buckets(|buckets| {
+        scan_expired(buckets, cursor, VIEW_PRUNE_BATCH_SIZE, |(_, bucket_start), _| *bucket_start < cutoff)
+    });
+    with_view_buckets_mut(|buckets| {
+        for key in expired_buckets {
+            buckets.remove(&key);
+        }
+    });
+    with_prune_cursors_mut(|cursors| cursors.view_buckets = next);
+}
+
+pub fn start_view_pruner() {
+    ic_cdk_timers::set_timer_interval(VIEW_PRUNE_INTERVAL, prune_views);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{set_time, START_TIME};
+
+    fn viewer(n: u32) -> Principal {
+        Principal::from_slice(&n.to_be_bytes())
+    }
+
+    #[test]
+    fn pruning_resumes_where_the_last_batch_stopped() {
+        let total = VIEW_PRUNE_BATCH_SIZE as u32 + 5;
+        with_last_views_mut(|views| {
+            for n in 0..total {
+                views.insert((viewer(n), IdKey("NFT_1".to_string())), START_TIME);
+            }
+            views.insert((viewer(total), IdKey("NFT_1".to_string())), START_TIME + VIEW_DEDUP_WINDOW_NANOS);
+        });
+        set_time(START_TIME + VIEW_DEDUP_WINDOW_NANOS);
+
+        prune_views();
+        assert_eq!(with_last_views(|views| views.len()), 6);
+
+        prune_views();
+        assert_eq!(with_last_views(|views| views.len()), 1);
+        assert!(with_last_views(|views| views.contains_key(&(viewer(total), IdKey("NFT_1".to_string())))));
+    }
+}
@@ -16,6 +16,14 @@ export const useIPService = () => {
     return await actor.search_ips(query, ipType ? [ipType] : []);
   };
 
+  const facetedSearchIPs = async (filters, offset = 0, take = 24) => {
+    return await actor.faceted_search_ips(
+      filters,
+      [BigInt(offset)],
+      [BigInt(take)],
+    );
+  };
+
   const getIPById = async (ipId) => {
     return await actor.get_ip_by_id(ipId);
   };
@@ -67,8 +75,13 @@ export const useIPService = () => {
     return await actor.get_trending_nfts(limit);
   };
 
-  const searchNFTs = async (query, filters) => {
-    return await actor.search_nfts(query, filters);
+  const searchNFTs = async (query, filters, cursor = null, limit = 24) => {
+    return await actor.search_nfts(
+      query,
+      filters,
+      cursor ? [cursor] : [],
+      [BigInt(limit)],
+    );
   };
 
   const incrementNFTView = async (nftId) => {
@@ -147,6 +160,7 @@ export const useIPService = () => {
     // IP Management
     getUserIPs,
     searchIPs,
+    facetedSearchIPs,
     getIPById,
 
     // NFT Management
@@ -31,7 +31,7 @@ const IPSearch = () => {
     try {
       const ipTypeFilter = ipType ? { [ipType]: null } : null;
       const searchResults = await searchIPs(searchQuery, ipTypeFilter);
-      setResults(searchResults);
+      setResults(searchResults.map((hit) => hit.ip));
     } catch (error) {
       console.error("Search error:", error);
       setResults([]);
@@ -223,7 +223,7 @@ const IPSearch = () => {
                     <button className="flex-1 bg-blue-600 text-white py-2 px-3 rounded text-sm hover:bg-blue-700 transition-colors">
                       View Details
                     </button>
-                    {ip.nft_id && (
+                    {ip.nft_ids.length > 0 && (
                       <button className="flex-1 bg-green-600 text-white py-2 px-3 rounded text-sm hover:bg-green-700 transition-colors">
                         View NFT
                       </button>
@@ -8,6 +8,8 @@ const NFTExplorer = () => {
   const { searchNFTs, getTrendingNFTs } = useIPService();
 
   const [nfts, setNfts] = useState([]);
+  const [totalCount, setTotalCount] = useState(0);
+  const [nextCursor, setNextCursor] = useState(null);
   const [loading, setLoading] = useState(true);
   const [searchQuery, setSearchQuery] = useState("");
   const [filters, setFilters] = useState({
@@ -22,14 +24,16 @@ const NFTExplorer = () => {
     loadNFTs();
   }, [activeTab]);
 
-  const loadNFTs = async () => {
+  const loadNFTs = async (cursor = null) => {
     try {
-      setLoading(true);
+      if (!cursor) setLoading(true);
       let result;
 
       if (activeTab === "trending") {
         result = await getTrendingNFTs(20);
         setNfts(result);
+        setTotalCount(result.length);
+        setNextCursor(null);
       } else {
         // Load all NFTs or search results
         const searchFilters = {
@@ -46,8 +50,16 @@ const NFTExplorer = () => {
           sort_by: filters.sort_by ? [filters.sort_by] : [],
         };
 
-        result = await searchNFTs(searchQuery, searchFilters);
-        setNfts(result);
+        result = await searchNFTs(searchQuery, searchFilters, cursor);
+        if (result.Ok) {
+          const page = result.Ok;
+          const pageNfts = page.nfts.map((hit) => hit.nft);
+          setNfts((prev) => (cursor ? [...prev, ...pageNfts] : pageNfts));
+          setTotalCount(Number(page.total_count));
+          setNextCursor(page.next_cursor[0] ?? null);
+        } else {
+          console.error("Error searching NFTs:", result.Err);
+        }
       }
     } catch (error) {
       console.error("Error loading NFTs:", error);
@@ -61,6 +73,10 @@ const NFTExplorer = () => {
     loadNFTs();
   };
 
+  const loadMore = () => {
+    if (nextCursor) loadNFTs(nextCursor);
+  };
+
   const resetFilters = () => {
     setSearchQuery("");
     setFilters({
@@ -222,7 +238,7 @@ const NFTExplorer = () => {
           <div className="flex justify-between items-center mb-6">
             <h2 className="text-xl font-semibold">
               {activeTab === "trending" ? "Trending NFTs" : "All NFTs"}(
-              {nfts.length})
+              {totalCount})
             </h2>
           </div>
 
@@ -251,6 +267,17 @@ const NFTExplorer = () => {
               ))}
             </div>
           )}
+
+          {nextCursor && (
+            <div className="flex justify-center mt-8">
+              <button
+                onClick={loadMore}
+                className="px-6 py-2 bg-gray-200 text-gray-700 rounded-lg font-medium hover:bg-gray-300 transition-colors"
+              >
+                Load more
+              </button>
+            </div>
+          )}
         </>
       )}
     </div>
@@ -0,0 +1,111 @@
+#!/bin/bash
+
+# End-to-end check of ICRC-2 settlement in buy_nft against a local ledger.
+# The settlement, payout and refund paths are also unit tested against a mock
+# ledger: cargo test -p ip_market_backend
+#
+# Requires a running local replica with ip_market_backend deployed and an
+# ICRC-1 ledger with the ICRC-2 feature enabled, in which the current dfx
+# identity holds funds. Point the script at it with:
+#
+#   LEDGER_CANISTER_ID=<ledger id> ./test-payments.sh
+#
+# The current identity acts as seller (and, as a controller, configures the
+# currency); a throwaway identity "ipm-test-buyer" acts as buyer.
+
+set -e
+
+CURRENCY=${CURRENCY:-TEST}
+PRICE=${PRICE:-1000000}
+BUYER_IDENTITY=ipm-test-buyer
+
+if [ -z "$LEDGER_CANISTER_ID" ]; then
+    echo "❌ LEDGER_CANISTER_ID is not set"
+    exit 1
+fi
+
+BACKEND_ID=$(dfx canister id ip_market_backend --network local)
+SELLER=$(dfx identity get-principal)
+
+if ! dfx identity list 2>/dev/null | grep -q "^${BUYER_IDENTITY}$"; then
+    dfx identity new --storage-mode plaintext "$BUYER_IDENTITY" > /dev/null
+fi
+BUYER=$(dfx identity get-principal --identity "$BUYER_IDENTITY")
+
+backend() {
+    dfx canister call --network local ip_market_backend "$@"
+}
+
+ledger() {
+    dfx canister call --network local "$LEDGER_CANISTER_ID" "$@"
+}
+
+balance_of() {
+    ledger icrc1_balance_of "(record { owner = principal \"$1\"; subaccount = null })" \
+        | grep -o '[0-9_]*' | head -1 | tr -d '_'
+}
+
+echo "🔧 Configuring $CURRENCY on ledger $LEDGER_CANISTER_ID..."
+backend set_currency_ledger "(\"$CURRENCY\", principal \"$LEDGER_CANISTER_ID\")" > /dev/null
+
+FEE=$(ledger icrc1_fee '()' | grep -o '[0-9_]*' | head -1 | tr -d '_')
+
+echo "💸 Funding buyer $BUYER..."
+ledger icrc1_transfer "(record { to = record { owner = principal \"$BUYER\"; subaccount = null }; amount = $((PRICE + 2 * FEE)) })" > /dev/null
+
+echo "🎨 Registering and minting as seller $SELLER..."
+backend create_user_profile '(record { username = "payment-test-seller"; bio = null; email = null; avatar_url = null; banner_url = null; social_links = vec {} })' > /dev/null || true
+IP_ID=$(backend register_ip '(record {
+    title = "Payment test";
+    description = "ICRC-2 settlement check";
+    ip_type = variant { DigitalArt };
+    metadata = record {
+        category = "test"; tags = vec {}; file_hash = null; file_url = null;
+        jurisdiction = "N/A"; expiry_date = null; priority_date = null;
+        application_number = null; registration_number = null; genre = null;
+        medium = null; dimensions = null; color_palette = vec {}; software_used = vec {};
+    };
+    image_url = null;
+    additional_files = vec {};
+})' | grep -o 'id = "IP_[0-9]*"' | head -1 | cut -d'"' -f2)
+NFT_ID=$(backend mint_ip_nft "(record {
+    ip_id = \"$IP_ID\"; name = \"Payment test\"; description = \"\";
+    image = \"https://example.com/image.png\"; attributes = vec {};
+    collection_name = null; edition_number = null; total_editions = null;
+    royalty_percentage = null; external_url = null; animation_url = null; background_color = null;
+})" | grep -o 'id = "NFT_[0-9]*"' | head -1 | cut -d'"' -f2)
+LISTING_ID=$(backend list_nft_for_sale "(record {
+    nft_id = \"$NFT_ID\"; price = $PRICE; currency = \"$CURRENCY\"; expires_at = null;
+    license_terms = null; is_auction = false; auction_duration = null; min_bid_increment = null;
+})" | grep -o 'id = "LISTING_[0-9]*"' | head -1 | cut -d'"' -f2)
+echo "   IP $IP_ID, NFT $NFT_ID, listing $LISTING_ID"
+
+SELLER_BEFORE=$(balance_of "$SELLER")
+
+echo "✍️  Approving marketplace $BACKEND_ID as spender..."
+dfx canister call --network local --identity "$BUYER_IDENTITY" "$LEDGER_CANISTER_ID" icrc2_approve \
+    "(record { spender = record { owner = principal \"$BACKEND_ID\"; subaccount = null }; amount = $((PRICE + FEE)) })" > /dev/null
+
+echo "🛒 Buying listing..."
+dfx canister call --network local --identity "$BUYER_IDENTITY" ip_market_backend buy_nft "(\"$LISTING_ID\")"
+
+SELLER_AFTER=$(balance_of "$SELLER")
+OWNER=$(backend get_nft_by_id "(\"$NFT_ID\")" | grep -o 'owner = principal "[^"]*"' | cut -d'"' -f2)
+
+# The seller minted the NFT, so no royalty applies; the payout out of the
+# marketplace account is net of one ledger fee
+EXPECTED=$((PRICE - FEE))
+if [ "$OWNER" = "$BUYER" ] && [ $((SELLER_AFTER - SELLER_BEFORE)) -eq "$EXPECTED" ]; then
+    echo "✅ NFT moved to buyer and seller received $EXPECTED"
+else
+    echo "❌ Settlement mismatch: owner=$OWNER, seller received $((SELLER_AFTER - SELLER_BEFORE))"
+    exit 1
+fi
+
+echo "🔁 Buying the same listing again must fail..."
+if dfx canister call --network local --identity "$BUYER_IDENTITY" ip_market_backend buy_nft "(\"$LISTING_ID\")" | grep -q "Err"; then
+    echo "✅ Second purchase rejected"
+else
+    echo "❌ Listing was sold twice"
+    exit 1
+fi